@@ -0,0 +1,42 @@
+use crate::bindings::server::RaftEntry;
+use std::collections::BTreeMap;
+
+type NodeId = u64;
+
+// Nodes whose last entry sits at the same index but carries different terms.
+// Raft never lets that happen on a healthy cluster, so it points at divergent
+// logs, e.g. after a botched recovery.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DivergenceWarning {
+    pub index: u64,
+    // Every node reporting `index`, with its term, ordered by node id
+    pub terms: Vec<(NodeId, u64)>,
+}
+
+// Checks over state collected from every node of a cluster
+pub struct ClusterDiagnostics;
+
+impl ClusterDiagnostics {
+    /// Flag indexes reported with more than one term.
+    ///
+    /// `entries` holds each node's `Node::describe_last_entry`. Warnings are
+    /// ordered by index; an empty result means no divergence was observed.
+    pub fn check_divergence(entries: &[(NodeId, RaftEntry)]) -> Vec<DivergenceWarning> {
+        let mut by_index: BTreeMap<u64, Vec<(NodeId, u64)>> = BTreeMap::new();
+        for (id, entry) in entries {
+            by_index.entry(entry.index).or_default().push((*id, entry.term));
+        }
+
+        by_index
+            .into_iter()
+            .filter_map(|(index, mut terms)| {
+                let first = terms[0].1;
+                if terms.iter().all(|&(_, term)| term == first) {
+                    return None;
+                }
+                terms.sort_unstable();
+                Some(DivergenceWarning { index, terms })
+            })
+            .collect()
+    }
+}
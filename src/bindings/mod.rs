@@ -1 +1,8 @@
+#![allow(non_camel_case_types, non_snake_case, non_upper_case_globals)]
+
+include!("../bindings.rs");
+
+pub mod diagnostics;
+pub mod optional;
 pub mod server;
+pub mod version;
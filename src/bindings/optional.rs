@@ -0,0 +1,51 @@
+use crate::bindings::dqlite_node;
+use std::ffi::CStr;
+use std::sync::OnceLock;
+
+// Node setters that only exist in newer libdqlite releases. They are looked up
+// with dlsym at runtime instead of being linked directly, so the crate still
+// loads against an older library and the wrappers can report the gap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    AutoRecovery,
+    TargetVoters,
+    SnapshotCompression,
+}
+
+pub(crate) type SetBoolFn = unsafe extern "C" fn(*mut dqlite_node, bool) -> libc::c_int;
+pub(crate) type SetIntFn = unsafe extern "C" fn(*mut dqlite_node, libc::c_int) -> libc::c_int;
+
+const FEATURE_COUNT: usize = 3;
+
+// Resolved address per feature; 0 when the symbol is missing
+static SYMBOLS: [OnceLock<usize>; FEATURE_COUNT] = [OnceLock::new(), OnceLock::new(), OnceLock::new()];
+
+impl Feature {
+    fn index(self) -> usize {
+        match self {
+            Feature::AutoRecovery => 0,
+            Feature::TargetVoters => 1,
+            Feature::SnapshotCompression => 2,
+        }
+    }
+
+    fn symbol(self) -> &'static CStr {
+        match self {
+            Feature::AutoRecovery => c"dqlite_node_set_auto_recovery",
+            Feature::TargetVoters => c"dqlite_node_set_target_voters",
+            Feature::SnapshotCompression => c"dqlite_node_set_snapshot_compression",
+        }
+    }
+
+    // Address of the symbol in the loaded libdqlite, resolved once
+    pub(crate) fn address(self) -> Option<usize> {
+        let addr = *SYMBOLS[self.index()].get_or_init(|| unsafe {
+            libc::dlsym(libc::RTLD_DEFAULT, self.symbol().as_ptr()) as usize
+        });
+        (addr != 0).then_some(addr)
+    }
+
+    pub fn is_available(self) -> bool {
+        self.address().is_some()
+    }
+}
@@ -5,20 +5,29 @@ use crate::bindings::{
     dqlite_node_set_connect_func, dqlite_node_set_failure_domain,
     dqlite_node_set_busy_timeout, dqlite_node_set_block_size,
     dqlite_node_get_bind_address, dqlite_node_describe_last_entry,
+    dqlite_node_recover_ext, dqlite_node_info_ext,
     dqlite_generate_node_id,
-    DQLITE_ERROR, DQLITE_MISUSE, DQLITE_NOMEM,
-    DQLITE_OK, DQLITE_SNAPSHOT_TRAILING_DYNAMIC, DQLITE_SNAPSHOT_TRAILING_STATIC,
+    DQLITE_SNAPSHOT_TRAILING_DYNAMIC, DQLITE_SNAPSHOT_TRAILING_STATIC,
 };
+use lazy_static::lazy_static;
 use libc::{SIGPIPE, SIG_IGN};
 use std::ffi::{CStr, CString};
 use std::fmt;
+use crate::bindings::optional::{Feature, SetBoolFn, SetIntFn};
+use crate::bindings::version::library_version;
+use crate::protocol::config::Config;
 use crate::protocol::connector::Conn;
 use crate::protocol::connector::DialFunc;
+use crate::protocol::message::Value;
+use crate::protocol::protocol::{Protocol, ProtocolError};
+use crate::protocol::store::{read_yaml_nodes, NodeInfo, NodeRole};
+use std::future::Future;
+use std::path::Path;
 use std::ptr;
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::collections::HashMap;
-use tokio::sync::CancellationToken;
+use tokio_util::sync::CancellationToken;
 use tokio::time::{timeout, Duration};
 use tokio::runtime::Handle;
 
@@ -28,6 +37,36 @@ type ContextRegistry = HashMap<ConnectHandle, Arc<CancellationToken>>;
 type RaftLogIndex = u64;
 type RaftLogTerm = u64;
 
+// Index and term of a raft log entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RaftEntry {
+    pub index: RaftLogIndex,
+    pub term: RaftLogTerm,
+}
+
+// Raft progress of a node, see Node::raft_stats. libdqlite doesn't expose
+// the current term, commit index or last applied index, so those are always
+// None; they are kept so a future libdqlite can fill them in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RaftStats {
+    // Current term, which may be ahead of last_log.term
+    pub term: Option<RaftLogTerm>,
+    pub commit_index: Option<RaftLogIndex>,
+    pub last_applied: Option<RaftLogIndex>,
+    pub last_log: RaftEntry,
+}
+
+// Outcome of PRAGMA wal_checkpoint, in the order SQLite reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalCheckpoint {
+    // A reader or writer kept the checkpoint from completing
+    pub busy: bool,
+    // Frames in the WAL
+    pub log_frames: i64,
+    // Frames copied back into the database
+    pub checkpointed_frames: i64,
+}
+
 // Global registry for connect functions
 lazy_static! {
     static ref CONNECT_REGISTRY: Arc<Mutex<ConnectRegistry>> = {
@@ -45,10 +84,54 @@ lazy_static! {
 
 static CONNECT_INDEX: AtomicU64 = AtomicU64::new(100);
 
-// Initialize the runtime handle
+static RUNTIME_INIT: Once = Once::new();
+
+// Initialize the runtime handle used by the connect trampoline. Only the
+// first call takes effect; later ones are ignored with a warning. Use
+// with_runtime_handle to swap one in temporarily (e.g. per test).
 pub fn init_runtime_handle(handle: Handle) {
-    let mut rt = RUNTIME_HANDLE.lock().unwrap();
-    *rt = Some(handle);
+    let mut installed = false;
+    RUNTIME_INIT.call_once(|| {
+        *RUNTIME_HANDLE.lock().unwrap() = Some(handle);
+        installed = true;
+    });
+    if !installed {
+        log::warn!("dqlite_rs runtime handle already initialized, ignoring init_runtime_handle");
+        return;
+    }
+    log::info!("dqlite_rs initialized against libdqlite {}", library_version());
+}
+
+// The handle the connect trampoline currently dials with, if any
+pub fn runtime_handle() -> Option<Handle> {
+    RUNTIME_HANDLE.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+// Restores the previously installed handle when dropped, so the scope is
+// unwound even if the closure panics
+struct RuntimeHandleGuard {
+    previous: Option<Handle>,
+}
+
+impl Drop for RuntimeHandleGuard {
+    fn drop(&mut self) {
+        let mut rt = RUNTIME_HANDLE.lock().unwrap_or_else(|e| e.into_inner());
+        *rt = self.previous.take();
+    }
+}
+
+// Install `handle` for the duration of `f`, then restore whatever was there before.
+// The lock is not held while `f` runs, so the trampoline can still use the handle.
+pub fn with_runtime_handle<F, R>(handle: Handle, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let previous = {
+        let mut rt = RUNTIME_HANDLE.lock().unwrap();
+        rt.replace(handle)
+    };
+    let _guard = RuntimeHandleGuard { previous };
+    f()
 }
 
 // SIGPIPE terminates the process when a client disconnects while the server is writing a response
@@ -59,6 +142,15 @@ fn ignore_sigpipe() {
     }
 }
 
+// libdqlite reports a failed bind or listen as a generic error. errno, read
+// right after the failing call, or the libuv message tell address-in-use
+// apart from the rest.
+fn is_address_in_use(message: &str, os_err: &std::io::Error) -> bool {
+    os_err.kind() == std::io::ErrorKind::AddrInUse
+        || message.contains("EADDRINUSE")
+        || message.to_ascii_lowercase().contains("address already in use")
+}
+
 // Helper function to safely extract error messages from dqlite_node
 fn get_node_error(node: *mut dqlite_node, default_msg: &str) -> String {
     unsafe {
@@ -79,7 +171,28 @@ pub enum DqliteError {
     Configuration(String),
     Start(String),
     Stop(String),
+    Timeout(String),
+    // The bind address is taken, e.g. by a node that hasn't finished
+    // stopping; worth retrying after a backoff
+    AddressInUse(String),
     NulError(std::ffi::NulError),
+    // Arc keeps the error Clone
+    Io(Arc<std::io::Error>),
+}
+
+impl From<std::io::Error> for DqliteError {
+    fn from(err: std::io::Error) -> Self {
+        DqliteError::Io(Arc::new(err))
+    }
+}
+
+impl From<ProtocolError> for DqliteError {
+    fn from(err: ProtocolError) -> Self {
+        match err {
+            ProtocolError::Io(e) => DqliteError::Io(Arc::new(e)),
+            other => DqliteError::Configuration(other.to_string()),
+        }
+    }
 }
 
 impl From<std::ffi::NulError> for DqliteError {
@@ -95,7 +208,10 @@ impl fmt::Display for DqliteError {
             DqliteError::Configuration(msg) => write!(f, "Configuration failed: {}", msg),
             DqliteError::Start(msg) => write!(f, "Start failed: {}", msg),
             DqliteError::Stop(msg) => write!(f, "Stop failed: {}", msg),
+            DqliteError::Timeout(msg) => write!(f, "Timed out: {}", msg),
+            DqliteError::AddressInUse(msg) => write!(f, "Address in use: {}", msg),
             DqliteError::NulError(err) => write!(f, "Nul error: {}", err),
+            DqliteError::Io(err) => write!(f, "IO error: {}", err),
         }
     }
 }
@@ -133,24 +249,74 @@ impl From<TrailingStrategy> for std::os::raw::c_int {
 }
 
 #[repr(C)]
-struct SnapShotParams {
-    threshold: u64,
-    trailing: u64,
-    strategy: TrailingStrategy,
+pub struct SnapShotParams {
+    pub threshold: u64,
+    pub trailing: u64,
+    pub strategy: TrailingStrategy,
+}
+
+// Named snapshot settings for users who don't want to tune threshold/trailing by hand.
+//
+// - LowMemory: snapshot every 512 entries and keep 1024 trailing entries
+//   (static), keeping the raft log small at the cost of more frequent snapshots
+//   and more full snapshot transfers to lagging nodes.
+// - Balanced: dqlite's own defaults, 1024 / 8192, with dynamic trailing so the
+//   log is trimmed once it outgrows the snapshot.
+// - HighThroughput: 8192 / 32768 (dynamic), snapshotting rarely under heavy
+//   write load and letting followers catch up from the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotPreset {
+    LowMemory,
+    Balanced,
+    HighThroughput,
+}
+
+impl SnapshotPreset {
+    fn params(self) -> SnapShotParams {
+        match self {
+            SnapshotPreset::LowMemory => SnapShotParams {
+                threshold: 512,
+                trailing: 1024,
+                strategy: TrailingStrategy::Static,
+            },
+            SnapshotPreset::Balanced => SnapShotParams {
+                threshold: 1024,
+                trailing: 8192,
+                strategy: TrailingStrategy::Dynamic,
+            },
+            SnapshotPreset::HighThroughput => SnapShotParams {
+                threshold: 8192,
+                trailing: 32768,
+                strategy: TrailingStrategy::Dynamic,
+            },
+        }
+    }
 }
 
 pub struct Node {
     node: *mut dqlite_node,
+    id: u64,
     cancel_token: Arc<CancellationToken>,
+    has_bind_address: AtomicBool,
+    // Between a successful start() and stop()
+    running: AtomicBool,
+    // Registry key of the dial func installed by set_dial_func, if any
+    connect_handle: Mutex<Option<ConnectHandle>>,
+    // Socket path and mode to chmod once start() has created the socket
+    unix_socket_mode: Mutex<Option<(String, u32)>>,
 }
 
 
 impl Node {
+    // `address` is the node's raft address, recorded in the cluster configuration
+    // and used by other nodes and clients to reach it. It is not a listening
+    // address: set_bind_address must be called before start().
     pub fn new(id: u64, address: &str, dir: &str) -> Result<Self, DqliteError> {
         let c_address = CString::new(address)?;
         let c_dir = CString::new(dir)?;
         let c_id = id as dqlite_node_id;
         let cancel_token = Arc::new(CancellationToken::new());
+        ignore_sigpipe();
 
         let mut node_ptr: *mut dqlite_node = ptr::null_mut();
 
@@ -165,21 +331,105 @@ impl Node {
 
         Ok(Node {
             node: node_ptr,
+            id,
             cancel_token,
+            has_bind_address: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            connect_handle: Mutex::new(None),
+            unix_socket_mode: Mutex::new(None),
         })
     }
 
+    // Like new, but keeps each node's data in its own `base_dir/<id>` directory,
+    // created with mode 0700 if it doesn't exist yet
+    pub fn new_with_layout(id: u64, address: &str, base_dir: &str) -> Result<Self, DqliteError> {
+        use std::os::unix::fs::DirBuilderExt;
+
+        let dir = std::path::Path::new(base_dir).join(id.to_string());
+        if !dir.is_dir() {
+            std::fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+        }
+
+        let dir = dir.to_str().ok_or_else(|| {
+            DqliteError::Configuration(format!("Data directory is not valid UTF-8: {}", dir.display()))
+        })?;
+        Self::new(id, address, dir)
+    }
+
+    // Like new, with the id taken from generate_id(address). libdqlite mixes
+    // the current time into that id, so it is only reproducible if the caller
+    // persists it: read it back with id() and reuse it with new on restart.
+    pub fn from_address(address: &str, dir: &str) -> Result<Self, DqliteError> {
+        let id = Self::generate_id(address)?;
+        Self::new(id, address, dir)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The underlying `dqlite_node` pointer, for calling libdqlite functions
+    /// this crate doesn't wrap.
+    ///
+    /// # Safety
+    ///
+    /// The pointer is owned by this `Node` and is destroyed when it drops, so
+    /// the `Node` must outlive every use of it. Never pass it to
+    /// `dqlite_node_destroy`, and keep to libdqlite's rules about which calls
+    /// are allowed before and after `start`; the wrappers here track some
+    /// state (bind address, dial func) that raw calls bypass.
+    ///
+    /// ```no_run
+    /// use dqlite_rs::bindings::dqlite_node_set_network_latency_ms;
+    /// use dqlite_rs::prelude::Node;
+    ///
+    /// let node = Node::new(1, "127.0.0.1:9001", "/tmp/dqlite")?;
+    /// let rc = unsafe { dqlite_node_set_network_latency_ms(node.as_raw(), 5) };
+    /// assert_eq!(rc, 0);
+    /// # Ok::<(), dqlite_rs::prelude::DqliteError>(())
+    /// ```
+    pub unsafe fn as_raw(&self) -> *mut dqlite_node {
+        self.node
+    }
+
+    // libdqlite gives no access to the listening socket's options. None are
+    // needed for quick restarts: libuv binds TCP listeners with SO_REUSEADDR,
+    // so connections left in TIME_WAIT by a stopped node don't block a new
+    // one on the same address. SO_REUSEPORT is never set, so two live nodes
+    // can't share an address by accident.
     pub fn set_bind_address(&self, address: &str) -> Result<(), DqliteError> {
         let c_address = CString::new(address)?;
         let rc = unsafe { dqlite_node_set_bind_address(self.node, c_address.as_ptr()) };
 
         if rc != 0 {
+            let os_err = std::io::Error::last_os_error();
             let err_msg = get_node_error(self.node, &format!("Failed to set bind address: error code {}", rc));
+            if is_address_in_use(&err_msg, &os_err) {
+                return Err(DqliteError::AddressInUse(format!("{}: {}", address, err_msg)));
+            }
             return Err(DqliteError::Configuration(format!(
                 "Failed to set bind address: {}",
                 err_msg
             )));
         }
+        self.has_bind_address.store(true, Ordering::SeqCst);
+        *self.unix_socket_mode.lock().unwrap() = None;
+        Ok(())
+    }
+
+    // Bind to a unix socket and restrict who can connect to it. The socket
+    // only exists once the node is listening, so the mode is applied by
+    // start(). Abstract sockets (leading '@') have no file and therefore no
+    // permissions; `mode` is ignored for them.
+    pub fn set_bind_unix(&self, path: &str, mode: u32) -> Result<(), DqliteError> {
+        self.set_bind_address(path)?;
+
+        let pending = if path.starts_with('@') {
+            None
+        } else {
+            Some((path.to_string(), mode))
+        };
+        *self.unix_socket_mode.lock().unwrap() = pending;
         Ok(())
     }
 
@@ -212,22 +462,77 @@ impl Node {
         Ok(())
     }
 
+    pub fn apply_snapshot_preset(&self, preset: SnapshotPreset) -> Result<(), DqliteError> {
+        self.set_snapshot_params(preset.params())
+    }
+
     pub fn start(&self) -> Result<(), DqliteError> {
+        if !self.has_bind_address.load(Ordering::SeqCst) {
+            return Err(DqliteError::Start("no bind address configured".to_string()));
+        }
+
         let rc = unsafe { dqlite_node_start(self.node) };
         if rc != 0 {
+            let os_err = std::io::Error::last_os_error();
             let err_msg = get_node_error(self.node, &format!("Failed to start node: error code {}", rc));
+            if is_address_in_use(&err_msg, &os_err) {
+                return Err(DqliteError::AddressInUse(err_msg));
+            }
             return Err(DqliteError::Start(err_msg));
         }
 
+        self.running.store(true, Ordering::SeqCst);
+
+        if let Some((path, mode)) = self.unix_socket_mode.lock().unwrap().as_ref() {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(*mode)).map_err(|e| {
+                DqliteError::Start(format!("Failed to set mode {:o} on {}: {}", mode, path, e))
+            })?;
+        }
+
         Ok(())
     }
 
+    // Whether start() succeeded and stop() hasn't been called since
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
     pub fn stop(&self) -> Result<(), DqliteError> {
         let rc = unsafe { dqlite_node_stop(self.node) };
         if rc != 0 {
             let err_msg = get_node_error(self.node, &format!("Failed to stop node: error code {}", rc));
             return Err(DqliteError::Stop(err_msg));
         }
+        self.running.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Stop the node and block until its listening socket is gone, so another
+    // node can bind the same address right away. libdqlite has no join call,
+    // so this polls: a TCP address counts as released once it can be bound
+    // again, a unix socket once connecting to it is refused.
+    pub fn stop_and_join(&self, timeout: Duration) -> Result<(), DqliteError> {
+        const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+        // Read before stopping; without a bind address there is nothing to wait for
+        let address = self.get_bind_address().ok();
+        self.stop()?;
+        let Some(address) = address else {
+            return Ok(());
+        };
+
+        let deadline = std::time::Instant::now() + timeout;
+        while !address_released(&address) {
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                return Err(DqliteError::Timeout(format!(
+                    "{} still in use {:?} after stop",
+                    address, timeout
+                )));
+            }
+            std::thread::sleep(POLL_INTERVAL.min(deadline - now));
+        }
         Ok(())
     }
 
@@ -269,9 +574,25 @@ impl Node {
         Ok(())
     }
 
+    // Whether the linked libdqlite provides `feature`
+    pub fn supports(&self, feature: Feature) -> bool {
+        feature.is_available()
+    }
+
+    fn optional_symbol(&self, feature: Feature) -> Result<usize, DqliteError> {
+        feature.address().ok_or_else(|| {
+            DqliteError::Configuration(format!(
+                "{:?} unsupported by linked libdqlite version {}",
+                feature,
+                library_version()
+            ))
+        })
+    }
+
     pub fn set_auto_recovery(&self, enabled: bool) -> Result<(), DqliteError> {
-        let c_bool = enabled as std::os::raw::c_bool;
-        let rc = unsafe { dqlite_node_set_auto_recovery(self.node, c_bool) };
+        let addr = self.optional_symbol(Feature::AutoRecovery)?;
+        let set: SetBoolFn = unsafe { std::mem::transmute(addr) };
+        let rc = unsafe { set(self.node, enabled) };
         if rc != 0 {
             let err_msg = get_node_error(self.node, &format!("Failed to set auto recovery: error code {}", rc));
             return Err(DqliteError::Configuration(format!(
@@ -282,6 +603,38 @@ impl Node {
         Ok(())
     }
 
+    pub fn set_target_voters(&self, voters: u32) -> Result<(), DqliteError> {
+        let addr = self.optional_symbol(Feature::TargetVoters)?;
+        let set: SetIntFn = unsafe { std::mem::transmute(addr) };
+        let rc = unsafe { set(self.node, voters as libc::c_int) };
+        if rc != 0 {
+            let err_msg = get_node_error(self.node, &format!("Failed to set target voters: error code {}", rc));
+            return Err(DqliteError::Configuration(format!(
+                "Failed to set target voters: {}",
+                err_msg
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn set_snapshot_compression(&self, enabled: bool) -> Result<(), DqliteError> {
+        let addr = self.optional_symbol(Feature::SnapshotCompression)?;
+        let set: SetBoolFn = unsafe { std::mem::transmute(addr) };
+        let rc = unsafe { set(self.node, enabled) };
+        if rc != 0 {
+            let err_msg = get_node_error(self.node, &format!("Failed to set snapshot compression: error code {}", rc));
+            return Err(DqliteError::Configuration(format!(
+                "Failed to set snapshot compression: {}",
+                err_msg
+            )));
+        }
+        Ok(())
+    }
+
+    // The returned pointer is borrowed from the node: libdqlite keeps the
+    // string in its own storage, valid until the bind address changes or the
+    // node is destroyed, and it must not be freed here. It is copied into an
+    // owned String before this returns, so callers never hold the pointer.
     pub fn get_bind_address(&self) -> Result<String, DqliteError> {
         let address = unsafe { dqlite_node_get_bind_address(self.node) };
         if address.is_null() {
@@ -292,7 +645,6 @@ impl Node {
                 .to_string_lossy()
                 .into_owned()
         };
-
         Ok(address_str)
     }
 
@@ -310,12 +662,76 @@ impl Node {
         Ok(())
     }
     
-    // TODO: Implement recover after protocol is implemented
-    pub fn recover(&self) -> Result<(), DqliteError> {
-        Err(DqliteError::Configuration("Not implemented yet".to_string()))
+    // Force a new cluster configuration onto this node after quorum loss. The
+    // node must not be running; see dqlite_node_recover_ext for the full
+    // procedure (pick the most up to date survivor, recover it, copy its data
+    // directory to the other survivors, restart them all).
+    pub fn recover(&self, nodes: &[NodeInfo]) -> Result<(), DqliteError> {
+        let addresses = nodes
+            .iter()
+            .map(|node| CString::new(node.addr.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut infos: Vec<dqlite_node_info_ext> = nodes
+            .iter()
+            .zip(&addresses)
+            .map(|(node, address)| dqlite_node_info_ext {
+                size: std::mem::size_of::<dqlite_node_info_ext>() as u64,
+                id: node.id,
+                address: address.as_ptr() as u64,
+                dqlite_role: node.role.value() as u64,
+            })
+            .collect();
+
+        let rc = unsafe {
+            dqlite_node_recover_ext(self.node, infos.as_mut_ptr(), infos.len() as libc::c_int)
+        };
+        if rc != 0 {
+            let err_msg = get_node_error(self.node, &format!("Failed to recover node: error code {}", rc));
+            return Err(DqliteError::Configuration(format!(
+                "Failed to recover node: {}",
+                err_msg
+            )));
+        }
+        Ok(())
+    }
+
+    // Single-node recovery from a YamlNodeStore file: the file must list this
+    // node and exactly one voter.
+    pub fn recover_from_yaml(&self, path: &Path) -> Result<(), DqliteError> {
+        let nodes = read_yaml_nodes(path).map_err(|e| {
+            DqliteError::Configuration(format!("Invalid recovery file {}: {}", path.display(), e))
+        })?;
+
+        let voters = nodes.iter().filter(|node| node.role == NodeRole::VOTER).count();
+        if voters != 1 {
+            return Err(DqliteError::Configuration(format!(
+                "Recovery file {} lists {} voters, expected exactly one",
+                path.display(),
+                voters
+            )));
+        }
+        if !nodes.iter().any(|node| node.id == self.id) {
+            return Err(DqliteError::Configuration(format!(
+                "Recovery file {} does not list this node ({})",
+                path.display(),
+                self.id
+            )));
+        }
+
+        self.recover(&nodes)
     }
 
+    // Index and term of the last entry persisted in the raft log, read from
+    // the data directory. libdqlite only allows this on a node that isn't
+    // running (it may rewrite raft files), so a running node is refused. It
+    // is the last stored entry, not the applied or committed index.
     pub fn describe_last_entry(&self) -> Result<(RaftLogIndex, RaftLogTerm), DqliteError> {
+        if self.running.load(Ordering::SeqCst) {
+            return Err(DqliteError::Configuration(
+                "Cannot describe the last entry of a running node; stop it first".to_string(),
+            ));
+        }
+
         let mut index: u64 = 0;
         let mut term: u64 = 0;
 
@@ -331,6 +747,68 @@ impl Node {
         Ok((index, term))
     }
 
+    // Raft progress read from a stopped node's data directory: only the last
+    // persisted log entry, via describe_last_entry, which refuses a running
+    // node. Not a monitoring call for live nodes.
+    pub fn raft_stats(&self) -> Result<RaftStats, DqliteError> {
+        let (index, term) = self.describe_last_entry()?;
+        Ok(RaftStats {
+            term: None,
+            commit_index: None,
+            last_applied: None,
+            last_log: RaftEntry { index, term },
+        })
+    }
+
+    /// Checkpoint the WAL of database `db` and truncate it, to reclaim space.
+    ///
+    /// libdqlite has no node-local checkpoint call, so this runs
+    /// `PRAGMA wal_checkpoint(TRUNCATE)` over a client connection to the
+    /// node's bind address. The node must be running and, since dqlite only
+    /// serves queries on the leader, currently be the leader.
+    pub async fn checkpoint(&self, db: &str) -> Result<WalCheckpoint, DqliteError> {
+        let address = self.get_bind_address()?;
+        let conn = dial_bind_address(&address).await?;
+
+        let (mut proto, db_id) = Protocol::establish(conn, self.id, db, &Config::new()).await?;
+        let rows = proto.query_sql(db_id, "PRAGMA wal_checkpoint(TRUNCATE)", &[]).await?;
+
+        let row = rows.iter().next().map(|row| row.values()).unwrap_or_default();
+        match row {
+            [Value::Integer(busy), Value::Integer(log), Value::Integer(checkpointed)] => Ok(WalCheckpoint {
+                busy: *busy != 0,
+                log_frames: *log,
+                checkpointed_frames: *checkpointed,
+            }),
+            other => Err(DqliteError::Configuration(format!(
+                "Unexpected wal_checkpoint result: {:?}",
+                other
+            ))),
+        }
+    }
+
+    /// Serve a client connection accepted by the caller, e.g. after
+    /// terminating TLS on it, as if it had reached the node's own listener.
+    ///
+    /// libdqlite can't adopt an accepted socket, so the returned future
+    /// connects to the node's bind address (ideally an abstract unix socket
+    /// only reachable locally) and relays bytes both ways until either side
+    /// closes. It takes ownership of `conn`: the relay closes it when done,
+    /// and nothing else may read or write it meanwhile. The future doesn't
+    /// borrow the node, so it can be spawned, one per connection.
+    pub fn handle_conn(
+        &self,
+        conn: Conn,
+    ) -> Result<impl Future<Output = Result<(), DqliteError>> + Send + 'static, DqliteError> {
+        let address = self.get_bind_address()?;
+        Ok(async move {
+            let mut client = conn;
+            let mut node = dial_bind_address(&address).await?;
+            tokio::io::copy_bidirectional(&mut client, &mut node).await?;
+            Ok(())
+        })
+    }
+
     pub fn generate_id(address: &str) -> Result<dqlite_node_id, DqliteError> {
         let c_address = CString::new(address)?;
         let id = unsafe { dqlite_generate_node_id(c_address.as_ptr())};
@@ -339,12 +817,72 @@ impl Node {
     
 }
 
+// Connect to a node's own listener. Bind addresses are "host:port", a socket
+// path, or '@' and an abstract socket name.
+async fn dial_bind_address(address: &str) -> std::io::Result<Conn> {
+    if let Some(name) = address.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        let stream = std::os::unix::net::UnixStream::connect_addr(&addr)?;
+        stream.set_nonblocking(true)?;
+        return Ok(Conn::from_unix(tokio::net::UnixStream::from_std(stream)?));
+    }
+    if address.starts_with('/') {
+        return Ok(Conn::from_unix(tokio::net::UnixStream::connect(address).await?));
+    }
+    Ok(Conn::from_tcp(tokio::net::TcpStream::connect(address).await?))
+}
+
+// Whether nothing listens on a bind address any more. Unix addresses are a
+// path or, starting with '@', an abstract socket name.
+fn address_released(address: &str) -> bool {
+    use std::os::unix::net::{SocketAddr as UnixSocketAddr, UnixStream};
+
+    let unix = if let Some(name) = address.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        Some(UnixSocketAddr::from_abstract_name(name))
+    } else if address.starts_with('/') {
+        Some(UnixSocketAddr::from_pathname(address))
+    } else {
+        None
+    };
+
+    match unix {
+        Some(Ok(addr)) => match UnixStream::connect_addr(&addr) {
+            Ok(_) => false,
+            Err(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::NotFound
+            ),
+        },
+        // Not a valid socket address, so nothing can be listening on it
+        Some(Err(_)) => true,
+        // std binds with SO_REUSEADDR like libuv, so TIME_WAIT leftovers
+        // don't count as in use here either
+        None => std::net::TcpListener::bind(address).is_ok(),
+    }
+}
+
 // RAII wrapper for dqlite_node
+// Teardown order matters while a connect callback may be running:
+// 1. cancel the token, so an in-flight dial gives up instead of finishing;
+// 2. remove the registry entries, so a callback that starts now finds nothing
+//    and returns RAFT_NOCONNECTION (one already running holds its own clones
+//    of the dial func and token, so nothing it uses is freed under it);
+// 3. destroy the node; dqlite makes no connect callbacks after destroy.
 impl Drop for Node {
     fn drop(&mut self) {
-
         self.cancel_token.cancel();
 
+        let handle = self
+            .connect_handle
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        if let Some(handle) = handle {
+            unregister_connect(handle);
+        }
+
         if !self.node.is_null() {
             unsafe {
                 dqlite_node_destroy(self.node);
@@ -353,22 +891,28 @@ impl Drop for Node {
     }
 }
 
-impl !Clone for Node {}
-
+// raft's RAFT_NOCONNECTION, what dqlite expects from a connect func that
+// couldn't reach the peer
+const RAFT_NOCONNECTION: libc::c_int = 16;
 
-// Custom Connect function for dqlite_server_set_connect_func
-#[no_mangle]
-pub extern "C" fn connect_with_dial(
+// Dials `address` with the func registered under `handle`, for connect_trampoline
+fn connect_with_dial(
     handle: ConnectHandle,
     address: *const libc::c_char,
     fd: *mut libc::c_int,
 ) -> libc::c_int {
-    use std::os::unix::io::RawFd;
+    use tokio::runtime::RuntimeFlavor;
 
-    let rt_handle = {
-        let rt = RUNTIME_HANDLE.lock().unwrap();
-        rt.clone().expect("Runtime handle not initialized")
+    let Some(rt_handle) = runtime_handle() else {
+        log::error!("dqlite_rs runtime handle not initialized, can't dial");
+        return RAFT_NOCONNECTION;
     };
+    // Handle::block_on can't drive a current_thread runtime's I/O: only the
+    // thread running it can, and that may be the very thread blocked here
+    if rt_handle.runtime_flavor() == RuntimeFlavor::CurrentThread {
+        log::error!("dqlite_rs runtime handle is a current_thread runtime, can't dial; use a multi-thread runtime");
+        return RAFT_NOCONNECTION;
+    }
 
     // Get Global Connect Registry
     let connect_reg = CONNECT_REGISTRY.lock().unwrap();
@@ -376,12 +920,12 @@ pub extern "C" fn connect_with_dial(
 
     let dial_fn = match connect_reg.get(&handle) {
         Some(dial_fn) => dial_fn.clone(),
-        None => return 16, // RAFT_NOCONNECTION
+        None => return RAFT_NOCONNECTION,
     };
 
     let cancel_token = match context_reg.get(&handle) {
         Some(token) => token.clone(),
-        None => return 16,
+        None => return RAFT_NOCONNECTION,
     };
 
     let addr_str = unsafe {
@@ -393,8 +937,9 @@ pub extern "C" fn connect_with_dial(
     drop(connect_reg);
     drop(context_reg);
 
-    // Use the context for timeout and cancellation
-    let result = rt_handle.block_on(async {
+    // Use the context for timeout and cancellation. dqlite owns the socket
+    // once it has the fd, so the Conn gives it up rather than closing it.
+    let dial_blocking = || rt_handle.block_on(async {
         let timeout_duration = Duration::from_secs(5);
 
         let dial_future = async {
@@ -402,27 +947,53 @@ pub extern "C" fn connect_with_dial(
                 return Err("cancelled".to_string());
             }
 
-            let conn_result = dial_fn(&addr_str).await;
-
-            match conn_result {
-                Ok(conn) => Ok(conn.as_raw_fd() as RawFd),
-                Err(e) => Err(e),
-            }
+            let conn = dial_fn(&addr_str).await?;
+            conn.into_raw_fd().map_err(|e| e.to_string())
         };
 
         timeout(timeout_duration, dial_future).await
     });
 
+    // dqlite calls this from its libuv thread, outside any runtime, where
+    // blocking is fine. On a runtime thread block_on would panic: a
+    // multi-thread worker hands its other tasks off with block_in_place,
+    // anything else waits on a helper thread while the stored runtime's own
+    // workers drive the dial.
+    let result = match Handle::try_current() {
+        Err(_) => dial_blocking(),
+        Ok(current) if current.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(dial_blocking)
+        }
+        Ok(_) => match std::thread::scope(|s| s.spawn(dial_blocking).join()) {
+            Ok(result) => result,
+            Err(_) => return RAFT_NOCONNECTION,
+        },
+    };
+
     match result {
         Ok(Ok(socket_fd)) => {
             unsafe { *fd = socket_fd as libc::c_int };
             0
         }
-        Ok(Err(_)) => 16, // RAFT_NOCONNECTION
-        Err(_) => 16
+        Ok(Err(e)) => {
+            log::warn!("Failed to dial {}: {}", addr_str, e);
+            RAFT_NOCONNECTION
+        }
+        Err(_) => {
+            log::warn!("Timed out dialing {}", addr_str);
+            RAFT_NOCONNECTION
+        }
     }
 }
 
+// Drop the registry entries behind a connect handle
+fn unregister_connect(handle: ConnectHandle) {
+    let mut connect_reg = CONNECT_REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    let mut context_reg = CONTEXT_REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    connect_reg.remove(&handle);
+    context_reg.remove(&handle);
+}
+
 // C trampoline function to get passed to dqlite_node_set_connect_func
 extern "C" fn connect_trampoline(
     data: *mut libc::c_void,
@@ -433,9 +1004,25 @@ extern "C" fn connect_trampoline(
     connect_with_dial(handle, address, fd)
 }
 
+// Run the connect trampoline the way libdqlite does, dialing `address` with
+// `dial`. Returns the fd handed over, or the error code. Lets tests exercise
+// the trampoline without a running node.
+#[cfg(feature = "testkit")]
+pub fn invoke_connect_trampoline(dial: DialFunc, address: &str) -> Result<libc::c_int, libc::c_int> {
+    let handle = CONNECT_INDEX.fetch_add(1, Ordering::SeqCst);
+    CONNECT_REGISTRY.lock().unwrap().insert(handle, dial);
+    CONTEXT_REGISTRY.lock().unwrap().insert(handle, Arc::new(CancellationToken::new()));
+
+    let address = CString::new(address).map_err(|_| RAFT_NOCONNECTION)?;
+    let mut fd = -1;
+    let rc = connect_trampoline(handle as *mut libc::c_void, address.as_ptr(), &mut fd);
+    unregister_connect(handle);
+    if rc == 0 { Ok(fd) } else { Err(rc) }
+}
+
 
 impl Node {
-    pub fn set_dial_func<F>(&self, dial: F) -> Result<(), DqliteError>
+    pub fn set_dial_func<F, Fut>(&self, dial: F) -> Result<(), DqliteError>
     where
         F: Fn(&str) -> Fut + Send + Sync + 'static,
         Fut: Future<Output = Result<Conn, String>> + Send + 'static,
@@ -460,17 +1047,14 @@ impl Node {
         let rc = unsafe {
             dqlite_node_set_connect_func(
                 self.node,
-                connect_trampoline,
+                Some(connect_trampoline),
                 handle as *mut libc::c_void,
             )
         };
 
         if rc != 0 {
             // Cleanup on error
-            let mut connect_reg = CONNECT_REGISTRY.lock().unwrap();
-            let mut context_reg = CONTEXT_REGISTRY.lock().unwrap();
-            connect_reg.remove(&handle);
-            context_reg.remove(&handle);
+            unregister_connect(handle);
 
             let err_msg = get_node_error(self.node, &format!("Failed to set dial function: error code {}", rc));
 
@@ -479,6 +1063,12 @@ impl Node {
                 err_msg
             )));
         }
+
+        // dqlite now calls the new handle; the previous one is unreachable
+        let previous = self.connect_handle.lock().unwrap().replace(handle);
+        if let Some(previous) = previous {
+            unregister_connect(previous);
+        }
         Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,25 @@
+use crate::bindings::dqlite_version_number;
+use std::sync::OnceLock;
+
+// libdqlite packs its version as major * 10000 + minor * 100 + release
+fn decode_version_number(number: u32) -> (u32, u32, u32) {
+    (number / 10000, (number / 100) % 100, number % 100)
+}
+
+// Version of the libdqlite linked at runtime, as (major, minor, release)
+pub fn version() -> (u32, u32, u32) {
+    let number = unsafe { dqlite_version_number() };
+    decode_version_number(number as u32)
+}
+
+pub fn version_string() -> String {
+    let (major, minor, release) = version();
+    format!("{}.{}.{}", major, minor, release)
+}
+
+// Version of the linked libdqlite as "major.minor.release", computed once.
+// Meant for logs and support reports.
+pub fn library_version() -> &'static str {
+    static VERSION: OnceLock<String> = OnceLock::new();
+    VERSION.get_or_init(version_string)
+}
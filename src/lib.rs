@@ -1,5 +1,8 @@
-#![allow(non_camel_case_types, non_snake_case, non_upper_case_globals)]
-
-include!("bindings.rs");
-
+#[path = "bindings/mod.rs"]
+pub mod bindings;
+pub mod protocol;
+pub mod prelude;
+#[cfg(feature = "testkit")]
+pub mod testkit;
 
+pub use bindings::version::{library_version, version, version_string};
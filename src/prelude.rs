@@ -0,0 +1,51 @@
+//! The supported public surface of the crate.
+//!
+//! `use dqlite_rs::prelude::*` brings in everything needed to run a node and
+//! talk to a cluster. The deep module paths keep working, but only the types
+//! re-exported here are considered stable.
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use dqlite_rs::prelude::*;
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let node = Node::new(1, "127.0.0.1:9001", "/tmp/dqlite")?;
+//! node.set_bind_address("127.0.0.1:9001")?;
+//! node.start()?;
+//!
+//! let store = Arc::new(ObservableNodeStore::new(InMemoryNodeStore::new()));
+//! let node_info = NodeInfo { id: 1, addr: "127.0.0.1:9001".into(), role: NodeRole::VOTER, failure_domain: None };
+//! store.set_all(vec![node_info]).await?;
+//! let connector: Connector<InMemoryNodeStore> = Connector::builder()
+//!     .store(store)
+//!     .config(Config::new())
+//!     .build()?;
+//!
+//! let db: Database = connector.open("app").await?;
+//! let result: ExecResult = db.execute("CREATE TABLE t (v TEXT)", &[]).await?;
+//! assert_eq!(result.rows_affected, 0);
+//! db.execute("INSERT INTO t VALUES (?)", &[Value::Text("a".into())]).await?;
+//! let rows: Rows = db.query("SELECT v FROM t", &[]).await?;
+//! for row in rows {
+//!     println!("{:?}", row.values());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub use crate::bindings::diagnostics::{ClusterDiagnostics, DivergenceWarning};
+pub use crate::bindings::optional::Feature;
+pub use crate::bindings::server::{DqliteError, Node, RaftEntry, RaftStats, WalCheckpoint};
+pub use crate::protocol::actor::{ProtocolActor, ProtocolHandle};
+pub use crate::protocol::config::{Config, ConfigError};
+pub use crate::protocol::connector::{Addr, Client, Conn, ConnEvent, ConnState, Connector, ConnectorBuilder, NodeConnState};
+pub use crate::protocol::database::{Database, Queued, ReadOnlyDatabase, ReconnectingDatabase, Statement, WritePolicy};
+pub use crate::protocol::message::{ExecResult, OpenFlags, Row, Rows, Value};
+pub use crate::protocol::pool::{Pool, PooledConn};
+pub use crate::protocol::protocol::{NodeMetadata, Protocol, ProtocolError, ProtocolResult, RowStream, SqliteCode};
+pub use crate::protocol::retry::{DefaultRetry, NoRetry, RetryPolicy};
+pub use crate::protocol::shard::ShardRouter;
+pub use crate::protocol::store::{
+    BackendSnapshot, CachedNodeStore, DatabaseNodeStore, FlushPolicy, InMemoryNodeStore, NodeInfo, NodeRole, NodeStore, NodeStoreError,
+    ObservableNodeStore, YamlNodeStore,
+};
@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use tokio::sync::{mpsc, oneshot};
+use crate::protocol::message::{
+    ExecResult, Message, OpenFlags, Rows, Value, REQUEST_EXEC_SQL, REQUEST_QUERY_SQL, RESPONSE_DB, RESPONSE_RESULT,
+};
+use crate::protocol::protocol::{open_request, sql_request, DbReply, Protocol, ProtocolError, ProtocolResult};
+
+// Requests that can be queued before submitters start waiting for room
+const ACTOR_QUEUE_SIZE: usize = 64;
+
+enum Request {
+    Open {
+        name: String,
+        reply: oneshot::Sender<ProtocolResult<u32>>,
+    },
+    Exec {
+        db_id: u32,
+        sql: String,
+        params: Vec<Value>,
+        reply: oneshot::Sender<ProtocolResult<ExecResult>>,
+    },
+    Query {
+        db_id: u32,
+        sql: String,
+        params: Vec<Value>,
+        reply: oneshot::Sender<ProtocolResult<Rows>>,
+    },
+}
+
+// Where the response to a request written on the connection goes
+enum Reply {
+    Open(oneshot::Sender<ProtocolResult<u32>>),
+    Exec(oneshot::Sender<ProtocolResult<ExecResult>>),
+    Query(oneshot::Sender<ProtocolResult<Rows>>),
+}
+
+impl Reply {
+    // A submitter that stopped waiting just doesn't get its reply
+    fn fail(self, err: ProtocolError) {
+        match self {
+            Reply::Open(reply) => {
+                let _ = reply.send(Err(err));
+            }
+            Reply::Exec(reply) => {
+                let _ = reply.send(Err(err));
+            }
+            Reply::Query(reply) => {
+                let _ = reply.send(Err(err));
+            }
+        }
+    }
+}
+
+/// Owns a [`Protocol`] on a dedicated task and pipelines the requests it
+/// receives on the connection: each one is written as soon as it arrives,
+/// without waiting for the responses to earlier ones. dqlite answers in
+/// order, so responses are matched to requests by their sequence number and
+/// handed back oldest first. Tasks talk to it through a [`ProtocolHandle`]
+/// instead of sharing the protocol behind a mutex.
+///
+/// Once the connection breaks, every pending and later request fails.
+pub struct ProtocolActor {
+    proto: Protocol,
+    rx: mpsc::Receiver<Request>,
+    // Requests written but not answered yet, oldest first
+    pending: VecDeque<(u64, Reply)>,
+}
+
+impl ProtocolActor {
+    /// Move `proto` into a new task and return a handle to it. The task ends,
+    /// dropping the connection, once every handle has been dropped and every
+    /// pending request has been answered.
+    pub fn spawn(proto: Protocol) -> ProtocolHandle {
+        let (tx, rx) = mpsc::channel(ACTOR_QUEUE_SIZE);
+        let actor = Self {
+            proto,
+            rx,
+            pending: VecDeque::new(),
+        };
+        tokio::spawn(actor.run());
+        ProtocolHandle { tx }
+    }
+
+    async fn run(mut self) {
+        let mut open = true;
+        while open || !self.pending.is_empty() {
+            let waiting = !self.pending.is_empty();
+            // Both branches are cancel safe: readable only waits for data to
+            // arrive, the response itself is read after the select
+            tokio::select! {
+                request = self.rx.recv(), if open => match request {
+                    Some(request) => self.write(request).await,
+                    None => open = false,
+                },
+                ready = self.proto.readable(), if waiting => match ready {
+                    Ok(()) => self.read().await,
+                    Err(e) => self.fail_pending(e),
+                },
+            }
+        }
+    }
+
+    // Write one request and queue its reply behind the ones already in flight
+    async fn write(&mut self, request: Request) {
+        let (message, reply) = match request {
+            Request::Open { name, reply } => (open_request(&name, OpenFlags::default()), Reply::Open(reply)),
+            Request::Exec { db_id, sql, params, reply } => {
+                (sql_request(REQUEST_EXEC_SQL, db_id, &sql, &params), Reply::Exec(reply))
+            }
+            Request::Query { db_id, sql, params, reply } => {
+                (sql_request(REQUEST_QUERY_SQL, db_id, &sql, &params), Reply::Query(reply))
+            }
+        };
+        match self.send(message).await {
+            Ok(seq) => self.pending.push_back((seq, reply)),
+            Err(e) => reply.fail(e),
+        }
+    }
+
+    async fn send(&mut self, message: ProtocolResult<Message>) -> ProtocolResult<u64> {
+        self.proto.send_pipelined(&message?).await
+    }
+
+    // Read the response to the oldest pending request
+    async fn read(&mut self) {
+        let Some((seq, reply)) = self.pending.pop_front() else {
+            return;
+        };
+        let proto = &mut self.proto;
+        match reply {
+            Reply::Open(reply) => {
+                let response = proto.recv_pipelined(seq, RESPONSE_DB).await;
+                let _ = reply.send(response.and_then(|mut response| Ok(DbReply::decode(&mut response)?.id)));
+            }
+            Reply::Exec(reply) => {
+                let response = proto.recv_pipelined(seq, RESPONSE_RESULT).await;
+                let _ = reply.send(response.and_then(|mut response| Ok(response.get_result()?)));
+            }
+            Reply::Query(reply) => {
+                let _ = reply.send(proto.recv_rows_pipelined(seq).await);
+            }
+        }
+        if self.proto.is_broken() {
+            let err = ProtocolError::Protocol(format!("Connection to {} is broken", self.proto.addr()));
+            self.fail_pending(err);
+        }
+    }
+
+    // The stream position is lost: nothing still pending will be answered
+    fn fail_pending(&mut self, err: ProtocolError) {
+        let message = err.to_string();
+        for (_, reply) in self.pending.drain(..) {
+            reply.fail(ProtocolError::Protocol(message.clone()));
+        }
+    }
+}
+
+/// Cloneable front end of a [`ProtocolActor`]
+#[derive(Clone)]
+pub struct ProtocolHandle {
+    tx: mpsc::Sender<Request>,
+}
+
+impl ProtocolHandle {
+    pub async fn open(&self, name: &str) -> ProtocolResult<u32> {
+        let (reply, rx) = oneshot::channel();
+        self.submit(Request::Open { name: name.to_string(), reply }, rx).await
+    }
+
+    pub async fn exec_sql(&self, db_id: u32, sql: &str, params: &[Value]) -> ProtocolResult<ExecResult> {
+        let (reply, rx) = oneshot::channel();
+        let request = Request::Exec {
+            db_id,
+            sql: sql.to_string(),
+            params: params.to_vec(),
+            reply,
+        };
+        self.submit(request, rx).await
+    }
+
+    pub async fn query_sql(&self, db_id: u32, sql: &str, params: &[Value]) -> ProtocolResult<Rows> {
+        let (reply, rx) = oneshot::channel();
+        let request = Request::Query {
+            db_id,
+            sql: sql.to_string(),
+            params: params.to_vec(),
+            reply,
+        };
+        self.submit(request, rx).await
+    }
+
+    async fn submit<T>(&self, request: Request, rx: oneshot::Receiver<ProtocolResult<T>>) -> ProtocolResult<T> {
+        self.tx.send(request).await.map_err(|_| actor_gone())?;
+        rx.await.map_err(|_| actor_gone())?
+    }
+}
+
+fn actor_gone() -> ProtocolError {
+    ProtocolError::Protocol("Protocol actor has shut down".to_string())
+}
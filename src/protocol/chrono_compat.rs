@@ -0,0 +1,57 @@
+// Conversions between the timestamp Values and chrono's DateTime<Utc>, so
+// UNIXTIME and ISO8601 columns can be read as real instants. Compiled with the
+// `chrono` feature.
+
+use crate::protocol::message::Value;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+
+// Layouts written by dqlite and SQLite's date functions, with an offset...
+const FORMATS_WITH_OFFSET: &[&str] = &["%Y-%m-%d %H:%M:%S%.f%:z", "%Y-%m-%dT%H:%M:%S%.f%:z"];
+// ...and without one
+const FORMATS_NAIVE: &[&str] = &[
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%dT%H:%M",
+];
+
+impl From<DateTime<Utc>> for Value {
+    fn from(v: DateTime<Utc>) -> Self {
+        Value::Unixtime(v.timestamp())
+    }
+}
+
+impl TryFrom<&Value> for DateTime<Utc> {
+    type Error = String;
+
+    fn try_from(v: &Value) -> Result<Self, Self::Error> {
+        match v {
+            Value::Unixtime(secs) => {
+                DateTime::from_timestamp(*secs, 0).ok_or_else(|| format!("Unix time {} is out of range", secs))
+            }
+            Value::Iso8601(text) => parse_iso8601(text),
+            other => Err(format!("Expected a timestamp, got {:?}", other)),
+        }
+    }
+}
+
+// Text without an offset is UTC, as it is for SQLite
+fn parse_iso8601(text: &str) -> Result<DateTime<Utc>, String> {
+    let text = text.trim();
+    if let Ok(t) = DateTime::parse_from_rfc3339(text) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    for format in FORMATS_WITH_OFFSET {
+        if let Ok(t) = DateTime::parse_from_str(text, format) {
+            return Ok(t.with_timezone(&Utc));
+        }
+    }
+    for format in FORMATS_NAIVE {
+        if let Ok(t) = NaiveDateTime::parse_from_str(text, format) {
+            return Ok(t.and_utc());
+        }
+    }
+    NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .map(|date| date.and_time(Default::default()).and_utc())
+        .map_err(|_| format!("Unrecognised ISO8601 timestamp {:?}", text))
+}
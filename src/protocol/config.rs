@@ -1,9 +1,19 @@
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::Semaphore;
 use crate::protocol::connector::DialFunc;
+use crate::protocol::message::SpanNamer;
+use crate::protocol::retry::RetryPolicy;
+use thiserror::Error;
 
-#[derive(Debug, Clone)]
+// Every malformed variable found by Config::from_env
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Invalid dqlite environment configuration: {}", .0.join("; "))]
+pub struct ConfigError(pub Vec<String>);
+
+#[derive(Clone, Default)]
 pub struct Config {
-    pub dial: Option<Arc<dyn DialFunc>>,
+    pub dial: Option<DialFunc>,
     pub dial_timeout: Duration,
     pub attempt_timeout: Duration,
     pub backoff_factor: Duration,
@@ -11,6 +21,42 @@ pub struct Config {
     pub retry_limit: Option<u32>,
     pub concurrent_leader_conns: u64,
     pub permit_shared: bool,
+    // How long a cached leader address is trusted before it is re-verified
+    pub leader_ttl: Duration,
+    // Bound on a whole ReconnectingDatabase operation, retries included
+    pub operation_timeout: Duration,
+    // Executed in order right after Connector::open opens a database
+    pub init_statements: Vec<String>,
+    // Shared across connectors to bound the total number of in-flight dials
+    pub dial_limiter: Option<Arc<Semaphore>>,
+    // Names the per-request tracing span; message::default_span_name if unset
+    pub span_namer: Option<SpanNamer>,
+    // Replaces the retry_limit/backoff schedule when set
+    pub retry_policy: Option<Arc<dyn RetryPolicy>>,
+    // Fail a dial whose connection's peer isn't the requested address
+    pub verify_peer_address: bool,
+}
+
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Config")
+            .field("dial", &self.dial.as_ref().map(|_| "<dial func>"))
+            .field("dial_timeout", &self.dial_timeout)
+            .field("attempt_timeout", &self.attempt_timeout)
+            .field("backoff_factor", &self.backoff_factor)
+            .field("backoff_cap", &self.backoff_cap)
+            .field("retry_limit", &self.retry_limit)
+            .field("concurrent_leader_conns", &self.concurrent_leader_conns)
+            .field("permit_shared", &self.permit_shared)
+            .field("leader_ttl", &self.leader_ttl)
+            .field("operation_timeout", &self.operation_timeout)
+            .field("init_statements", &self.init_statements)
+            .field("dial_limiter", &self.dial_limiter.as_ref().map(|s| s.available_permits()))
+            .field("span_namer", &self.span_namer.is_some())
+            .field("retry_policy", &self.retry_policy.is_some())
+            .field("verify_peer_address", &self.verify_peer_address)
+            .finish()
+    }
 }
 
 impl Config {
@@ -18,7 +64,53 @@ impl Config {
         Self::default()
     }
 
-    pub fn with_dial(mut self, dial: Arc<dyn DialFunc>) -> Self {
+    /// Build a config from `DQLITE_*` environment variables. Anything unset
+    /// keeps its default; every malformed value is reported at once.
+    ///
+    /// - `DQLITE_DIAL_TIMEOUT`, `DQLITE_ATTEMPT_TIMEOUT`: a number with an
+    ///   `ms`, `s` or `m` suffix; a bare number is seconds
+    /// - `DQLITE_BACKOFF_FACTOR_MS`, `DQLITE_BACKOFF_CAP_MS`: milliseconds
+    /// - `DQLITE_RETRY_LIMIT`, `DQLITE_CONCURRENT_LEADER_CONNS`: integers
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let mut errors = Vec::new();
+        let mut read = |name: &str, parse: fn(&str) -> Option<u64>| {
+            let value = var(name)?;
+            let parsed = parse(value.trim());
+            if parsed.is_none() {
+                errors.push(format!("{}={:?}", name, value));
+            }
+            parsed
+        };
+        let integer = |v: &str| v.parse().ok();
+
+        let dial_timeout = read("DQLITE_DIAL_TIMEOUT", parse_duration_ms);
+        let attempt_timeout = read("DQLITE_ATTEMPT_TIMEOUT", parse_duration_ms);
+        let backoff_factor = read("DQLITE_BACKOFF_FACTOR_MS", integer);
+        let backoff_cap = read("DQLITE_BACKOFF_CAP_MS", integer);
+        let retry_limit = read("DQLITE_RETRY_LIMIT", |v| v.parse::<u32>().ok().map(u64::from));
+        let leader_conns = read("DQLITE_CONCURRENT_LEADER_CONNS", integer);
+
+        if !errors.is_empty() {
+            return Err(ConfigError(errors));
+        }
+
+        // Unset fields stay zero so Connector::new fills in the defaults
+        Ok(Self {
+            dial_timeout: Duration::from_millis(dial_timeout.unwrap_or(0)),
+            attempt_timeout: Duration::from_millis(attempt_timeout.unwrap_or(0)),
+            backoff_factor: Duration::from_millis(backoff_factor.unwrap_or(0)),
+            backoff_cap: Duration::from_millis(backoff_cap.unwrap_or(0)),
+            retry_limit: retry_limit.map(|n| n as u32),
+            concurrent_leader_conns: leader_conns.unwrap_or(0),
+            ..Self::default()
+        })
+    }
+
+    pub fn with_dial(mut self, dial: DialFunc) -> Self {
         self.dial = Some(dial);
         self
     }
@@ -58,7 +150,47 @@ impl Config {
         self
     }
 
-    pub fn with_defaults(mut self, default_dial: Arc<dyn DialFunc>) -> Self {
+    pub fn with_leader_ttl(mut self, ttl: Duration) -> Self {
+        self.leader_ttl = ttl;
+        self
+    }
+
+    pub fn with_operation_timeout(mut self, timeout: Duration) -> Self {
+        self.operation_timeout = timeout;
+        self
+    }
+
+    pub fn with_init_statements(mut self, statements: Vec<String>) -> Self {
+        self.init_statements = statements;
+        self
+    }
+
+    pub fn with_dial_limiter(mut self, limiter: Arc<Semaphore>) -> Self {
+        self.dial_limiter = Some(limiter);
+        self
+    }
+
+    pub fn with_span_namer(mut self, namer: SpanNamer) -> Self {
+        self.span_namer = Some(namer);
+        self
+    }
+
+    pub fn with_retry_policy(mut self, policy: Arc<dyn RetryPolicy>) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// Check that every dialed connection's peer is the address that was
+    /// asked for, to catch a custom dial func connecting to the wrong node.
+    /// Only literal `ip:port` addresses and socket paths can be compared;
+    /// host names and abstract sockets pass unchecked. Leave it off with
+    /// dial funcs that tunnel, such as proxy_dial, whose peer is the proxy.
+    pub fn with_verify_peer_address(mut self, verify: bool) -> Self {
+        self.verify_peer_address = verify;
+        self
+    }
+
+    pub fn with_defaults(mut self, default_dial: DialFunc) -> Self {
         if self.dial.is_none() {
             self.dial = Some(default_dial);
         }
@@ -80,6 +212,27 @@ impl Config {
         if self.concurrent_leader_conns == 0 {
             self.concurrent_leader_conns = 10;
         }
+        if self.leader_ttl.is_zero() {
+            self.leader_ttl = Duration::from_secs(10);
+        }
+        if self.operation_timeout.is_zero() {
+            self.operation_timeout = Duration::from_secs(30);
+        }
         self
     }
-}
\ No newline at end of file
+}
+
+// "500ms", "5s", "2m", or a bare number of seconds, in milliseconds
+fn parse_duration_ms(value: &str) -> Option<u64> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let n: u64 = number.parse().ok()?;
+    match unit {
+        "ms" => Some(n),
+        "s" => n.checked_mul(1_000),
+        "m" => n.checked_mul(60_000),
+        _ => None,
+    }
+}
@@ -1,20 +1,38 @@
 use parking_lot::Mutex;
-use crate::protocol::Protocol;
-use crate::protocol::store::{NodeStore, ObservableNodeStore};
+use crate::protocol::protocol::{Protocol, ProtocolError, ProtocolResult};
+use crate::protocol::store::{NodeInfo, NodeRole, NodeStore, ObservableNodeStore};
 use crate::protocol::config::Config;
+use crate::protocol::database::Database;
+use crate::protocol::deadline::Deadline;
+use crate::protocol::message::{OpenFlags, Rows, Value, VERSION_LEGACY, VERSION_ONE};
+#[cfg(feature = "metrics-hist")]
+use crate::protocol::metrics::{LatencyStats, SharedLatencyRecorder};
+use futures::stream::{self, Stream, StreamExt};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use std::sync::{Arc, Weak};
-use std::io::{self, Read, Write};
-use tokio::net::{TcpStream, UnixStream, SocketAddr as TcpSocketAddr, UnixSocketAddr};
-use std::os::unix::io::AsRawFd;
+use std::io;
+use tokio::net::{TcpStream, UnixStream};
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::future::Future;
 use std::net::SocketAddr as StdSocketAddr;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use std::task::{ready, Context, Poll};
+
+// Size of the per-connection read buffer. Row batches arrive as many small
+// frame reads, so serving them from one larger read saves a syscall per frame.
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
 // Unified address type
 #[derive(Debug, Clone)]
 pub enum Addr {
-    Tcp(TcpSocketAddr),
-    Unix(UnixSocketAddr),
+    Tcp(StdSocketAddr),
+    // None for unnamed sockets, e.g. one end of a socketpair
+    Unix(Option<PathBuf>),
 }
 
 impl std::fmt::Display for Addr {
@@ -32,21 +50,46 @@ enum ConnectionType {
     Unix(UnixStream),
 }
 
+impl ConnectionType {
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self {
+            ConnectionType::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            ConnectionType::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
 pub struct Conn {
     inner: ConnectionType,
+    // Buffered reads: bytes in read_buf[read_pos..read_filled] are pending
+    read_buf: Box<[u8]>,
+    read_pos: usize,
+    read_filled: usize,
 }
 
 impl Conn {
-    pub fn from_tcp(stream: TcpStream) -> Self {
+    fn new(inner: ConnectionType) -> Self {
         Self {
-            inner: ConnectionType::Tcp(stream),
+            inner,
+            read_buf: vec![0u8; READ_BUFFER_SIZE].into_boxed_slice(),
+            read_pos: 0,
+            read_filled: 0,
         }
     }
 
+    pub fn from_tcp(stream: TcpStream) -> Self {
+        Self::new(ConnectionType::Tcp(stream))
+    }
+
     pub fn from_unix(stream: UnixStream) -> Self {
-        Self {
-            inner: ConnectionType::Unix(stream),
-        }
+        Self::new(ConnectionType::Unix(stream))
+    }
+
+    // Two connected ends of an unnamed socket pair, e.g. to wire a client to
+    // an in-process server without any networking
+    pub fn from_unix_pair() -> io::Result<(Self, Self)> {
+        let (a, b) = UnixStream::pair()?;
+        Ok((Self::from_unix(a), Self::from_unix(b)))
     }
 
     pub fn local_addr(&self) -> io::Result<Addr> {
@@ -75,26 +118,91 @@ impl Conn {
             ConnectionType::Unix(s) => s.as_raw_fd(),
         }
     }
+
+    // Give the socket up, e.g. to libdqlite, which then owns and closes it.
+    // Fails if bytes were already read into the buffer, as they'd be lost.
+    pub fn into_raw_fd(self) -> io::Result<RawFd> {
+        if self.read_pos < self.read_filled {
+            return Err(io::Error::other("Conn has buffered bytes that would be lost"));
+        }
+        match self.inner {
+            ConnectionType::Tcp(s) => s.into_std().map(IntoRawFd::into_raw_fd),
+            ConnectionType::Unix(s) => s.into_std().map(IntoRawFd::into_raw_fd),
+        }
+    }
+
+    // Cheap check that the peer hasn't closed the socket, without consuming
+    // anything: peeks one byte without blocking. Pending data (buffered here
+    // or in the kernel) counts as alive; EOF or a socket error does not.
+    pub fn is_alive(&self) -> bool {
+        if self.read_pos < self.read_filled {
+            return true;
+        }
+
+        let mut byte = 0u8;
+        let n = unsafe {
+            libc::recv(
+                self.as_raw_fd(),
+                &mut byte as *mut u8 as *mut libc::c_void,
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        match n {
+            0 => false,
+            n if n > 0 => true,
+            _ => io::Error::last_os_error().kind() == io::ErrorKind::WouldBlock,
+        }
+    }
 }
 
 impl AsyncRead for Conn {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
+        buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        match self.get_mut().inner {
-            ConnectionType::Tcp(ref mut s) => {
-                let result = Pin::new(s).poll_read(cx, buf);
+        let this = self.get_mut();
 
-                return result;
-            }
-            ConnectionType::Unix(ref mut s) => {
-                let result = Pin::new(s).poll_read(cx, buf);
+        // Reads at least as large as our buffer gain nothing from it
+        if this.read_pos == this.read_filled && buf.remaining() >= this.read_buf.len() {
+            return this.inner.poll_read(cx, buf);
+        }
 
-                return result;
-            }
+        // Refill once everything buffered has been handed out. A frame may
+        // straddle two refills; callers use read_exact, which just keeps polling.
+        if this.read_pos == this.read_filled {
+            let mut fill = ReadBuf::new(&mut this.read_buf);
+            ready!(this.inner.poll_read(cx, &mut fill))?;
+            this.read_filled = fill.filled().len();
+            this.read_pos = 0;
         }
+
+        let available = &this.read_buf[this.read_pos..this.read_filled];
+        let n = available.len().min(buf.remaining());
+        buf.put_slice(&available[..n]);
+        this.read_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Exposes the read buffer. Waiting on fill_buf consumes nothing, so unlike
+// read_exact it can be cancelled, e.g. to wait for a response in a select!.
+impl AsyncBufRead for Conn {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.read_pos == this.read_filled {
+            let mut fill = ReadBuf::new(&mut this.read_buf);
+            ready!(this.inner.poll_read(cx, &mut fill))?;
+            this.read_filled = fill.filled().len();
+            this.read_pos = 0;
+        }
+        Poll::Ready(Ok(&this.read_buf[this.read_pos..this.read_filled]))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        this.read_pos = (this.read_pos + amt).min(this.read_filled);
     }
 }
 
@@ -105,74 +213,919 @@ impl AsyncWrite for Conn {
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
         match self.get_mut().inner {
-            ConnectionType::Tcp(ref mut s) => {
-                let result = Pin::new(s).poll_write(cx, buf);
-
-                return result;
-            }
-            ConnectionType::Unix(ref mut s) => {
-                let result = Pin::new(s).poll_write(cx, buf);
-
-                return result;
-            }
+            ConnectionType::Tcp(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            ConnectionType::Unix(ref mut s) => Pin::new(s).poll_write(cx, buf),
         }
     }
     
-    fn poll_flush(self: Pin<&mut self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         match self.get_mut().inner {
-            ConnectionType::Tcp(ref mut s) => {
-                let result = Pin::new(s).poll_flush(cx);
-
-                return result;
-            }
-            ConnectionType::Unix(ref mut s) => {
-                let result = Pin::new(s).poll_flush(cx);
-    
-                return result;
-            }
+            ConnectionType::Tcp(ref mut s) => Pin::new(s).poll_flush(cx),
+            ConnectionType::Unix(ref mut s) => Pin::new(s).poll_flush(cx),
         }
     }
 
-    fn poll_shutdown(self: Pin<&mut self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         match self.get_mut().inner {
-            ConnectionType::Tcp(ref mut s) => {
-                let result = Pin::new(s).poll_shutdown(cx);
-
-                return result;
-            }
-            ConnectionType::Unix(ref mut s) => {
-                let result = Pin::new(s).poll_shutdown(cx);
-
-                return result;
-            }
+            ConnectionType::Tcp(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            ConnectionType::Unix(ref mut s) => Pin::new(s).poll_shutdown(cx),
         }
     }
 }
 
-pub fn dial(addr: &str) -> Result<Conn, String> {
-    if addr.starts_with("unix:") {
-        let path = addr[5..];
+pub async fn dial(addr: &str) -> Result<Conn, String> {
+    if let Some(path) = addr.strip_prefix("unix:") {
         let stream = UnixStream::connect(path).await.map_err(|e| e.to_string())?;
         Ok(Conn::from_unix(stream))
+    } else if let Ok(addr) = addr.parse::<StdSocketAddr>() {
+        let stream = TcpStream::connect(addr).await.map_err(|e| e.to_string())?;
+        Ok(Conn::from_tcp(stream))
     } else {
-        let addr = addr.parse::<StdSocketAddr>().map_err(|e| e.to_string())?;
+        // host:port, tried against every resolved address (e.g. both IPv6 and IPv4)
         let stream = TcpStream::connect(addr).await.map_err(|e| e.to_string())?;
         Ok(Conn::from_tcp(stream))
     }
 }
 
-pub type DialFunc = Arc<dyn Fn(&str) -> Pin<Box<dyn Future<Output = Result<Conn, String>> + Send + Sync + 'static>> + Send + Sync + 'static>;
+// Dial with an upper bound on the whole attempt. When a host name resolves to
+// several addresses the timeout covers all of them, not each one.
+pub async fn dial_timeout(addr: &str, timeout: Duration) -> Result<Conn, String> {
+    match tokio::time::timeout(timeout, dial(addr)).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("Dial {} timed out after {:?}", addr, timeout)),
+    }
+}
+
+pub type DialFunc = Arc<dyn Fn(&str) -> Pin<Box<dyn Future<Output = Result<Conn, String>> + Send + 'static>> + Send + Sync + 'static>;
+
+// Reject addresses no dialer could use before spending an attempt on them:
+// anything but "unix:<path>", "@<name>", "/<path>" or "<host>:<port>"
+fn check_dial_addr(addr: &str) -> Result<(), String> {
+    if addr.starts_with("unix:") || addr.starts_with('@') || addr.starts_with('/') {
+        return Ok(());
+    }
+    if addr.parse::<StdSocketAddr>().is_ok() {
+        return Ok(());
+    }
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err("not a valid dial address".to_string()),
+    }
+}
+
+// DialFunc wrapping the standalone `dial`
+pub fn default_dial_func() -> DialFunc {
+    Arc::new(|addr: &str| {
+        let addr = addr.to_string();
+        Box::pin(async move { dial(&addr).await })
+    })
+}
+
+// DialFunc wrapping `dial_timeout` with a fixed timeout
+pub fn timeout_dial_func(timeout: Duration) -> DialFunc {
+    Arc::new(move |addr: &str| {
+        let addr = addr.to_string();
+        Box::pin(async move { dial_timeout(&addr, timeout).await })
+    })
+}
+
+/// Connection lifecycle events reported to the listener set with
+/// [`Connector::set_event_listener`].
+#[derive(Debug)]
+pub enum ConnEvent {
+    /// A connection to the leader was handed out
+    Connected(Addr),
+    /// A connection was given up after it failed, e.g. by
+    /// [`ReconnectingDatabase`](crate::protocol::database::ReconnectingDatabase)
+    /// before reconnecting
+    Disconnected(Addr, ProtocolError),
+    /// A different leader address was discovered; `from` is None for the
+    /// first one
+    LeaderChanged { from: Option<String>, to: String },
+}
+
+type EventListener = Arc<dyn Fn(ConnEvent) + Send + Sync>;
 
 pub struct Connector<S: NodeStore + Send + Sync> {
-    clientID: u64,
+    client_id: u64,
     store: Arc<ObservableNodeStore<S>>,
-    nodeID: u64,
-    nodeAddr: String,
-    lt: Mutex<Option<Weak<LeaderTracker>>>,
+    node_id: u64,
+    node_addr: String,
+    // Failure domain of this client; nodes sharing it are tried first
+    failure_domain: Option<u64>,
+    lt: Mutex<Option<LeaderTracker>>,
     config: Arc<Config>,
+    conns: ConnectionRegistry,
+    listener: Mutex<Option<EventListener>>,
+    // Last leader reported through ConnEvent::LeaderChanged. Unlike lt it
+    // survives the cache being reset, so `from` stays meaningful.
+    reported_leader: Mutex<Option<String>>,
+    // Latencies of requests on every connection this connector dials
+    #[cfg(feature = "metrics-hist")]
+    latency: SharedLatencyRecorder,
+}
+
+/// The client side of a cluster: a [`Connector`] under the name the
+/// observability APIs use, e.g. `Client::latency_stats`.
+pub type Client<S> = Connector<S>;
+
+impl<S: NodeStore + Send + Sync> Connector<S> {
+    pub fn new(client_id: u64, store: Arc<ObservableNodeStore<S>>, config: Config) -> Self {
+        // Without a custom dial func, default to one bounded by the resolved dial_timeout
+        let mut config = config;
+        let custom_dial = config.dial.take();
+        let mut config = config.with_defaults(default_dial_func());
+        config.dial = Some(custom_dial.unwrap_or_else(|| timeout_dial_func(config.dial_timeout)));
+
+        Self {
+            client_id,
+            store,
+            node_id: 0,
+            node_addr: String::new(),
+            failure_domain: None,
+            lt: Mutex::new(None),
+            config: Arc::new(config),
+            conns: ConnectionRegistry::default(),
+            listener: Mutex::new(None),
+            reported_leader: Mutex::new(None),
+            #[cfg(feature = "metrics-hist")]
+            latency: SharedLatencyRecorder::default(),
+        }
+    }
+
+    /// Start building a connector. This is the usual way to create a client:
+    /// the builder picks a fresh client id and starts with an empty leader
+    /// tracker, so only the store and config need to be supplied.
+    ///
+    /// ```no_run
+    /// use std::sync::Arc;
+    /// use tokio::sync::Mutex;
+    /// use dqlite_rs::protocol::config::Config;
+    /// use dqlite_rs::protocol::connector::Connector;
+    /// use dqlite_rs::protocol::database::Database;
+    /// use dqlite_rs::protocol::store::{InMemoryNodeStore, ObservableNodeStore};
+    ///
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// let store = Arc::new(ObservableNodeStore::new(InMemoryNodeStore::new()));
+    /// let connector = Connector::builder()
+    ///     .store(store)
+    ///     .config(Config::new())
+    ///     .build()?;
+    ///
+    /// let proto = connector.connect().await?;
+    /// let db = Database::open(Arc::new(Mutex::new(proto)), "app").await?;
+    /// let rows = db.query("SELECT id, name FROM users", &[]).await?;
+    /// for row in rows {
+    ///     println!("{:?}", row.values());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> ConnectorBuilder<S> {
+        ConnectorBuilder::new()
+    }
+
+    // Find the leader and return a protocol connected to it, retrying with
+    // backoff up to the configured retry limit
+    pub async fn connect(&self) -> ProtocolResult<Protocol> {
+        self.connect_with_deadline(Deadline::none()).await
+    }
+
+    /// Like [`Connector::connect`], but every dial, handshake and backoff sleep
+    /// draws from the single `deadline`. The returned protocol keeps the
+    /// deadline, so requests issued on it share the same budget.
+    pub async fn connect_with_deadline(&self, deadline: Deadline) -> ProtocolResult<Protocol> {
+        let mut attempt: u32 = 0;
+        // Membership updates cut the backoff short so the next attempt uses
+        // the new node list right away
+        let mut changes = self.store.subscribe();
+
+        loop {
+            let err = match self.connect_attempt_all(deadline).await {
+                Ok(proto) => {
+                    self.note_leader(&proto);
+                    return Ok(proto);
+                }
+                Err(ProtocolError::DeadlineExceeded) => return Err(ProtocolError::DeadlineExceeded),
+                Err(err) => err,
+            };
+
+            attempt += 1;
+            let Some(delay) = self.retry_delay(&err, attempt) else {
+                return Err(err);
+            };
+            tokio::select! {
+                result = deadline.sleep(delay) => result?,
+                _ = store_changed(&mut changes) => {}
+            }
+        }
+    }
+
+    /// Connect to node `id` itself, leader or not, e.g. for maintenance such
+    /// as transferring leadership away from it. The address comes from the
+    /// store and there is no redirection or retry. An id missing from the
+    /// store fails with an I/O error of kind `NotFound`.
+    pub async fn connect_to(&self, id: u64) -> ProtocolResult<Protocol> {
+        let node = self
+            .store
+            .get_by_id(id)
+            .await
+            .map_err(|e| ProtocolError::Protocol(format!("Failed to get node {} from store: {}", id, e)))?
+            .ok_or_else(|| {
+                ProtocolError::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Node {} is not in the store", id),
+                ))
+            })?;
+
+        let deadline = Deadline::none().cap(self.config.attempt_timeout);
+        let (mut proto, _) = self.dial_and_handshake(&node.addr, deadline).await?;
+        proto.set_deadline(Deadline::none());
+        Ok(proto)
+    }
+
+    /// Connect to the leader and open `name` on the new connection, then run
+    /// the configured init statements (e.g. `PRAGMA foreign_keys=ON`) in
+    /// order. If any of them fails the connection is dropped and the error
+    /// returned, so callers never see a half-initialised database.
+    pub async fn open(&self, name: &str) -> ProtocolResult<Database> {
+        self.open_with_deadline(name, Deadline::none()).await
+    }
+
+    /// Like [`Connector::open`], with connecting, opening and the init
+    /// statements bounded by `deadline`. The returned database is not: its
+    /// connection is reset to no deadline once set up.
+    pub async fn open_with_deadline(&self, name: &str, deadline: Deadline) -> ProtocolResult<Database> {
+        let proto = self.connect_with_deadline(deadline).await?;
+        let proto = Arc::new(tokio::sync::Mutex::new(proto));
+        let db = Database::open(proto.clone(), name).await?;
+        for sql in &self.config.init_statements {
+            db.execute(sql, &[]).await?;
+        }
+        proto.lock().await.set_deadline(Deadline::none());
+        Ok(db)
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Request latency percentiles per request type, over every connection
+    /// this connector has dialed
+    #[cfg(feature = "metrics-hist")]
+    pub fn latency_stats(&self) -> LatencyStats {
+        self.latency.stats()
+    }
+
+    pub fn client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    // Local node identity set with ConnectorBuilder::node; 0 and "" if none
+    pub fn node_id(&self) -> u64 {
+        self.node_id
+    }
+
+    pub fn node_addr(&self) -> &str {
+        &self.node_addr
+    }
+
+    pub fn failure_domain(&self) -> Option<u64> {
+        self.failure_domain
+    }
+
+    /// Connection state of every address this connector has dialed, for
+    /// dashboards. Addresses are listed in sorted order.
+    pub fn states(&self) -> Vec<NodeConnState> {
+        self.conns.states()
+    }
+
+    /// Call `listener` on connects, disconnects and leader changes, replacing
+    /// any previous listener. It runs inline on the connecting task, so it
+    /// should return quickly.
+    pub fn set_event_listener(&self, listener: Box<dyn Fn(ConnEvent) + Send + Sync>) {
+        *self.listener.lock() = Some(Arc::from(listener));
+    }
+
+    pub fn clear_event_listener(&self) {
+        *self.listener.lock() = None;
+    }
+
+    pub(crate) fn emit(&self, event: ConnEvent) {
+        // Not called under the lock, so the listener may replace itself
+        let listener = self.listener.lock().clone();
+        if let Some(listener) = listener {
+            listener(event);
+        }
+    }
+
+    // Report a connection to the leader at `addr`, and the leader itself if it moved
+    fn note_leader(&self, proto: &Protocol) {
+        let addr = proto.addr();
+        let previous = self.reported_leader.lock().replace(addr.to_string());
+        if previous.as_deref() != Some(addr) {
+            self.emit(ConnEvent::LeaderChanged { from: previous, to: addr.to_string() });
+        }
+        if let Ok(peer) = proto.peer_addr() {
+            self.emit(ConnEvent::Connected(peer));
+        }
+    }
+
+    /// Assign roles so the cluster has exactly `voters` voters and `standbys`
+    /// stand-bys, with every other node a spare. Within each role, nodes in
+    /// failure domains the role doesn't cover yet are picked first; domains
+    /// come from the store, since the cluster reply doesn't carry them. After
+    /// that nodes keep their current role where possible (voters stay voters
+    /// first), and ties go by id. Running it again against a cluster already
+    /// in shape sends nothing.
+    pub async fn apply_topology(&self, voters: usize, standbys: usize) -> ProtocolResult<()> {
+        let mut proto = self.connect().await?;
+        let mut nodes = proto.cluster().await?;
+        let known = self
+            .store
+            .get_all()
+            .await
+            .map_err(|e| ProtocolError::Protocol(format!("Failed to read node store: {}", e)))?;
+        for node in &mut nodes {
+            node.failure_domain = known.iter().find(|k| k.id == node.id).and_then(|k| k.failure_domain);
+        }
+        if voters == 0 || voters + standbys > nodes.len() {
+            return Err(ProtocolError::Protocol(format!(
+                "Cannot place {} voters and {} stand-bys on {} nodes",
+                voters,
+                standbys,
+                nodes.len()
+            )));
+        }
+
+        let plan = plan_topology(&nodes, voters, standbys);
+
+        // Promote before demoting, so the voter count never dips below target
+        // while the changes are being applied
+        let (promotions, others): (Vec<_>, Vec<_>) =
+            plan.into_iter().partition(|(_, role)| *role == NodeRole::VOTER);
+        for (id, role) in promotions.into_iter().chain(others) {
+            proto.assign(id, role).await?;
+        }
+        Ok(())
+    }
+
+    /// Like [`Connector::connect`], but gives up with
+    /// [`ProtocolError::Cancelled`] as soon as `token` is cancelled. Whatever
+    /// dial, handshake or backoff sleep is in flight at that point is dropped,
+    /// so a shutting-down app doesn't wait out the retry schedule.
+    pub async fn connect_with_cancel(&self, token: CancellationToken) -> ProtocolResult<Protocol> {
+        tokio::select! {
+            biased;
+            _ = token.cancelled() => Err(ProtocolError::Cancelled),
+            result = self.connect() => result,
+        }
+    }
+
+    // Delay before retrying after `attempt` failures, None to give up. Without
+    // a configured RetryPolicy every error is retried up to retry_limit.
+    pub(crate) fn retry_delay(&self, err: &ProtocolError, attempt: u32) -> Option<Duration> {
+        match &self.config.retry_policy {
+            Some(policy) => policy.should_retry(err, attempt),
+            None => (attempt <= self.config.retry_limit.unwrap_or(0)).then(|| self.backoff(attempt)),
+        }
+    }
+
+    // Exponential backoff: factor * 2^(attempt - 1), capped
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(16);
+        let backoff = self.config.backoff_factor.saturating_mul(1 << exp);
+        backoff.min(self.config.backoff_cap)
+    }
+
+    /// Tie the cached leader to the connection behind `db`. Once `db` and
+    /// all its clones are dropped the next connect rediscovers the leader
+    /// instead of trusting the cache.
+    pub async fn track_database(&self, db: &Database) {
+        let conn = db.connection();
+        let mut tracker = LeaderTracker::new(conn.lock().await.addr());
+        tracker.proto = Some(Arc::downgrade(conn));
+        *self.lt.lock() = Some(tracker);
+    }
+
+    // Forget the cached leader, e.g. after it said it isn't the leader any
+    // more, so the next connect asks the cluster instead of trusting the TTL
+    pub(crate) fn forget_leader(&self) {
+        *self.lt.lock() = None;
+    }
+
+    // Try the cached leader first, then each known node once, returning a
+    // protocol connected to the leader
+    async fn connect_attempt_all(&self, deadline: Deadline) -> ProtocolResult<Protocol> {
+        match self.connect_cached_leader(deadline).await {
+            Ok(Some(proto)) => return Ok(proto),
+            Err(ProtocolError::DeadlineExceeded) => return Err(ProtocolError::DeadlineExceeded),
+            // A stale or unreachable cached leader just means a full scan
+            Ok(None) | Err(_) => {}
+        }
+
+        let proto = self.connect_scan(deadline).await?;
+        *self.lt.lock() = Some(LeaderTracker::new(proto.addr()));
+        Ok(proto)
+    }
+
+    // Reuse the last known leader. Within leader_ttl the address is trusted as
+    // is; past it, the node is asked again via leader() before being used.
+    async fn connect_cached_leader(&self, deadline: Deadline) -> ProtocolResult<Option<Protocol>> {
+        let cached = {
+            let mut lt = self.lt.lock();
+            let cached = lt.as_ref().and_then(|lt| {
+                lt.leader_addr()
+                    .map(|addr| (addr.to_string(), lt.is_fresh(self.config.leader_ttl)))
+            });
+            // A tracker whose protocol was dropped is forgotten, so the
+            // caller falls back to a full rediscovery
+            if cached.is_none() {
+                *lt = None;
+            }
+            cached
+        };
+        let Some((addr, fresh)) = cached else {
+            return Ok(None);
+        };
+
+        let result = if fresh {
+            let attempt_deadline = deadline.cap(self.config.attempt_timeout);
+            self.dial_and_handshake(&addr, attempt_deadline).await.map(|(mut proto, _)| {
+                proto.set_deadline(deadline);
+                Some(proto)
+            })
+        } else {
+            self.connect_attempt_one(&addr, deadline).await
+        };
+
+        match &result {
+            Ok(Some(proto)) if !fresh => *self.lt.lock() = Some(LeaderTracker::new(proto.addr())),
+            Ok(Some(_)) => {}
+            _ => *self.lt.lock() = None,
+        }
+        result
+    }
+
+    async fn connect_scan(&self, deadline: Deadline) -> ProtocolResult<Protocol> {
+        let nodes = self.candidates().await?;
+
+        // One bad entry must not hide the others: every candidate is tried
+        // and its failure kept for the final error
+        let mut failures = Vec::new();
+        for node in nodes {
+            if let Err(reason) = check_dial_addr(&node.addr) {
+                failures.push(format!("{}: {}", node.addr, reason));
+                continue;
+            }
+            match self.connect_attempt_one(&node.addr, deadline).await {
+                Ok(Some(proto)) => return Ok(proto),
+                Ok(None) => failures.push(format!("{}: no leader known", node.addr)),
+                Err(ProtocolError::DeadlineExceeded) => return Err(ProtocolError::DeadlineExceeded),
+                Err(err) => failures.push(format!("{}: {}", node.addr, err)),
+            }
+        }
+
+        if failures.is_empty() {
+            return Err(ProtocolError::Protocol("No available dqlite leader server found".to_string()));
+        }
+        // Reported as I/O so callers keep treating it as transient
+        Err(ProtocolError::Io(io::Error::new(
+            io::ErrorKind::NotConnected,
+            format!("No available dqlite leader server found ({})", failures.join("; ")),
+        )))
+    }
+
+    // Connect to `addr` and follow its view of the leader. Returns None if the
+    // node doesn't currently know of a leader.
+    async fn connect_attempt_one(&self, addr: &str, deadline: Deadline) -> ProtocolResult<Option<Protocol>> {
+        let attempt_deadline = deadline.cap(self.config.attempt_timeout);
+
+        let (mut proto, (_, leader_addr)) = self.dial_and_handshake(addr, attempt_deadline).await?;
+
+        if leader_addr == addr {
+            proto.set_deadline(deadline);
+            return Ok(Some(proto));
+        }
+        // Not the leader, so this connection is dropped
+        self.conns.set_idle(addr);
+        if leader_addr.is_empty() {
+            return Ok(None);
+        }
+
+        // The node pointed us elsewhere; make sure the leader agrees it's the leader
+        let (mut proto, (_, confirmed)) = self.dial_and_handshake(&leader_addr, attempt_deadline).await?;
+        if confirmed != leader_addr {
+            self.conns.set_idle(&leader_addr);
+            return Ok(None);
+        }
+        proto.set_deadline(deadline);
+        Ok(Some(proto))
+    }
+
+    // Feed the server list learned by a protocol's heartbeat back into the
+    // store. Heartbeats carry only ids and addresses, so stored nodes keep
+    // their role and failure domain and just take the new address; nodes new
+    // to the store are added as voters, and nodes missing from the heartbeat
+    // are dropped. Use Protocol::cluster for the actual roles.
+    pub async fn sync_servers(&self, proto: &Protocol) -> ProtocolResult<()> {
+        let servers = proto.servers();
+        if servers.is_empty() {
+            return Ok(());
+        }
+        let stored = self.store.get_all().await.map_err(|e| {
+            ProtocolError::Protocol(format!("Failed to get nodes from store: {}", e))
+        })?;
+        let merged = servers
+            .iter()
+            .map(|server| match stored.iter().find(|node| node.same_node(server)) {
+                Some(node) => NodeInfo { addr: server.addr.clone(), ..node.clone() },
+                None => server.clone(),
+            })
+            .collect();
+        self.store.set_all(merged).await.map_err(|e| {
+            ProtocolError::Protocol(format!("Failed to update store with servers: {}", e))
+        })
+    }
+
+    /// Run the same query on every known node, `concurrent_leader_conns` at a
+    /// time, and return the first successful result; the outstanding attempts
+    /// are dropped.
+    ///
+    /// dqlite only serves queries on the leader, so followers answer with a
+    /// not-leader failure and the leader's reply usually wins. This mostly
+    /// helps when the leader is unknown or a node is slow to respond.
+    pub async fn query_any(&self, db: &str, sql: &str, params: &[Value]) -> ProtocolResult<Rows> {
+        let mut pending = self.fan_out(db, sql, params).await?;
+
+        let mut last_err = None;
+        while let Some(result) = pending.next().await {
+            match result {
+                Ok(rows) => return Ok(rows),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| ProtocolError::Protocol("No nodes to query".to_string())))
+    }
+
+    // The query's result on each known node in completion order. At most
+    // concurrent_leader_conns run at once; the next node is dialed as soon as
+    // one finishes.
+    async fn fan_out<'a>(
+        &'a self,
+        db: &'a str,
+        sql: &'a str,
+        params: &'a [Value],
+    ) -> ProtocolResult<impl Stream<Item = ProtocolResult<Rows>> + 'a> {
+        let nodes = self.candidates().await?;
+        let limit = (self.config.concurrent_leader_conns as usize).max(1);
+
+        let results = stream::iter(nodes)
+            .map(move |node| async move {
+                let deadline = Deadline::none().cap(self.config.attempt_timeout);
+                let (mut proto, _) = self.dial_and_handshake(&node.addr, deadline).await?;
+                let db_id = proto.open(db, OpenFlags::default()).await?;
+                proto.query_sql(db_id, sql, params).await
+            })
+            .buffer_unordered(limit);
+        Ok(results)
+    }
+
+    // Known nodes in the order they should be tried: those in our failure
+    // domain first, each group in store order. dqlite serves every query on
+    // the leader, so a nearby node can't answer reads itself, but asking it
+    // first finds the leader (or answers query_any) with the fewest slow
+    // cross-domain round trips.
+    async fn candidates(&self) -> ProtocolResult<Vec<NodeInfo>> {
+        let mut nodes = self.store.get_all().await.map_err(|e| {
+            ProtocolError::Protocol(format!("Failed to get nodes from store: {}", e))
+        })?;
+        if let Some(local) = self.failure_domain {
+            nodes.sort_by_key(|node| node.failure_domain != Some(local));
+        }
+        Ok(nodes)
+    }
+
+    // Dial and handshake `addr`, recording the outcome in the connection
+    // registry. Also returns the node's view of the leader, which the version
+    // probe asks for anyway.
+    async fn dial_and_handshake(&self, addr: &str, deadline: Deadline) -> ProtocolResult<(Protocol, (u64, String))> {
+        self.conns.set_connecting(addr);
+        let start = Instant::now();
+        let result = self.dial_and_handshake_untracked(addr, deadline).await;
+        match &result {
+            Ok(_) => self.conns.set_connected(addr, start.elapsed()),
+            Err(err) => self.conns.set_failed(addr, err),
+        }
+        result
+    }
+
+    // Agree on a protocol version the way go-dqlite does: offer VERSION_ONE
+    // and probe it with a leader request. A server predating it hangs up on
+    // the unknown version, so an EOF or reset (but not a timeout) leads to a
+    // fresh dial offering VERSION_LEGACY.
+    async fn dial_and_handshake_untracked(&self, addr: &str, deadline: Deadline) -> ProtocolResult<(Protocol, (u64, String))> {
+        let mut proto = self.dial_version(addr, deadline, VERSION_ONE).await?;
+        match proto.leader().await {
+            Ok(leader) => Ok((proto, leader)),
+            Err(ProtocolError::Io(err)) if err.kind() != io::ErrorKind::TimedOut => {
+                log::debug!("{} hung up on protocol version {} ({}), retrying with the legacy version", addr, VERSION_ONE, err);
+                let mut proto = self.dial_version(addr, deadline, VERSION_LEGACY).await?;
+                let leader = proto.leader().await?;
+                Ok((proto, leader))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn dial_version(&self, addr: &str, deadline: Deadline, version: u64) -> ProtocolResult<Protocol> {
+        // Custom dial funcs are bounded by config.dial_timeout through the deadline
+        let dial = self.config.dial.clone().unwrap_or_else(default_dial_func);
+        let dial_deadline = deadline.cap(self.config.dial_timeout);
+
+        let conn = dial_deadline
+            .run(async {
+                // Hold a permit for the duration of the dial only
+                let _permit = match &self.config.dial_limiter {
+                    Some(limiter) => Some(limiter.acquire().await.map_err(|_| {
+                        ProtocolError::Protocol("Dial limiter closed".to_string())
+                    })?),
+                    None => None,
+                };
+
+                dial(addr).await.map_err(|e| {
+                    ProtocolError::Io(io::Error::new(io::ErrorKind::ConnectionRefused, e))
+                })
+            })
+            .await?;
+        if self.config.verify_peer_address {
+            verify_peer(addr, &conn)?;
+        }
+
+        let mut proto = Protocol::with_version(conn, addr, version);
+        proto.set_deadline(deadline);
+        if let Some(namer) = self.config.span_namer {
+            proto.set_span_namer(namer);
+        }
+        #[cfg(feature = "metrics-hist")]
+        proto.set_latency_recorder(self.latency.clone());
+        proto.handshake().await?;
+        Ok(proto)
+    }
+}
+
+// Fail if the connection's peer provably isn't `addr`. Peers that can't be
+// compared with it (host names, abstract or unnamed sockets) pass.
+fn verify_peer(addr: &str, conn: &Conn) -> ProtocolResult<()> {
+    let peer = conn.peer_addr()?;
+    let matches = match &peer {
+        Addr::Tcp(peer) => addr.parse::<StdSocketAddr>().ok().map(|requested| *peer == requested),
+        Addr::Unix(Some(path)) => {
+            let requested = addr.strip_prefix("unix:").unwrap_or(addr);
+            (!requested.starts_with('@')).then(|| path.as_path() == std::path::Path::new(requested))
+        }
+        Addr::Unix(None) => None,
+    };
+    if matches == Some(false) {
+        return Err(ProtocolError::Protocol(format!(
+            "Dialed {} but the connection's peer is {}",
+            addr, peer
+        )));
+    }
+    Ok(())
+}
+
+pub struct ConnectorBuilder<S: NodeStore + Send + Sync> {
+    store: Option<Arc<ObservableNodeStore<S>>>,
+    config: Config,
+    client_id: Option<u64>,
+    node_id: u64,
+    node_addr: String,
+    failure_domain: Option<u64>,
 }
 
+impl<S: NodeStore + Send + Sync> ConnectorBuilder<S> {
+    pub fn new() -> Self {
+        Self {
+            store: None,
+            config: Config::default(),
+            client_id: None,
+            node_id: 0,
+            node_addr: String::new(),
+            failure_domain: None,
+        }
+    }
+
+    pub fn store(mut self, store: Arc<ObservableNodeStore<S>>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    // Override the generated client id, e.g. to keep it stable across restarts
+    pub fn client_id(mut self, client_id: u64) -> Self {
+        self.client_id = Some(client_id);
+        self
+    }
+
+    // Identity of the local node, when the client runs alongside one
+    pub fn node(mut self, id: u64, addr: &str) -> Self {
+        self.node_id = id;
+        self.node_addr = addr.to_string();
+        self
+    }
+
+    // Failure domain the client runs in, matched against
+    // NodeInfo::failure_domain to try nearby nodes first
+    pub fn failure_domain(mut self, domain: u64) -> Self {
+        self.failure_domain = Some(domain);
+        self
+    }
+
+    pub fn build(self) -> ProtocolResult<Connector<S>> {
+        let store = self
+            .store
+            .ok_or_else(|| ProtocolError::Protocol("Connector requires a node store".to_string()))?;
+        let client_id = self.client_id.unwrap_or_else(generate_client_id);
+
+        let mut connector = Connector::new(client_id, store, self.config);
+        connector.node_id = self.node_id;
+        connector.node_addr = self.node_addr;
+        connector.failure_domain = self.failure_domain;
+        Ok(connector)
+    }
+}
+
+impl<S: NodeStore + Send + Sync> Default for ConnectorBuilder<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Role changes needed to reach the requested counts, as (node id, new role).
+// Candidates for each role are ordered by current role preference, then id;
+// a candidate whose failure domain the role already has is passed over while
+// one from a new domain (or with no domain) is left.
+fn plan_topology(nodes: &[NodeInfo], voters: usize, standbys: usize) -> Vec<(u64, NodeRole)> {
+    fn rank(role: &NodeRole, order: &[NodeRole; 3]) -> usize {
+        order.iter().position(|r| r == role).unwrap_or(order.len())
+    }
+
+    let mut remaining: Vec<&NodeInfo> = nodes.iter().collect();
+    let mut desired: Vec<(u64, NodeRole)> = Vec::with_capacity(nodes.len());
+
+    for (role, count, order) in [
+        (NodeRole::VOTER, voters, [NodeRole::VOTER, NodeRole::STAND_BY, NodeRole::SPARE]),
+        (NodeRole::STAND_BY, standbys, [NodeRole::STAND_BY, NodeRole::SPARE, NodeRole::VOTER]),
+    ] {
+        remaining.sort_by_key(|n| (rank(&n.role, &order), n.id));
+        let mut domains = Vec::new();
+        for _ in 0..count {
+            let pick = remaining
+                .iter()
+                .position(|n| n.failure_domain.is_none_or(|d| !domains.contains(&d)))
+                .unwrap_or(0);
+            let node = remaining.remove(pick);
+            domains.extend(node.failure_domain);
+            desired.push((node.id, role));
+        }
+    }
+    for node in remaining {
+        desired.push((node.id, NodeRole::SPARE));
+    }
+
+    desired
+        .into_iter()
+        .filter(|(id, role)| nodes.iter().any(|n| n.id == *id && n.role != *role))
+        .collect()
+}
+
+// Resolves on the next store update. A closed channel never resolves, so the
+// caller's backoff just runs its course.
+async fn store_changed(changes: &mut broadcast::Receiver<Vec<NodeInfo>>) {
+    match changes.recv().await {
+        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+        Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+    }
+}
+
+// Random non-zero client id, seeded from the per-process hasher keys so no
+// extra dependency is needed
+fn generate_client_id() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0),
+    );
+    hasher.finish().max(1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnState {
+    /// Reachable, but the connector holds no connection to it (not the leader)
+    Idle,
+    Connecting,
+    Connected,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeConnState {
+    pub addr: String,
+    pub state: ConnState,
+    /// Error of the most recent failed dial or handshake
+    pub last_error: Option<String>,
+    /// Dial plus handshake time of the most recent successful connect
+    pub last_rtt: Option<Duration>,
+    pub updated_at: Instant,
+}
+
+// Per address connection outcomes, updated by the connect loop
+#[derive(Default)]
+struct ConnectionRegistry {
+    nodes: Mutex<HashMap<String, NodeConnState>>,
+}
+
+impl ConnectionRegistry {
+    fn update(&self, addr: &str, f: impl FnOnce(&mut NodeConnState)) {
+        let mut nodes = self.nodes.lock();
+        let entry = nodes.entry(addr.to_string()).or_insert_with(|| NodeConnState {
+            addr: addr.to_string(),
+            state: ConnState::Idle,
+            last_error: None,
+            last_rtt: None,
+            updated_at: Instant::now(),
+        });
+        f(entry);
+        entry.updated_at = Instant::now();
+    }
+
+    fn set_connecting(&self, addr: &str) {
+        self.update(addr, |n| n.state = ConnState::Connecting);
+    }
+
+    fn set_connected(&self, addr: &str, rtt: Duration) {
+        self.update(addr, |n| {
+            n.state = ConnState::Connected;
+            n.last_rtt = Some(rtt);
+        });
+    }
+
+    fn set_failed(&self, addr: &str, err: &ProtocolError) {
+        self.update(addr, |n| {
+            n.state = ConnState::Failed;
+            n.last_error = Some(err.to_string());
+        });
+    }
+
+    fn set_idle(&self, addr: &str) {
+        self.update(addr, |n| n.state = ConnState::Idle);
+    }
+
+    fn states(&self) -> Vec<NodeConnState> {
+        let mut states: Vec<_> = self.nodes.lock().values().cloned().collect();
+        states.sort_by(|a, b| a.addr.cmp(&b.addr));
+        states
+    }
+}
+
+// Cached leader address. When `proto` is set, the cache is tied to that
+// database connection (see Connector::track_database): once it has been
+// dropped the leader counts as unknown.
 pub struct LeaderTracker {
     pub last_known_leader_addr: String,
-    pub proto: Weak<Protocol>,
+    pub proto: Option<Weak<tokio::sync::Mutex<Protocol>>>,
+    pub discovered_at: Instant,
+}
+
+impl LeaderTracker {
+    pub fn new(addr: &str) -> Self {
+        Self {
+            last_known_leader_addr: addr.to_string(),
+            proto: None,
+            discovered_at: Instant::now(),
+        }
+    }
+
+    // The cached address, unless the tracked protocol is gone
+    pub fn leader_addr(&self) -> Option<&str> {
+        match &self.proto {
+            Some(proto) if proto.upgrade().is_none() => None,
+            _ => Some(&self.last_known_leader_addr),
+        }
+    }
+
+    // Whether the cached address is still young enough to use without asking
+    pub fn is_fresh(&self, ttl: Duration) -> bool {
+        self.discovered_at.elapsed() < ttl
+    }
 }
\ No newline at end of file
@@ -0,0 +1,591 @@
+use std::sync::Arc;
+use std::pin::Pin;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use crate::protocol::message::{ExecResult, OpenFlags, Row, Rows, Value};
+use crate::protocol::connector::{ConnEvent, Connector};
+use crate::protocol::deadline::Deadline;
+use crate::protocol::protocol::{Protocol, ProtocolError, ProtocolResult, SqliteCode, StmtInfo};
+use crate::protocol::retry::is_unknown_database;
+use crate::protocol::store::NodeStore;
+
+// Number of attempts made by Statement::exec_idempotent before giving up
+const IDEMPOTENT_ATTEMPTS: u32 = 3;
+
+/// Caller supplied check telling whether the write tagged with an idempotency
+/// key has already been applied on the server. It is given the database on
+/// the connection the retry will use.
+pub type AppliedCheck = Arc<dyn Fn(Database, String) -> Pin<Box<dyn Future<Output = ProtocolResult<bool>> + Send + 'static>> + Send + Sync + 'static>;
+
+/// Callback run after every statement issued through [`Database::execute`] or
+/// [`Database::query`], with the SQL, the time it took and, for a successful
+/// exec, its result. Queries and failed statements pass `None`.
+pub type Observer = Arc<ObserverFn>;
+
+pub type ObserverFn = dyn Fn(&str, Duration, Option<&ExecResult>) + Send + Sync + 'static;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdempotentExec {
+    /// The statement ran on this call
+    Executed(ExecResult),
+    /// A previous attempt had already applied the write, so it was not re-run
+    AlreadyApplied,
+}
+
+/// When statements queued with [`Database::queue`] are sent to the server.
+/// Named apart from the YAML store's `FlushPolicy`, which is about files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WritePolicy {
+    /// Send each queued statement right away
+    #[default]
+    Immediate,
+    /// Buffer queued statements and send them as one pipelined write every
+    /// `n` statements, or before anything else runs on the database
+    Batched { n: usize },
+}
+
+/// Outcome of [`Database::queue`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Queued {
+    /// Buffered; its result comes back from whichever call sends the batch
+    Pending,
+    /// The batch was sent: the results of every statement in it, this one
+    /// last, in the order they were queued
+    Sent(Vec<ExecResult>),
+}
+
+type PendingWrites = Arc<Mutex<Vec<(String, Vec<Value>)>>>;
+
+// Handle to a database opened on a single connection
+#[derive(Clone)]
+pub struct Database {
+    proto: Arc<Mutex<Protocol>>,
+    id: u32,
+    name: String,
+    observer: Option<Observer>,
+    write_policy: WritePolicy,
+    // Statements buffered under WritePolicy::Batched, shared by clones
+    pending: PendingWrites,
+}
+
+// Drop doesn't send what is still buffered: that would mean blocking I/O, and
+// a deadlock if the connection is locked further up the stack. The last handle
+// to go only warns about statements it discards; call flush() or close() to
+// send them.
+impl Drop for Database {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.pending) != 1 {
+            return;
+        }
+        let Ok(pending) = self.pending.try_lock() else {
+            return;
+        };
+        if !pending.is_empty() {
+            log::warn!(
+                "Discarding {} queued statements on {}: flush() or close() wasn't called",
+                pending.len(),
+                self.name
+            );
+        }
+    }
+}
+
+impl Database {
+    pub async fn open(proto: Arc<Mutex<Protocol>>, name: &str) -> ProtocolResult<Self> {
+        let id = proto.lock().await.open(name, OpenFlags::default()).await?;
+        Ok(Self {
+            proto,
+            id,
+            name: name.to_string(),
+            observer: None,
+            write_policy: WritePolicy::Immediate,
+            pending: PendingWrites::default(),
+        })
+    }
+
+    /// Open `name` read-only. The server is asked to open the database with
+    /// SQLITE_OPEN_READONLY, and the returned handle only exposes queries.
+    pub async fn read_only(proto: Arc<Mutex<Protocol>>, name: &str) -> ProtocolResult<ReadOnlyDatabase> {
+        let id = proto.lock().await.open(name, OpenFlags::MAIN_DB | OpenFlags::READONLY).await?;
+        Ok(ReadOnlyDatabase {
+            db: Self {
+                proto,
+                id,
+                name: name.to_string(),
+                observer: None,
+                write_policy: WritePolicy::Immediate,
+                pending: PendingWrites::default(),
+            },
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // The connection shared by this handle and its clones
+    pub(crate) fn connection(&self) -> &Arc<Mutex<Protocol>> {
+        &self.proto
+    }
+
+    /// Install a callback invoked after each exec/query, e.g. for a slow-query
+    /// log. Clones made afterwards share it. Without an observer no timing is
+    /// taken at all.
+    pub fn set_observer(&mut self, observer: Box<ObserverFn>) {
+        self.observer = Some(Arc::from(observer));
+    }
+
+    pub fn clear_observer(&mut self) {
+        self.observer = None;
+    }
+
+    pub fn with_write_policy(mut self, policy: WritePolicy) -> Self {
+        self.write_policy = policy;
+        self
+    }
+
+    /// Send every statement buffered under [`WritePolicy::Batched`] and return
+    /// their results, in order. If one failed, the others were still sent and
+    /// the first error is returned. Call it, or [`Database::close`], before
+    /// dropping the last handle: Drop discards what is still buffered.
+    pub async fn flush(&self) -> ProtocolResult<Vec<ExecResult>> {
+        let statements = std::mem::take(&mut *self.pending.lock().await);
+        if statements.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.proto.lock().await.exec_sql_many(self.id, &statements).await
+    }
+
+    /// Send any statements still buffered, then close the database with
+    /// [`Protocol::close_database`]. dqlite can only release a database by
+    /// closing its connection, so every handle on the same connection fails
+    /// afterwards. If the flush failed its error is returned, after closing.
+    pub async fn close(self) -> ProtocolResult<()> {
+        let flushed = self.flush().await;
+        let closed = self.proto.lock().await.close_database(self.id).await;
+        flushed?;
+        closed
+    }
+
+    /// Queue a statement that does not return rows, for bulk loads.
+    ///
+    /// Under [`WritePolicy::Batched`] the statement is buffered until the
+    /// batch is full, and this returns [`Queued::Pending`]; the call that
+    /// fills the batch sends it and returns every result. Anything else run
+    /// on the database sends the batch first. Dropping the last handle
+    /// doesn't: call [`Database::flush`] or [`Database::close`] before that,
+    /// or the buffered statements are discarded with a warning.
+    /// Under [`WritePolicy::Immediate`] the statement is sent right away.
+    pub async fn queue(&self, sql: &str, params: &[Value]) -> ProtocolResult<Queued> {
+        let n = match self.write_policy {
+            WritePolicy::Immediate => 1,
+            WritePolicy::Batched { n } => n,
+        };
+        let full = {
+            let mut pending = self.pending.lock().await;
+            pending.push((sql.to_string(), params.to_vec()));
+            pending.len() >= n
+        };
+        if !full {
+            return Ok(Queued::Pending);
+        }
+        Ok(Queued::Sent(self.flush().await?))
+    }
+
+    /// Execute a statement that does not return rows.
+    ///
+    /// Exec vs query is chosen by the caller, not by parsing the SQL: use
+    /// [`Database::query`] or [`Database::execute_returning`] for anything
+    /// that yields rows.
+    ///
+    /// Statements still buffered by [`Database::queue`] are sent first, and
+    /// their failure is returned without running this one.
+    pub async fn execute(&self, sql: &str, params: &[Value]) -> ProtocolResult<ExecResult> {
+        self.flush().await?;
+
+        let Some(observer) = &self.observer else {
+            return self.proto.lock().await.exec_sql(self.id, sql, params).await;
+        };
+
+        let start = Instant::now();
+        let result = self.proto.lock().await.exec_sql(self.id, sql, params).await;
+        observer(sql, start.elapsed(), result.as_ref().ok());
+        result
+    }
+
+    pub async fn query(&self, sql: &str, params: &[Value]) -> ProtocolResult<Rows> {
+        // Reads must see buffered writes
+        self.flush().await?;
+
+        let Some(observer) = &self.observer else {
+            return self.proto.lock().await.query_sql(self.id, sql, params).await;
+        };
+
+        let start = Instant::now();
+        let result = self.proto.lock().await.query_sql(self.id, sql, params).await;
+        observer(sql, start.elapsed(), None);
+        result
+    }
+
+    /// Run a query binding `:name`, `@name` or `$name` parameters. Names are
+    /// numbered in order of first appearance, as SQLite does.
+    pub async fn query_named(&self, sql: &str, params: &[(&str, Value)]) -> ProtocolResult<Rows> {
+        let values = bind_named(sql, params)?;
+        self.query(sql, &values).await
+    }
+
+    /// Execute a write with a `RETURNING` clause and collect the returned rows.
+    ///
+    /// dqlite answers these with RESPONSE_ROWS, so this goes through the query
+    /// path; callers don't need to pick between exec and query themselves.
+    pub async fn execute_returning(&self, sql: &str, params: &[Value]) -> ProtocolResult<Vec<Row>> {
+        let rows = self.query(sql, params).await?;
+        Ok(rows.into_vec())
+    }
+
+    /// Rows changed by the most recent statement on this database, via
+    /// `SELECT changes()`.
+    ///
+    /// Prefer [`ExecResult::rows_affected`], which comes back with the exec
+    /// itself and costs no extra round trip; this is for when the result of
+    /// the statement wasn't kept.
+    pub async fn changes(&self) -> ProtocolResult<i64> {
+        self.query_single_integer("SELECT changes()").await
+    }
+
+    /// Rows changed by every statement since the database was opened on the
+    /// server, via `SELECT total_changes()`. Unlike
+    /// [`ExecResult::rows_affected`] this is cumulative across statements.
+    pub async fn total_changes(&self) -> ProtocolResult<i64> {
+        self.query_single_integer("SELECT total_changes()").await
+    }
+
+    /// The schema cookie, via `PRAGMA schema_version`. SQLite bumps it on
+    /// every schema change, so a different value means cached prepared
+    /// statements or column metadata may be stale.
+    pub async fn schema_version(&self) -> ProtocolResult<i64> {
+        self.query_single_integer("PRAGMA schema_version").await
+    }
+
+    async fn query_single_integer(&self, sql: &str) -> ProtocolResult<i64> {
+        let rows = self.query(sql, &[]).await?;
+        match rows.iter().next().and_then(|row| row.get(0)) {
+            Some(Value::Integer(n)) => Ok(*n),
+            other => Err(ProtocolError::Protocol(format!(
+                "Expected a single integer from {:?}, got {:?}",
+                sql, other
+            ))),
+        }
+    }
+
+    /// Build an [`AppliedCheck`] following the dedup table convention: the
+    /// idempotent write inserts its key into `table(key TEXT PRIMARY KEY)` in
+    /// the same transaction, so the key being present means the write landed.
+    pub fn dedup_table_check(table: &str) -> AppliedCheck {
+        let sql = format!("SELECT 1 FROM {} WHERE key = ?", table);
+        Arc::new(move |db: Database, key: String| {
+            let sql = sql.clone();
+            Box::pin(async move {
+                let rows = db.query(&sql, &[Value::Text(key)]).await?;
+                Ok(!rows.is_empty())
+            })
+        })
+    }
+
+    pub async fn prepare(&self, sql: &str) -> ProtocolResult<Statement> {
+        self.flush().await?;
+        let info = self.proto.lock().await.prepare(self.id, sql).await?;
+        Ok(Statement {
+            proto: self.proto.clone(),
+            info,
+            sql: sql.to_string(),
+            db_name: self.name.clone(),
+        })
+    }
+}
+
+/// Query-only view of a database opened with the read-only flag. Writes
+/// don't compile:
+///
+/// ```compile_fail
+/// # use dqlite_rs::protocol::database::ReadOnlyDatabase;
+/// async fn purge(db: &ReadOnlyDatabase) {
+///     db.execute("DELETE FROM reports", &[]).await.unwrap();
+/// }
+/// ```
+#[derive(Clone)]
+pub struct ReadOnlyDatabase {
+    db: Database,
+}
+
+impl ReadOnlyDatabase {
+    pub fn id(&self) -> u32 {
+        self.db.id()
+    }
+
+    pub fn name(&self) -> &str {
+        self.db.name()
+    }
+
+    pub async fn query(&self, sql: &str, params: &[Value]) -> ProtocolResult<Rows> {
+        self.db.query(sql, params).await
+    }
+
+    pub async fn query_named(&self, sql: &str, params: &[(&str, Value)]) -> ProtocolResult<Rows> {
+        self.db.query_named(sql, params).await
+    }
+}
+
+// Map named parameters onto positional ones following SQLite's numbering:
+// every distinct name gets the next index the first time it appears.
+fn bind_named(sql: &str, params: &[(&str, Value)]) -> ProtocolResult<Vec<Value>> {
+    let mut names: Vec<&str> = Vec::new();
+    let bytes = sql.as_bytes();
+    let mut i = 0;
+    let mut quote: Option<u8> = None;
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+            i += 1;
+            continue;
+        }
+
+        match c {
+            b'\'' | b'"' => quote = Some(c),
+            b':' | b'@' | b'$' => {
+                let start = i;
+                i += 1;
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                if i > start + 1 {
+                    let name = &sql[start..i];
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    names
+        .into_iter()
+        .map(|name| {
+            params
+                .iter()
+                .find(|(n, _)| *n == name || *n == &name[1..])
+                .map(|(_, v)| v.clone())
+                .ok_or_else(|| ProtocolError::Protocol(format!("Missing value for parameter {}", name)))
+        })
+        .collect()
+}
+
+// Prepared statement bound to the connection it was prepared on
+pub struct Statement {
+    proto: Arc<Mutex<Protocol>>,
+    info: StmtInfo,
+    // Kept to prepare the statement again on a new connection
+    sql: String,
+    db_name: String,
+}
+
+impl Statement {
+    pub fn param_count(&self) -> u64 {
+        self.info.params
+    }
+
+    pub async fn exec(&self, params: &[Value]) -> ProtocolResult<ExecResult> {
+        self.proto
+            .lock()
+            .await
+            .exec(self.info.db_id, self.info.stmt_id, params)
+            .await
+    }
+
+    pub async fn query(&self, params: &[Value]) -> ProtocolResult<Rows> {
+        self.proto
+            .lock()
+            .await
+            .query(self.info.db_id, self.info.stmt_id, params)
+            .await
+    }
+
+    /// Execute the statement, retrying transient failures without risking a
+    /// double apply.
+    ///
+    /// The statement itself is responsible for recording `key` (for example in
+    /// a dedup table, see [`Database::dedup_table_check`]) atomically with the
+    /// write. When an attempt fails with an I/O error or reaches a node that
+    /// isn't the leader, the connection is given up: a new one is opened
+    /// through `connector`, `applied` is consulted with the key on it, and
+    /// unless the write turns out to be applied the statement is prepared
+    /// again there and re-executed. From then on the statement stays on the
+    /// new connection. Other errors (such as a constraint failure) are
+    /// returned immediately.
+    pub async fn exec_idempotent<S: NodeStore + Send + Sync>(
+        &mut self,
+        connector: &Connector<S>,
+        params: &[Value],
+        key: &str,
+        applied: &AppliedCheck,
+    ) -> ProtocolResult<IdempotentExec> {
+        let mut attempt = 1;
+        loop {
+            let err = match self.exec(params).await {
+                Ok(result) => return Ok(IdempotentExec::Executed(result)),
+                Err(err) => err,
+            };
+
+            let retryable = err.is_transient() || err.sqlite_code().is_some_and(SqliteCode::is_not_leader);
+            if !retryable || attempt >= IDEMPOTENT_ATTEMPTS {
+                return Err(err);
+            }
+
+            connector.forget_leader();
+            let db = connector.open(&self.db_name).await?;
+            if applied(db.clone(), key.to_string()).await? {
+                return Ok(IdempotentExec::AlreadyApplied);
+            }
+            let Statement { proto, info, .. } = db.prepare(&self.sql).await?;
+            self.proto = proto;
+            self.info = info;
+            attempt += 1;
+        }
+    }
+
+    pub async fn finalize(self) -> ProtocolResult<()> {
+        self.proto
+            .lock()
+            .await
+            .finalize(self.info.db_id, self.info.stmt_id)
+            .await
+    }
+}
+
+/// A [`Database`] meant to be held for the life of the process. It reconnects
+/// through the [`Connector`] when the leader changes or the connection drops,
+/// and only reports an error once `retry_limit` or `operation_timeout` from
+/// the connector's [`Config`](crate::protocol::config::Config) runs out.
+///
+/// Reads are retried after any connection or leadership failure. Writes are
+/// retried only when the server rejected them outright, i.e. they were
+/// certainly not applied; an I/O error after a write was sent is returned,
+/// since the write may have been committed.
+///
+/// Database ids are per connection, so every reconnect opens the database by
+/// name again. A server that doesn't recognise the id triggers the same
+/// reconnect and reopen.
+pub struct ReconnectingDatabase<S: NodeStore + Send + Sync> {
+    connector: Arc<Connector<S>>,
+    name: String,
+    current: Mutex<Option<Database>>,
+}
+
+impl<S: NodeStore + Send + Sync> ReconnectingDatabase<S> {
+    pub fn new(connector: Arc<Connector<S>>, name: &str) -> Self {
+        Self {
+            connector,
+            name: name.to_string(),
+            current: Mutex::new(None),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub async fn query(&self, sql: &str, params: &[Value]) -> ProtocolResult<Rows> {
+        self.run(retry_read, |db| async move { db.query(sql, params).await }).await
+    }
+
+    pub async fn execute(&self, sql: &str, params: &[Value]) -> ProtocolResult<ExecResult> {
+        self.run(retry_write, |db| async move { db.execute(sql, params).await }).await
+    }
+
+    async fn run<T, F, Fut>(&self, retryable: fn(&ProtocolError) -> bool, op: F) -> ProtocolResult<T>
+    where
+        F: Fn(Database) -> Fut,
+        Fut: Future<Output = ProtocolResult<T>>,
+    {
+        let config = self.connector.config();
+        let deadline = Deadline::after(config.operation_timeout);
+        let mut attempt: u32 = 0;
+
+        loop {
+            // Connector::open_with_deadline already retries the connect itself
+            let db = self.database(deadline).await?;
+            let proto = db.proto.clone();
+            let err = match deadline.run(op(db)).await {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+
+            // A failed or abandoned request leaves the connection unusable
+            // whether or not the operation is retried: drop it so the next
+            // call reconnects instead of failing on it forever
+            let unusable = matches!(err, ProtocolError::Io(_) | ProtocolError::DeadlineExceeded)
+                || proto.lock().await.is_broken();
+            if !retryable(&err) {
+                if unusable {
+                    self.connector.forget_leader();
+                    self.disconnect(&err).await;
+                }
+                return Err(err);
+            }
+
+            // The connection and the leader it points at are suspect either
+            // way; reconnect and look the leader up again next time
+            self.connector.forget_leader();
+            self.disconnect(&err).await;
+
+            attempt += 1;
+            let Some(delay) = self.connector.retry_delay(&err, attempt) else {
+                return Err(err);
+            };
+            deadline.sleep(delay).await?;
+        }
+    }
+
+    // Drop the cached connection, reporting it lost with `err`
+    async fn disconnect(&self, err: &ProtocolError) {
+        if let Some(db) = self.current.lock().await.take() {
+            if let Ok(addr) = db.proto.lock().await.peer_addr() {
+                self.connector.emit(ConnEvent::Disconnected(addr, err.duplicate()));
+            }
+        }
+    }
+
+    async fn database(&self, deadline: Deadline) -> ProtocolResult<Database> {
+        let mut current = self.current.lock().await;
+        if let Some(db) = current.as_ref() {
+            return Ok(db.clone());
+        }
+        let db = self.connector.open_with_deadline(&self.name, deadline).await?;
+        *current = Some(db.clone());
+        Ok(db)
+    }
+}
+
+fn retry_read(err: &ProtocolError) -> bool {
+    err.is_transient()
+        || is_unknown_database(err)
+        || err.sqlite_code().is_some_and(SqliteCode::is_not_leader)
+}
+
+// LEADERSHIP_LOST is left out: the write may have been committed before the
+// leader stepped down
+fn retry_write(err: &ProtocolError) -> bool {
+    is_unknown_database(err) || err.sqlite_code() == Some(SqliteCode::IOERR_NOT_LEADER)
+}
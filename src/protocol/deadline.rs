@@ -0,0 +1,76 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+use crate::protocol::protocol::{ProtocolError, ProtocolResult};
+
+// A single point in time bounding a whole operation (dial + handshake + query).
+// Each step derives its budget from what is left instead of having its own
+// independent timeout. A deadline without an instant never expires.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    pub fn none() -> Self {
+        Self(None)
+    }
+
+    pub fn at(instant: Instant) -> Self {
+        Self(Some(instant))
+    }
+
+    pub fn after(duration: Duration) -> Self {
+        Self(Some(Instant::now() + duration))
+    }
+
+    pub fn instant(&self) -> Option<Instant> {
+        self.0
+    }
+
+    // The earlier of this deadline and `now + budget`
+    pub fn cap(self, budget: Duration) -> Self {
+        let capped = Instant::now() + budget;
+        match self.0 {
+            Some(instant) if instant <= capped => self,
+            _ => Self(Some(capped)),
+        }
+    }
+
+    // Time left before the deadline, or DeadlineExceeded if it already passed
+    pub fn remaining(&self) -> ProtocolResult<Option<Duration>> {
+        match self.0 {
+            None => Ok(None),
+            Some(instant) => {
+                let now = Instant::now();
+                if now >= instant {
+                    Err(ProtocolError::DeadlineExceeded)
+                } else {
+                    Ok(Some(instant - now))
+                }
+            }
+        }
+    }
+
+    // Drive `fut` to completion unless the deadline passes first
+    pub async fn run<T, F>(&self, fut: F) -> ProtocolResult<T>
+    where
+        F: Future<Output = ProtocolResult<T>>,
+    {
+        match self.remaining()? {
+            None => fut.await,
+            Some(left) => tokio::time::timeout(left, fut)
+                .await
+                .map_err(|_| ProtocolError::DeadlineExceeded)?,
+        }
+    }
+
+    // Sleep for `duration`, failing early if the deadline would pass meanwhile
+    pub async fn sleep(&self, duration: Duration) -> ProtocolResult<()> {
+        if let Some(left) = self.remaining()? {
+            if left < duration {
+                tokio::time::sleep(left).await;
+                return Err(ProtocolError::DeadlineExceeded);
+            }
+        }
+        tokio::time::sleep(duration).await;
+        Ok(())
+    }
+}
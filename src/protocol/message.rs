@@ -0,0 +1,679 @@
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::protocol::wire;
+
+// Protocol versions sent during the handshake
+pub const VERSION_ONE: u64 = 1;
+pub const VERSION_LEGACY: u64 = 0x86104dd760433fe5;
+
+// Request types
+pub const REQUEST_LEADER: u8 = 0;
+pub const REQUEST_CLIENT: u8 = 1;
+pub const REQUEST_HEARTBEAT: u8 = 2;
+pub const REQUEST_OPEN: u8 = 3;
+pub const REQUEST_PREPARE: u8 = 4;
+pub const REQUEST_EXEC: u8 = 5;
+pub const REQUEST_QUERY: u8 = 6;
+pub const REQUEST_FINALIZE: u8 = 7;
+pub const REQUEST_EXEC_SQL: u8 = 8;
+pub const REQUEST_QUERY_SQL: u8 = 9;
+pub const REQUEST_INTERRUPT: u8 = 10;
+// Raft peer handshake: the gateway hands the socket over to raft. It is not a
+// client-side proxy to the leader, so clients must keep dialing the leader.
+pub const REQUEST_CONNECT: u8 = 11;
+pub const REQUEST_ADD: u8 = 12;
+pub const REQUEST_ASSIGN: u8 = 13;
+pub const REQUEST_REMOVE: u8 = 14;
+pub const REQUEST_DUMP: u8 = 15;
+pub const REQUEST_CLUSTER: u8 = 16;
+pub const REQUEST_TRANSFER: u8 = 17;
+pub const REQUEST_DESCRIBE: u8 = 18;
+pub const REQUEST_WEIGHT: u8 = 19;
+
+// Response types
+pub const RESPONSE_FAILURE: u8 = 0;
+pub const RESPONSE_NODE: u8 = 1;
+pub const RESPONSE_WELCOME: u8 = 2;
+pub const RESPONSE_NODES: u8 = 3;
+pub const RESPONSE_DB: u8 = 4;
+pub const RESPONSE_STMT: u8 = 5;
+pub const RESPONSE_RESULT: u8 = 6;
+pub const RESPONSE_ROWS: u8 = 7;
+pub const RESPONSE_EMPTY: u8 = 8;
+pub const RESPONSE_FILES: u8 = 9;
+pub const RESPONSE_METADATA: u8 = 10;
+
+// SQLite value type codes used in tuples and row headers
+pub const TYPE_INTEGER: u8 = 1;
+pub const TYPE_FLOAT: u8 = 2;
+pub const TYPE_TEXT: u8 = 3;
+pub const TYPE_BLOB: u8 = 4;
+pub const TYPE_NULL: u8 = 5;
+pub const TYPE_UNIXTIME: u8 = 9;
+pub const TYPE_ISO8601: u8 = 10;
+pub const TYPE_BOOLEAN: u8 = 11;
+
+// Markers terminating a batch of rows in a RESPONSE_ROWS body
+pub const ROWS_DONE: u64 = 0xffffffffffffffff;
+pub const ROWS_PART: u64 = 0xeeeeeeeeeeeeeeee;
+
+/// SQLite open flags (SQLITE_OPEN_*) carried by REQUEST_OPEN.
+///
+/// dqlite databases live in its replicated VFS, so only this subset is
+/// meaningful and accepted:
+///
+/// - exactly one of `READONLY` or `READWRITE`
+/// - `CREATE`, together with `READWRITE` only
+/// - `MAIN_DB`
+/// - `NOFOLLOW`
+///
+/// `MEMORY` and any other bit are rejected; see [`OpenFlags::validate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OpenFlags(pub u64);
+
+impl OpenFlags {
+    pub const READONLY: OpenFlags = OpenFlags(0x00000001);
+    pub const READWRITE: OpenFlags = OpenFlags(0x00000002);
+    pub const CREATE: OpenFlags = OpenFlags(0x00000004);
+    pub const MEMORY: OpenFlags = OpenFlags(0x00000080);
+    pub const MAIN_DB: OpenFlags = OpenFlags(0x00000100);
+    pub const NOFOLLOW: OpenFlags = OpenFlags(0x01000000);
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    const ACCEPTED: OpenFlags = OpenFlags(
+        Self::READONLY.0 | Self::READWRITE.0 | Self::CREATE.0 | Self::MAIN_DB.0 | Self::NOFOLLOW.0,
+    );
+
+    // Check the combination against what dqlite accepts, describing the
+    // first problem found
+    pub fn validate(self) -> Result<(), String> {
+        if self.contains(Self::MEMORY) {
+            return Err("MEMORY is not supported: dqlite databases are replicated, not in-memory".to_string());
+        }
+        let unknown = self.0 & !Self::ACCEPTED.0;
+        if unknown != 0 {
+            return Err(format!("Open flags {:#x} are not supported by dqlite", unknown));
+        }
+        match (self.contains(Self::READONLY), self.contains(Self::READWRITE)) {
+            (true, true) => return Err("READONLY and READWRITE are mutually exclusive".to_string()),
+            (false, false) => return Err("One of READONLY or READWRITE is required".to_string()),
+            _ => {}
+        }
+        if self.contains(Self::CREATE) && !self.contains(Self::READWRITE) {
+            return Err("CREATE requires READWRITE".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for OpenFlags {
+    fn default() -> Self {
+        OpenFlags::MAIN_DB | OpenFlags::CREATE | OpenFlags::READWRITE
+    }
+}
+
+impl std::ops::BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, rhs: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitOrAssign for OpenFlags {
+    fn bitor_assign(&mut self, rhs: OpenFlags) {
+        self.0 |= rhs.0;
+    }
+}
+
+// Raw SQLITE_OPEN_* bits
+impl From<u64> for OpenFlags {
+    fn from(bits: u64) -> Self {
+        OpenFlags(bits)
+    }
+}
+
+/// A request type code, as carried in the message header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestType(pub u8);
+
+impl RequestType {
+    pub fn name(&self) -> &'static str {
+        match self.0 {
+            REQUEST_LEADER => "leader",
+            REQUEST_CLIENT => "client",
+            REQUEST_HEARTBEAT => "heartbeat",
+            REQUEST_OPEN => "open",
+            REQUEST_PREPARE => "prepare",
+            REQUEST_EXEC => "exec",
+            REQUEST_QUERY => "query",
+            REQUEST_FINALIZE => "finalize",
+            REQUEST_EXEC_SQL => "exec_sql",
+            REQUEST_QUERY_SQL => "query_sql",
+            REQUEST_INTERRUPT => "interrupt",
+            REQUEST_CONNECT => "connect",
+            REQUEST_ADD => "add",
+            REQUEST_ASSIGN => "assign",
+            REQUEST_REMOVE => "remove",
+            REQUEST_DUMP => "dump",
+            REQUEST_CLUSTER => "cluster",
+            REQUEST_TRANSFER => "transfer",
+            REQUEST_DESCRIBE => "describe",
+            REQUEST_WEIGHT => "weight",
+            _ => "unknown",
+        }
+    }
+}
+
+impl std::fmt::Display for RequestType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Names the tracing span opened around each request
+pub type SpanNamer = fn(&RequestType) -> String;
+
+// "dqlite.exec_sql", "dqlite.query", ...
+pub fn default_span_name(request_type: &RequestType) -> String {
+    format!("dqlite.{}", request_type.name())
+}
+
+pub const WORD_SIZE: usize = wire::WORD_SIZE;
+// Largest body a header can describe: its word count is a u32. A blob
+// parameter must fit in this together with the rest of the request; dqlite
+// has no chunked transfer for bigger values. In practice a single blob or
+// text value is capped well below, at SQLite's default SQLITE_MAX_LENGTH of
+// 1,000,000,000 bytes, which Protocol checks before encoding.
+pub const MAX_BODY_SIZE: u64 = u32::MAX as u64 * WORD_SIZE as u64;
+pub const HEADER_SIZE: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Integer(i64),
+    Float(f64),
+    Text(String),
+    Blob(Vec<u8>),
+    Null,
+    Boolean(bool),
+    // Seconds since the Unix epoch, sent with the dqlite UNIXTIME type
+    Unixtime(i64),
+    // Timestamp text, e.g. of a DATETIME column, sent with the ISO8601 type
+    Iso8601(String),
+}
+
+impl Value {
+    pub fn type_code(&self) -> u8 {
+        match self {
+            Value::Integer(_) => TYPE_INTEGER,
+            Value::Float(_) => TYPE_FLOAT,
+            Value::Text(_) => TYPE_TEXT,
+            Value::Blob(_) => TYPE_BLOB,
+            Value::Null => TYPE_NULL,
+            Value::Boolean(_) => TYPE_BOOLEAN,
+            Value::Unixtime(_) => TYPE_UNIXTIME,
+            Value::Iso8601(_) => TYPE_ISO8601,
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Value::Integer(v)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self {
+        Value::Float(v)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Self {
+        Value::Boolean(v)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Value::Text(v.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Value::Text(v)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(v: Vec<u8>) -> Self {
+        Value::Blob(v)
+    }
+}
+
+// Whole seconds; anything finer is truncated, towards the past
+impl From<SystemTime> for Value {
+    fn from(v: SystemTime) -> Self {
+        let secs = match v.duration_since(UNIX_EPOCH) {
+            Ok(after) => after.as_secs() as i64,
+            Err(before) => {
+                let before = before.duration();
+                -(before.as_secs() as i64) - i64::from(before.subsec_nanos() > 0)
+            }
+        };
+        Value::Unixtime(secs)
+    }
+}
+
+// Unixtime values, and Iso8601 ones too with the `chrono` feature
+impl TryFrom<&Value> for SystemTime {
+    type Error = String;
+
+    fn try_from(v: &Value) -> Result<Self, Self::Error> {
+        match v {
+            Value::Unixtime(secs) if *secs >= 0 => Ok(UNIX_EPOCH + Duration::from_secs(*secs as u64)),
+            Value::Unixtime(secs) => Ok(UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())),
+            #[cfg(feature = "chrono")]
+            Value::Iso8601(_) => chrono::DateTime::<chrono::Utc>::try_from(v).map(SystemTime::from),
+            other => Err(format!("Expected a timestamp, got {:?}", other)),
+        }
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        match v {
+            Some(v) => v.into(),
+            None => Value::Null,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ExecResult {
+    pub last_insert_id: u64,
+    pub rows_affected: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    values: Vec<Value>,
+}
+
+impl Row {
+    pub fn new(values: Vec<Value>) -> Self {
+        Self { values }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Value> {
+        self.values.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    pub fn into_values(self) -> Vec<Value> {
+        self.values
+    }
+}
+
+// Fully decoded result set of a query, including every part the server streamed
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Rows {
+    columns: Vec<String>,
+    rows: Vec<Row>,
+}
+
+impl Rows {
+    pub fn new(columns: Vec<String>, rows: Vec<Row>) -> Self {
+        Self { columns, rows }
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Row> {
+        self.rows.iter()
+    }
+
+    pub fn into_vec(self) -> Vec<Row> {
+        self.rows
+    }
+}
+
+impl IntoIterator for Rows {
+    type Item = Row;
+    type IntoIter = std::vec::IntoIter<Row>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.rows.into_iter()
+    }
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+// A single protocol message: an 8 byte header followed by a word aligned body
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    pub mtype: u8,
+    pub schema: u8,
+    pub extra: u16,
+    body: Vec<u8>,
+    offset: usize,
+}
+
+impl Message {
+    pub fn new(mtype: u8) -> Self {
+        Self {
+            mtype,
+            ..Default::default()
+        }
+    }
+
+    pub fn from_parts(header: [u8; HEADER_SIZE], body: Vec<u8>) -> Self {
+        Self {
+            mtype: header[4],
+            schema: header[5],
+            extra: wire::get_u16_le(&header[6..8]),
+            body,
+            offset: 0,
+        }
+    }
+
+    // Number of words declared in a raw header
+    pub fn header_words(header: &[u8; HEADER_SIZE]) -> u32 {
+        wire::get_u32_le(&header[0..4])
+    }
+
+    pub fn header(&self) -> [u8; HEADER_SIZE] {
+        let words = (self.body.len() / WORD_SIZE) as u32;
+        let mut buf = Vec::with_capacity(HEADER_SIZE);
+        wire::put_u32_le(&mut buf, words);
+        buf.push(self.mtype);
+        buf.push(self.schema);
+        wire::put_u16_le(&mut buf, self.extra);
+
+        let mut header = [0u8; HEADER_SIZE];
+        header.copy_from_slice(&buf);
+        header
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    // Append the framed message (header and body) to a buffer, so several
+    // requests can go out in a single write
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.header());
+        out.extend_from_slice(&self.body);
+    }
+
+    // Encoders
+
+    fn pad(&mut self) {
+        wire::put_padding(&mut self.body);
+    }
+
+    pub fn put_u8(&mut self, v: u8) {
+        self.body.push(v);
+    }
+
+    pub fn put_u32(&mut self, v: u32) {
+        wire::put_u32_le(&mut self.body, v);
+    }
+
+    pub fn put_u64(&mut self, v: u64) {
+        wire::put_u64_le(&mut self.body, v);
+    }
+
+    pub fn put_i64(&mut self, v: i64) {
+        wire::put_i64_le(&mut self.body, v);
+    }
+
+    pub fn put_f64(&mut self, v: f64) {
+        wire::put_f64_le(&mut self.body, v);
+    }
+
+    pub fn put_text(&mut self, v: &str) {
+        wire::put_text(&mut self.body, v);
+    }
+
+    pub fn put_blob(&mut self, v: &[u8]) {
+        self.put_u64(v.len() as u64);
+        self.body.extend_from_slice(v);
+        self.pad();
+    }
+
+    pub fn put_value(&mut self, v: &Value) {
+        match v {
+            Value::Integer(i) => self.put_i64(*i),
+            Value::Float(f) => self.put_f64(*f),
+            Value::Text(s) => self.put_text(s),
+            Value::Blob(b) => self.put_blob(b),
+            Value::Null => self.put_u64(0),
+            Value::Boolean(b) => self.put_u64(*b as u64),
+            Value::Unixtime(t) => self.put_i64(*t),
+            Value::Iso8601(s) => self.put_text(s),
+        }
+    }
+
+    // Encode a parameter tuple: the count, one type byte per value, padding,
+    // then the values themselves.
+    //
+    // Up to 255 parameters the count is a single byte (schema 0). Beyond that
+    // dqlite expects schema 1, where the count is a u32; the schema is set on
+    // the message here so the header matches the body.
+    pub fn put_params(&mut self, params: &[Value]) -> io::Result<()> {
+        if params.len() <= u8::MAX as usize {
+            self.put_u8(params.len() as u8);
+        } else {
+            let count = u32::try_from(params.len()).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Too many parameters: {}", params.len()),
+                )
+            })?;
+            self.schema = 1;
+            self.put_u32(count);
+        }
+        for param in params {
+            self.put_u8(param.type_code());
+        }
+        self.pad();
+
+        for param in params {
+            self.put_value(param);
+        }
+        Ok(())
+    }
+
+    // Decoders
+
+    fn take(&mut self, n: usize) -> io::Result<&[u8]> {
+        if self.offset + n > self.body.len() {
+            return Err(invalid_data(format!(
+                "Short message body: need {} bytes at offset {}, have {}",
+                n,
+                self.offset,
+                self.body.len()
+            )));
+        }
+        let slice = &self.body[self.offset..self.offset + n];
+        self.offset += n;
+        Ok(slice)
+    }
+
+    fn skip_padding(&mut self) -> io::Result<()> {
+        let n = wire::padding(self.offset);
+        self.take(n)?;
+        Ok(())
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.body.len() - self.offset
+    }
+
+    pub fn get_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_u32(&mut self) -> io::Result<u32> {
+        Ok(wire::get_u32_le(self.take(4)?))
+    }
+
+    pub fn get_u64(&mut self) -> io::Result<u64> {
+        Ok(wire::get_u64_le(self.take(8)?))
+    }
+
+    pub fn peek_u64(&self) -> io::Result<u64> {
+        if self.offset + 8 > self.body.len() {
+            return Err(invalid_data("Short message body while peeking a word"));
+        }
+        Ok(wire::get_u64_le(&self.body[self.offset..]))
+    }
+
+    pub fn get_i64(&mut self) -> io::Result<i64> {
+        Ok(wire::get_i64_le(self.take(8)?))
+    }
+
+    pub fn get_f64(&mut self) -> io::Result<f64> {
+        Ok(wire::get_f64_le(self.take(8)?))
+    }
+
+    pub fn get_text(&mut self) -> io::Result<String> {
+        let (text, consumed) = wire::get_text(&self.body[self.offset..])?;
+        self.offset += consumed;
+        Ok(text)
+    }
+
+    pub fn get_blob(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.get_u64()? as usize;
+        let blob = self.take(len)?.to_vec();
+        self.skip_padding()?;
+        Ok(blob)
+    }
+
+    pub fn get_value(&mut self, type_code: u8) -> io::Result<Value> {
+        match type_code {
+            TYPE_INTEGER => Ok(Value::Integer(self.get_i64()?)),
+            TYPE_FLOAT => Ok(Value::Float(self.get_f64()?)),
+            TYPE_TEXT => Ok(Value::Text(self.get_text()?)),
+            TYPE_BLOB => Ok(Value::Blob(self.get_blob()?)),
+            TYPE_NULL => {
+                self.get_u64()?;
+                Ok(Value::Null)
+            }
+            TYPE_UNIXTIME => Ok(Value::Unixtime(self.get_i64()?)),
+            TYPE_ISO8601 => Ok(Value::Iso8601(self.get_text()?)),
+            TYPE_BOOLEAN => Ok(Value::Boolean(self.get_u64()? != 0)),
+            other => Err(invalid_data(format!("Unknown value type: {}", other))),
+        }
+    }
+
+    pub fn get_result(&mut self) -> io::Result<ExecResult> {
+        Ok(ExecResult {
+            last_insert_id: self.get_u64()?,
+            rows_affected: self.get_u64()?,
+        })
+    }
+
+    pub fn get_columns(&mut self) -> io::Result<Vec<String>> {
+        let count = self.get_u64()? as usize;
+        // Every name takes at least a word, so a larger count can't be real
+        // and mustn't size the allocation
+        if count > self.remaining() / WORD_SIZE {
+            return Err(invalid_data(format!(
+                "Column count {} exceeds the {} bytes left in the message",
+                count,
+                self.remaining()
+            )));
+        }
+        let mut columns = Vec::with_capacity(count);
+        for _ in 0..count {
+            columns.push(self.get_text()?);
+        }
+        Ok(columns)
+    }
+
+    // Decode the rows of a single RESPONSE_ROWS batch. Returns true if the
+    // batch ended with the PART marker, meaning the server will send more.
+    //
+    // The batch layout and its DONE/PART trailer are the same under
+    // VERSION_LEGACY and VERSION_ONE, so this doesn't depend on the protocol
+    // version. What does differ is RESPONSE_NODE (see Protocol::leader) and
+    // the server list encoding (see Protocol::heartbeat and cluster).
+    pub fn get_rows(&mut self, column_count: usize, rows: &mut Vec<Row>) -> io::Result<bool> {
+        // Each column type takes 4 bits, and the row header is word aligned
+        let header_size = (column_count * 4).div_ceil(64) * WORD_SIZE;
+
+        loop {
+            match self.peek_u64()? {
+                ROWS_DONE => {
+                    self.get_u64()?;
+                    return Ok(false);
+                }
+                ROWS_PART => {
+                    self.get_u64()?;
+                    return Ok(true);
+                }
+                // A row without columns takes no bytes, so anything but a
+                // marker would be read as rows forever
+                word if column_count == 0 => {
+                    return Err(invalid_data(format!(
+                        "Expected the end of a column-less result, got {:#x}",
+                        word
+                    )));
+                }
+                _ => {}
+            }
+
+            let mut types = vec![0u8; column_count];
+            for i in 0..header_size {
+                let slot = self.get_u8()?;
+                let index = i * 2;
+                if index < column_count {
+                    types[index] = slot & 0x0f;
+                }
+                if index + 1 < column_count {
+                    types[index + 1] = slot >> 4;
+                }
+            }
+
+            let mut values = Vec::with_capacity(column_count);
+            for type_code in types {
+                values.push(self.get_value(type_code)?);
+            }
+            rows.push(Row::new(values));
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Bucket i counts samples up to 2^i microseconds, so 40 buckets reach past
+// twelve days; anything slower lands in the last one
+const BUCKETS: usize = 40;
+
+// Log-scale latency histogram. Percentiles are reported as the upper bound of
+// the bucket they fall in, i.e. at most a factor of two high.
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; BUCKETS],
+            count: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, elapsed: Duration) {
+        let micros = elapsed.as_micros().max(1);
+        let bucket = (u128::BITS - (micros - 1).leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    // Latency below which `quantile` (0.0..=1.0) of the samples fall
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        let target = ((self.count as f64) * quantile.clamp(0.0, 1.0)).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return Duration::from_micros(1u64 << i);
+            }
+        }
+        Duration::from_micros(1u64 << (BUCKETS - 1))
+    }
+}
+
+// Latency summary for one request type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeLatency {
+    pub count: u64,
+    pub p50: Duration,
+    pub p99: Duration,
+}
+
+// Snapshot of the latencies recorded on a connection, keyed by request type
+// (the REQUEST_* constants)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub by_type: HashMap<u8, TypeLatency>,
+}
+
+impl LatencyStats {
+    pub fn get(&self, request_type: u8) -> Option<&TypeLatency> {
+        self.by_type.get(&request_type)
+    }
+}
+
+// Per request type histograms kept by a Protocol
+#[derive(Debug, Clone, Default)]
+pub struct LatencyRecorder {
+    histograms: HashMap<u8, LatencyHistogram>,
+}
+
+impl LatencyRecorder {
+    pub fn record(&mut self, request_type: u8, elapsed: Duration) {
+        self.histograms.entry(request_type).or_default().record(elapsed);
+    }
+
+    pub fn stats(&self) -> LatencyStats {
+        let by_type = self
+            .histograms
+            .iter()
+            .map(|(&mtype, h)| {
+                (
+                    mtype,
+                    TypeLatency {
+                        count: h.count(),
+                        p50: h.percentile(0.50),
+                        p99: h.percentile(0.99),
+                    },
+                )
+            })
+            .collect();
+        LatencyStats { by_type }
+    }
+}
+
+// Recorder shared by several connections, e.g. all those of one Connector.
+// Clones record into the same histograms.
+#[derive(Debug, Clone, Default)]
+pub struct SharedLatencyRecorder {
+    inner: Arc<Mutex<LatencyRecorder>>,
+}
+
+impl SharedLatencyRecorder {
+    pub fn record(&self, request_type: u8, elapsed: Duration) {
+        self.inner.lock().record(request_type, elapsed);
+    }
+
+    pub fn stats(&self) -> LatencyStats {
+        self.inner.lock().stats()
+    }
+}
@@ -1,4 +1,21 @@
-pub mod protocol
-pub mod store
-pub mod connector
-pub mod config
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod protocol;
+pub mod store;
+pub mod connector;
+pub mod config;
+pub mod message;
+pub mod database;
+pub mod deadline;
+pub mod actor;
+pub mod shard;
+pub mod pool;
+pub mod retry;
+#[cfg(feature = "metrics-hist")]
+pub mod metrics;
+#[cfg(feature = "proxy")]
+pub mod proxy;
+pub(crate) mod wire;
+#[cfg(feature = "rusqlite-compat")]
+pub mod rusqlite_compat;
+#[cfg(feature = "chrono")]
+pub mod chrono_compat;
@@ -0,0 +1,165 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::join_all;
+use tokio::sync::Notify;
+
+use crate::protocol::connector::Connector;
+use crate::protocol::protocol::{Protocol, ProtocolError, ProtocolResult};
+use crate::protocol::store::NodeStore;
+
+// How long close waits for checked-out connections unless told otherwise
+const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reuses leader connections from a [`Connector`] across requests.
+///
+/// A connection goes back to the pool when its [`PooledConn`] is dropped,
+/// unless a network failure broke it or `max_idle` connections are already
+/// waiting. Clones share the same connections.
+pub struct Pool<S: NodeStore + Send + Sync> {
+    connector: Arc<Connector<S>>,
+    shared: Arc<Shared>,
+    close_timeout: Duration,
+}
+
+struct Shared {
+    state: Mutex<PoolState>,
+    returned: Notify,
+    max_idle: usize,
+}
+
+struct PoolState {
+    // None once close has shut the idle connections down
+    idle: Option<Vec<Protocol>>,
+    checked_out: usize,
+    closed: bool,
+}
+
+impl<S: NodeStore + Send + Sync> Pool<S> {
+    pub fn new(connector: Connector<S>, max_idle: usize) -> Self {
+        Self {
+            connector: Arc::new(connector),
+            shared: Arc::new(Shared {
+                state: Mutex::new(PoolState { idle: Some(Vec::new()), checked_out: 0, closed: false }),
+                returned: Notify::new(),
+                max_idle,
+            }),
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+        }
+    }
+
+    // How long close waits for checked-out connections to come back
+    pub fn with_close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_timeout = timeout;
+        self
+    }
+
+    /// An idle connection, or a new one to the leader if there is none.
+    /// Fails with [`ProtocolError::PoolClosed`] once [`Pool::close`] was
+    /// called on any clone.
+    pub async fn acquire(&self) -> ProtocolResult<PooledConn> {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.closed {
+                return Err(ProtocolError::PoolClosed);
+            }
+            // Counted before dialing so that close waits for the dial too
+            state.checked_out += 1;
+            if let Some(proto) = state.idle.as_mut().and_then(Vec::pop) {
+                return Ok(PooledConn { proto: Some(proto), shared: self.shared.clone() });
+            }
+        }
+        match self.connector.connect().await {
+            Ok(proto) => Ok(PooledConn { proto: Some(proto), shared: self.shared.clone() }),
+            Err(err) => {
+                self.shared.check_in(None);
+                Err(err)
+            }
+        }
+    }
+
+    // Connections waiting to be acquired
+    pub fn idle(&self) -> usize {
+        let state = self.shared.state.lock().unwrap();
+        state.idle.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Stop handing out connections, wait up to the close timeout for the
+    /// checked-out ones to come back, and shut every pooled connection down
+    /// with [`Protocol::close`] so the servers see clean disconnects.
+    ///
+    /// Fails with [`ProtocolError::DeadlineExceeded`] if connections were
+    /// still checked out at the timeout; they are dropped, without the
+    /// shutdown, when they come back. Otherwise returns the first error of
+    /// a shutdown, if any.
+    pub async fn close(self) -> ProtocolResult<()> {
+        self.shared.state.lock().unwrap().closed = true;
+
+        let drained = tokio::time::timeout(self.close_timeout, async {
+            loop {
+                let returned = self.shared.returned.notified();
+                if self.shared.state.lock().unwrap().checked_out == 0 {
+                    return;
+                }
+                returned.await;
+            }
+        })
+        .await;
+
+        let idle = self.shared.state.lock().unwrap().idle.take().unwrap_or_default();
+        let results = join_all(idle.into_iter().map(Protocol::close)).await;
+        drained.map_err(|_| ProtocolError::DeadlineExceeded)?;
+        results.into_iter().collect()
+    }
+}
+
+impl<S: NodeStore + Send + Sync> Clone for Pool<S> {
+    fn clone(&self) -> Self {
+        Self {
+            connector: self.connector.clone(),
+            shared: self.shared.clone(),
+            close_timeout: self.close_timeout,
+        }
+    }
+}
+
+impl Shared {
+    fn check_in(&self, proto: Option<Protocol>) {
+        let mut state = self.state.lock().unwrap();
+        state.checked_out -= 1;
+        if let (Some(proto), Some(idle)) = (proto, state.idle.as_mut()) {
+            if !proto.is_broken() && idle.len() < self.max_idle {
+                idle.push(proto);
+            }
+        }
+        drop(state);
+        self.returned.notify_waiters();
+    }
+}
+
+/// A connection checked out of a [`Pool`], returned to it on drop.
+pub struct PooledConn {
+    proto: Option<Protocol>,
+    shared: Arc<Shared>,
+}
+
+impl Deref for PooledConn {
+    type Target = Protocol;
+
+    fn deref(&self) -> &Protocol {
+        self.proto.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConn {
+    fn deref_mut(&mut self) -> &mut Protocol {
+        self.proto.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConn {
+    fn drop(&mut self) {
+        self.shared.check_in(self.proto.take());
+    }
+}
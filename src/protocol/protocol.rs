@@ -1,17 +1,1250 @@
-use parking_lot::Mutex;
+use std::collections::VecDeque;
 use std::sync::Arc;
-use mod::connector::Conn;
+use std::io;
+use crate::protocol::connector::{Addr, Conn};
+use crate::protocol::message::{
+    default_span_name, Message, ExecResult, OpenFlags, RequestType, Row, SpanNamer, Rows, Value, HEADER_SIZE, MAX_BODY_SIZE, WORD_SIZE,
+    REQUEST_ASSIGN, REQUEST_CLIENT, REQUEST_CLUSTER, REQUEST_DESCRIBE, REQUEST_EXEC, REQUEST_HEARTBEAT, REQUEST_EXEC_SQL, REQUEST_FINALIZE, REQUEST_INTERRUPT, REQUEST_LEADER,
+    REQUEST_OPEN, REQUEST_PREPARE, REQUEST_QUERY, REQUEST_QUERY_SQL, REQUEST_WEIGHT,
+    RESPONSE_DB, RESPONSE_EMPTY, RESPONSE_FAILURE, RESPONSE_METADATA, RESPONSE_NODE, RESPONSE_NODES, RESPONSE_RESULT,
+    RESPONSE_ROWS, RESPONSE_STMT, RESPONSE_WELCOME, VERSION_LEGACY, VERSION_ONE,
+};
+use crate::protocol::config::Config;
+use crate::protocol::deadline::Deadline;
+use crate::protocol::wire;
+#[cfg(feature = "metrics-hist")]
+use crate::protocol::metrics::{LatencyStats, SharedLatencyRecorder};
+use crate::protocol::store::{NodeInfo, NodeRole};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+// Request timings follow the runtime clock, so they can be tested with paused time
+use tokio::time::Instant;
+use tracing::Instrument;
 
-// Short lived per-connection instance
+// SQLite result code carried by RESPONSE_FAILURE. The low 8 bits are the
+// primary code, the full value is the extended code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SqliteCode(pub u32);
+
+impl SqliteCode {
+    pub const ERROR: SqliteCode = SqliteCode(1);
+    pub const INTERNAL: SqliteCode = SqliteCode(2);
+    pub const PERM: SqliteCode = SqliteCode(3);
+    pub const ABORT: SqliteCode = SqliteCode(4);
+    pub const BUSY: SqliteCode = SqliteCode(5);
+    pub const LOCKED: SqliteCode = SqliteCode(6);
+    pub const NOMEM: SqliteCode = SqliteCode(7);
+    pub const READONLY: SqliteCode = SqliteCode(8);
+    pub const INTERRUPT: SqliteCode = SqliteCode(9);
+    pub const IOERR: SqliteCode = SqliteCode(10);
+    pub const CORRUPT: SqliteCode = SqliteCode(11);
+    pub const NOTFOUND: SqliteCode = SqliteCode(12);
+    pub const FULL: SqliteCode = SqliteCode(13);
+    pub const CANTOPEN: SqliteCode = SqliteCode(14);
+    pub const PROTOCOL: SqliteCode = SqliteCode(15);
+    pub const SCHEMA: SqliteCode = SqliteCode(17);
+    pub const TOOBIG: SqliteCode = SqliteCode(18);
+    pub const CONSTRAINT: SqliteCode = SqliteCode(19);
+    pub const MISMATCH: SqliteCode = SqliteCode(20);
+    pub const MISUSE: SqliteCode = SqliteCode(21);
+    pub const RANGE: SqliteCode = SqliteCode(25);
+    pub const NOTADB: SqliteCode = SqliteCode(26);
+
+    // Extended codes
+    pub const BUSY_RECOVERY: SqliteCode = SqliteCode(5 | (1 << 8));
+    pub const BUSY_SNAPSHOT: SqliteCode = SqliteCode(5 | (2 << 8));
+    pub const BUSY_TIMEOUT: SqliteCode = SqliteCode(5 | (3 << 8));
+    pub const READONLY_RECOVERY: SqliteCode = SqliteCode(8 | (1 << 8));
+    pub const READONLY_CANTLOCK: SqliteCode = SqliteCode(8 | (2 << 8));
+    pub const READONLY_ROLLBACK: SqliteCode = SqliteCode(8 | (3 << 8));
+    pub const READONLY_DBMOVED: SqliteCode = SqliteCode(8 | (4 << 8));
+    pub const CONSTRAINT_CHECK: SqliteCode = SqliteCode(19 | (1 << 8));
+    pub const CONSTRAINT_COMMITHOOK: SqliteCode = SqliteCode(19 | (2 << 8));
+    pub const CONSTRAINT_FOREIGNKEY: SqliteCode = SqliteCode(19 | (3 << 8));
+    pub const CONSTRAINT_FUNCTION: SqliteCode = SqliteCode(19 | (4 << 8));
+    pub const CONSTRAINT_NOTNULL: SqliteCode = SqliteCode(19 | (5 << 8));
+    pub const CONSTRAINT_PRIMARYKEY: SqliteCode = SqliteCode(19 | (6 << 8));
+    pub const CONSTRAINT_TRIGGER: SqliteCode = SqliteCode(19 | (7 << 8));
+    pub const CONSTRAINT_UNIQUE: SqliteCode = SqliteCode(19 | (8 << 8));
+    pub const CONSTRAINT_VTAB: SqliteCode = SqliteCode(19 | (9 << 8));
+    pub const CONSTRAINT_ROWID: SqliteCode = SqliteCode(19 | (10 << 8));
+    // dqlite specific: the node lost or never had leadership
+    pub const IOERR_NOT_LEADER: SqliteCode = SqliteCode(10 | (40 << 8));
+    pub const IOERR_LEADERSHIP_LOST: SqliteCode = SqliteCode(10 | (41 << 8));
+
+    pub fn from_raw(code: u64) -> Self {
+        SqliteCode(code as u32)
+    }
+
+    pub fn primary(self) -> SqliteCode {
+        SqliteCode(self.0 & 0xff)
+    }
+
+    pub fn extended(self) -> u32 {
+        self.0
+    }
+
+    pub fn is_constraint(self) -> bool {
+        self.primary() == Self::CONSTRAINT
+    }
+
+    pub fn is_busy(self) -> bool {
+        self.primary() == Self::BUSY || self.primary() == Self::LOCKED
+    }
+
+    pub fn is_readonly(self) -> bool {
+        self.primary() == Self::READONLY
+    }
+
+    // The request reached a node that isn't (or stopped being) the leader
+    pub fn is_not_leader(self) -> bool {
+        self == Self::IOERR_NOT_LEADER || self == Self::IOERR_LEADERSHIP_LOST
+    }
+
+    pub fn name(self) -> Option<&'static str> {
+        let name = match self {
+            Self::BUSY_RECOVERY => "SQLITE_BUSY_RECOVERY",
+            Self::BUSY_SNAPSHOT => "SQLITE_BUSY_SNAPSHOT",
+            Self::BUSY_TIMEOUT => "SQLITE_BUSY_TIMEOUT",
+            Self::READONLY_RECOVERY => "SQLITE_READONLY_RECOVERY",
+            Self::READONLY_CANTLOCK => "SQLITE_READONLY_CANTLOCK",
+            Self::READONLY_ROLLBACK => "SQLITE_READONLY_ROLLBACK",
+            Self::READONLY_DBMOVED => "SQLITE_READONLY_DBMOVED",
+            Self::CONSTRAINT_CHECK => "SQLITE_CONSTRAINT_CHECK",
+            Self::CONSTRAINT_COMMITHOOK => "SQLITE_CONSTRAINT_COMMITHOOK",
+            Self::CONSTRAINT_FOREIGNKEY => "SQLITE_CONSTRAINT_FOREIGNKEY",
+            Self::CONSTRAINT_FUNCTION => "SQLITE_CONSTRAINT_FUNCTION",
+            Self::CONSTRAINT_NOTNULL => "SQLITE_CONSTRAINT_NOTNULL",
+            Self::CONSTRAINT_PRIMARYKEY => "SQLITE_CONSTRAINT_PRIMARYKEY",
+            Self::CONSTRAINT_TRIGGER => "SQLITE_CONSTRAINT_TRIGGER",
+            Self::CONSTRAINT_UNIQUE => "SQLITE_CONSTRAINT_UNIQUE",
+            Self::CONSTRAINT_VTAB => "SQLITE_CONSTRAINT_VTAB",
+            Self::CONSTRAINT_ROWID => "SQLITE_CONSTRAINT_ROWID",
+            Self::IOERR_NOT_LEADER => "SQLITE_IOERR_NOT_LEADER",
+            Self::IOERR_LEADERSHIP_LOST => "SQLITE_IOERR_LEADERSHIP_LOST",
+            _ => match self.primary() {
+                Self::ERROR => "SQLITE_ERROR",
+                Self::INTERNAL => "SQLITE_INTERNAL",
+                Self::PERM => "SQLITE_PERM",
+                Self::ABORT => "SQLITE_ABORT",
+                Self::BUSY => "SQLITE_BUSY",
+                Self::LOCKED => "SQLITE_LOCKED",
+                Self::NOMEM => "SQLITE_NOMEM",
+                Self::READONLY => "SQLITE_READONLY",
+                Self::INTERRUPT => "SQLITE_INTERRUPT",
+                Self::IOERR => "SQLITE_IOERR",
+                Self::CORRUPT => "SQLITE_CORRUPT",
+                Self::NOTFOUND => "SQLITE_NOTFOUND",
+                Self::FULL => "SQLITE_FULL",
+                Self::CANTOPEN => "SQLITE_CANTOPEN",
+                Self::PROTOCOL => "SQLITE_PROTOCOL",
+                Self::SCHEMA => "SQLITE_SCHEMA",
+                Self::TOOBIG => "SQLITE_TOOBIG",
+                Self::CONSTRAINT => "SQLITE_CONSTRAINT",
+                Self::MISMATCH => "SQLITE_MISMATCH",
+                Self::MISUSE => "SQLITE_MISUSE",
+                Self::RANGE => "SQLITE_RANGE",
+                Self::NOTADB => "SQLITE_NOTADB",
+                _ => return None,
+            },
+        };
+        Some(name)
+    }
+}
+
+impl std::fmt::Display for SqliteCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{} ({})", name, self.0),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ProtocolError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Server failure ({code}): {message}")]
+    Failure { code: SqliteCode, message: String },
+
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+
+    #[error("Deadline exceeded")]
+    DeadlineExceeded,
+
+    #[error("Operation cancelled")]
+    Cancelled,
+
+    #[error("Request too large: {0}")]
+    RequestTooLarge(String),
+
+    #[error("Unsupported by the server: {0}")]
+    Unsupported(String),
+
+    #[error("Connection pool closed")]
+    PoolClosed,
+}
+
+pub type ProtocolResult<T> = Result<T, ProtocolError>;
+
+impl ProtocolError {
+    // The SQLite code of a server side failure, if this is one
+    pub fn sqlite_code(&self) -> Option<SqliteCode> {
+        match self {
+            ProtocolError::Failure { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    // Errors where the request may or may not have reached the server, and
+    // retrying on a healthy connection can succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ProtocolError::Io(_))
+    }
+
+    // A copy for reporting. io::Error isn't Clone, so an Io error keeps its
+    // kind and message but loses its source.
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            ProtocolError::Io(e) => ProtocolError::Io(io::Error::new(e.kind(), e.to_string())),
+            ProtocolError::Failure { code, message } => ProtocolError::Failure { code: *code, message: message.clone() },
+            ProtocolError::Protocol(message) => ProtocolError::Protocol(message.clone()),
+            ProtocolError::DeadlineExceeded => ProtocolError::DeadlineExceeded,
+            ProtocolError::Cancelled => ProtocolError::Cancelled,
+            ProtocolError::RequestTooLarge(message) => ProtocolError::RequestTooLarge(message.clone()),
+            ProtocolError::Unsupported(message) => ProtocolError::Unsupported(message.clone()),
+            ProtocolError::PoolClosed => ProtocolError::PoolClosed,
+        }
+    }
+}
+
+impl From<ProtocolError> for io::Error {
+    fn from(err: ProtocolError) -> Self {
+        match err {
+            ProtocolError::Io(e) => e,
+            other => io::Error::other(other),
+        }
+    }
+}
+
+// Longest database name the dqlite VFS accepts, excluding the terminator
+const MAX_DB_NAME_LENGTH: usize = 511;
+// SQLite's default SQLITE_MAX_SQL_LENGTH
+const MAX_SQL_LENGTH: usize = 1_000_000_000;
+// SQLite's default SQLITE_MAX_LENGTH: the largest text or blob value the
+// server accepts, and so the largest blob that can be sent inline. The frame
+// limit (MAX_BODY_SIZE) is far above it.
+const MAX_VALUE_LENGTH: usize = 1_000_000_000;
+
+// Reject text the server can't accept before it is framed. A NUL byte would
+// end the field early and desync the rest of the body.
+fn check_text(field: &str, value: &str, max: usize) -> ProtocolResult<()> {
+    if value.len() > max {
+        return Err(ProtocolError::RequestTooLarge(format!(
+            "{} is {} bytes, limit is {}",
+            field,
+            value.len(),
+            max
+        )));
+    }
+    if value.as_bytes().contains(&0) {
+        return Err(ProtocolError::Protocol(format!("{} contains a NUL byte", field)));
+    }
+    Ok(())
+}
+
+// Encode `params` into `request`, rejecting values the server would refuse
+// as too big before any of them is copied
+fn put_params(request: &mut Message, params: &[Value]) -> ProtocolResult<()> {
+    for (i, param) in params.iter().enumerate() {
+        let len = match param {
+            Value::Blob(blob) => blob.len(),
+            Value::Text(text) => text.len(),
+            _ => continue,
+        };
+        if len > MAX_VALUE_LENGTH {
+            return Err(ProtocolError::RequestTooLarge(format!(
+                "parameter {} is {} bytes, limit is {}; dqlite has no chunked transfer, \
+                 split big blobs across rows",
+                i + 1,
+                len,
+                MAX_VALUE_LENGTH
+            )));
+        }
+    }
+    request.put_params(params)?;
+    Ok(())
+}
+
+// The header counts body words in a u32; a longer body can't be framed and
+// would otherwise go out with a truncated word count
+fn check_frame(request: &Message) -> ProtocolResult<()> {
+    let size = request.body().len() as u64;
+    if size > MAX_BODY_SIZE {
+        return Err(ProtocolError::RequestTooLarge(format!(
+            "body of {} bytes exceeds the {} byte frame limit; \
+             dqlite can't stream larger values, split big blobs across rows",
+            size, MAX_BODY_SIZE
+        )));
+    }
+    Ok(())
+}
+
+// REQUEST_OPEN for `name` with `flags`
+pub(crate) fn open_request(name: &str, flags: OpenFlags) -> ProtocolResult<Message> {
+    flags.validate().map_err(ProtocolError::Protocol)?;
+
+    let mut request = Message::new(REQUEST_OPEN);
+    check_text("Database name", name, MAX_DB_NAME_LENGTH)?;
+    request.put_text(name);
+    request.put_u64(flags.bits());
+    request.put_text("volatile");
+    Ok(request)
+}
+
+// REQUEST_EXEC_SQL or REQUEST_QUERY_SQL: database id, SQL text and parameters
+pub(crate) fn sql_request(mtype: u8, db_id: u32, sql: &str, params: &[Value]) -> ProtocolResult<Message> {
+    let mut request = Message::new(mtype);
+    request.put_u64(db_id as u64);
+    check_text("SQL", sql, MAX_SQL_LENGTH)?;
+    request.put_text(sql);
+    put_params(&mut request, params)?;
+    Ok(request)
+}
+
+// Body of RESPONSE_DB: the database id followed by a reserved word that the
+// server always sends as zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DbReply {
+    pub id: u32,
+    pub reserved: u32,
+}
+
+impl DbReply {
+    pub(crate) fn decode(response: &mut Message) -> ProtocolResult<Self> {
+        let id = response.get_u32()?;
+        let reserved = response.get_u32()?;
+        // Anything else means we're reading the stream at the wrong offset
+        if reserved != 0 {
+            return Err(ProtocolError::Protocol(format!(
+                "Unexpected reserved word {:#x} in database reply",
+                reserved
+            )));
+        }
+        Ok(Self { id, reserved })
+    }
+}
+
+// Body of RESPONSE_METADATA
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeMetadata {
+    pub failure_domain: u64,
+    pub weight: u64,
+}
+
+// Prepared statement handle returned by RESPONSE_STMT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StmtInfo {
+    pub db_id: u32,
+    pub stmt_id: u32,
+    pub params: u64,
+}
+
+/// Short lived per-connection instance.
+///
+/// A Protocol owns its connection outright and every request takes
+/// `&mut self`, so requests on one connection are serialized by the borrow
+/// checker rather than a lock, and nothing blocks a runtime worker while a
+/// request waits on the socket. Sharing a connection between tasks means
+/// wrapping the Protocol in an async mutex, as Database does with
+/// `tokio::sync::Mutex`, or handing it to a ProtocolActor.
 pub struct Protocol {
     version: u64,
-    conn: Arc<Mutex<Conn>>,
-    netErr: String,
+    conn: Conn,
+    // Set once an I/O failure or timeout leaves the stream at an unknown
+    // position; every later request fails fast with it
+    net_err: Option<String>,
     addr: String,
-    lt: Mutex<Option<Weak<LeaderTracker>>>,
+    // Sequence numbers of requests written but not yet fully answered, oldest
+    // first. dqlite answers strictly in order and responses carry no id, so
+    // this is what ties a response to its request when pipelining.
+    next_seq: u64,
+    in_flight: VecDeque<u64>,
+    deadline: Deadline,
+    // Last values announced by the server in WELCOME / heartbeat replies
+    heartbeat_timeout: Option<Duration>,
+    servers: Vec<NodeInfo>,
+    // Databases opened on this connection and when a request last used them
+    databases: Vec<OpenDatabase>,
+    span_namer: SpanNamer,
+    #[cfg(feature = "metrics-hist")]
+    latency: SharedLatencyRecorder,
+}
+
+struct OpenDatabase {
+    id: u32,
+    name: String,
+    last_used: Instant,
 }
 
 pub struct SharedProtocol {
     pub proto: Arc<Protocol>,
 }
 
+impl Protocol {
+    // A protocol that will offer VERSION_ONE in its handshake
+    pub fn new(conn: Conn, addr: &str) -> Self {
+        Self::with_version(conn, addr, VERSION_ONE)
+    }
+
+    /// A protocol that will offer `version` (VERSION_ONE or VERSION_LEGACY)
+    /// in its handshake. The server never answers the handshake itself, so
+    /// picking the version is up to the caller; Connector does it by falling
+    /// back to VERSION_LEGACY when a server hangs up on VERSION_ONE.
+    pub fn with_version(conn: Conn, addr: &str, version: u64) -> Self {
+        Self {
+            version,
+            conn,
+            net_err: None,
+            addr: addr.to_string(),
+            next_seq: 0,
+            in_flight: VecDeque::new(),
+            deadline: Deadline::none(),
+            heartbeat_timeout: None,
+            servers: Vec::new(),
+            databases: Vec::new(),
+            span_namer: default_span_name,
+            #[cfg(feature = "metrics-hist")]
+            latency: SharedLatencyRecorder::default(),
+        }
+    }
+
+    /// Run the whole session setup on a freshly dialed connection: protocol
+    /// handshake, client registration and opening `db_name`. Returns the ready
+    /// protocol and the database id.
+    ///
+    /// The sequence is bounded by `config.attempt_timeout` when it is set; the
+    /// returned protocol has no deadline.
+    pub async fn establish(conn: Conn, client_id: u64, db_name: &str, config: &Config) -> ProtocolResult<(Protocol, u32)> {
+        let addr = conn.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+        let mut proto = Protocol::new(conn, &addr);
+        if let Some(namer) = config.span_namer {
+            proto.set_span_namer(namer);
+        }
+        if !config.attempt_timeout.is_zero() {
+            proto.set_deadline(Deadline::after(config.attempt_timeout));
+        }
+
+        proto.handshake().await?;
+        proto.register_client(client_id).await?;
+        let db_id = proto.open(db_name, OpenFlags::default()).await?;
+
+        proto.set_deadline(Deadline::none());
+        Ok((proto, db_id))
+    }
+
+    pub fn set_span_namer(&mut self, namer: SpanNamer) {
+        self.span_namer = namer;
+    }
+
+    // Span wrapping one request/response exchange. `otel.name` is the field
+    // OpenTelemetry exporters use as the span name.
+    fn request_span(&self, request_type: u8) -> tracing::Span {
+        let name = (self.span_namer)(&RequestType(request_type));
+        tracing::debug_span!(
+            "dqlite.request",
+            otel.name = %name,
+            request_type = request_type,
+            addr = %self.addr,
+        )
+    }
+
+    // Request latency percentiles per request type, since the connection
+    // opened or, with a shared recorder, across every connection using it
+    #[cfg(feature = "metrics-hist")]
+    pub fn latency_stats(&self) -> LatencyStats {
+        self.latency.stats()
+    }
+
+    // Record into `recorder` instead of this connection's own histograms
+    #[cfg(feature = "metrics-hist")]
+    pub fn set_latency_recorder(&mut self, recorder: SharedLatencyRecorder) {
+        self.latency = recorder;
+    }
+
+    #[cfg(feature = "metrics-hist")]
+    fn record_latency(&mut self, request_type: u8, start: Instant) {
+        self.latency.record(request_type, start.elapsed());
+    }
+
+    #[cfg(not(feature = "metrics-hist"))]
+    fn record_latency(&mut self, _request_type: u8, _start: Instant) {}
+
+    // Heartbeat timeout negotiated in register_client, if it has been called
+    pub fn heartbeat_timeout(&self) -> Option<Duration> {
+        self.heartbeat_timeout
+    }
+
+    // Cluster members reported by the most recent heartbeat. Heartbeats don't
+    // carry roles or failure domains: every entry is a voter without one.
+    pub fn servers(&self) -> &[NodeInfo] {
+        &self.servers
+    }
+
+    // Bound every subsequent request on this connection by `deadline`
+    pub fn set_deadline(&mut self, deadline: Deadline) {
+        self.deadline = deadline;
+    }
+
+    pub fn deadline(&self) -> Deadline {
+        self.deadline
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    // Socket address of the peer, which differs from addr() behind a proxy
+    pub fn peer_addr(&self) -> io::Result<Addr> {
+        self.conn.peer_addr()
+    }
+
+    /// Shut the connection down cleanly, so the server sees an orderly
+    /// disconnect instead of a reset, and release it. Dropping a Protocol
+    /// closes the socket without the shutdown.
+    pub async fn close(mut self) -> ProtocolResult<()> {
+        let deadline = self.deadline;
+        deadline
+            .run(async { self.conn.shutdown().await.map_err(ProtocolError::Io) })
+            .await
+    }
+
+    // Whether a network failure has made this connection unusable
+    pub fn is_broken(&self) -> bool {
+        self.net_err.is_some()
+    }
+
+    fn check_net(&self) -> ProtocolResult<()> {
+        match &self.net_err {
+            Some(err) => Err(ProtocolError::Protocol(format!(
+                "Connection to {} is broken: {}",
+                self.addr, err
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // Remember failures after which the stream can't be trusted any more
+    fn note_net_err<T>(&mut self, result: ProtocolResult<T>) -> ProtocolResult<T> {
+        if let Err(e @ (ProtocolError::Io(_) | ProtocolError::DeadlineExceeded | ProtocolError::Cancelled)) = &result {
+            self.net_err = Some(e.to_string());
+        }
+        result
+    }
+
+    // Send the protocol version, which must be the first thing written on a new connection
+    pub async fn handshake(&mut self) -> ProtocolResult<()> {
+        let mut version = Vec::with_capacity(8);
+        wire::put_u64_le(&mut version, self.version);
+        self.write_bytes(&version).await
+    }
+
+    async fn write_bytes(&mut self, bytes: &[u8]) -> ProtocolResult<()> {
+        self.check_net()?;
+        let conn = &mut self.conn;
+        let result = self
+            .deadline
+            .run(async move {
+                conn.write_all(bytes).await?;
+                conn.flush().await?;
+                Ok(())
+            })
+            .await;
+        self.note_net_err(result)
+    }
+
+    // Write one request, returning its sequence number
+    async fn send(&mut self, request: &Message) -> ProtocolResult<u64> {
+        check_frame(request)?;
+        let mut frame = Vec::with_capacity(HEADER_SIZE + request.body().len());
+        request.encode_into(&mut frame);
+        let seq = self.issue(request.mtype);
+        self.write_bytes(&frame).await?;
+        Ok(seq)
+    }
+
+    // Allocate the sequence number of a request about to be written
+    fn issue(&mut self, request_type: u8) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.in_flight.push_back(seq);
+        tracing::trace!(seq, request_type, "request sent");
+        seq
+    }
+
+    // Mark request `seq` as answered. Responses must settle requests in send
+    // order; anything else means our framing went wrong, so the connection is
+    // poisoned rather than risk handing a response to the wrong caller.
+    fn settle(&mut self, seq: u64) -> ProtocolResult<()> {
+        let oldest = self.in_flight.pop_front();
+        if oldest == Some(seq) {
+            tracing::trace!(seq, "response received");
+            return Ok(());
+        }
+
+        let err = format!("response for request {} arrived while {:?} was the oldest pending", seq, oldest);
+        tracing::error!(addr = %self.addr, "{}", err);
+        self.net_err = Some(err.clone());
+        Err(ProtocolError::Protocol(err))
+    }
+
+    // Settle `seq` once the server has answered it, successfully or with a
+    // failure response. Transport errors poison the connection instead.
+    fn settle_result<T>(&mut self, seq: u64, result: ProtocolResult<T>) -> ProtocolResult<T> {
+        if matches!(result, Ok(_) | Err(ProtocolError::Failure { .. })) {
+            self.settle(seq)?;
+        }
+        result
+    }
+
+    async fn recv(&mut self) -> ProtocolResult<Message> {
+        self.check_net()?;
+        let conn = &mut self.conn;
+        let result = self
+            .deadline
+            .run(async move {
+                let mut header = [0u8; HEADER_SIZE];
+                conn.read_exact(&mut header).await?;
+
+                let words = Message::header_words(&header) as usize;
+                let mut body = vec![0u8; words * WORD_SIZE];
+                conn.read_exact(&mut body).await?;
+                Ok((header, body))
+            })
+            .await;
+        let (header, body) = self.note_net_err(result)?;
+
+        let mut response = Message::from_parts(header, body);
+        if response.mtype == RESPONSE_FAILURE {
+            let code = SqliteCode::from_raw(response.get_u64()?);
+            let message = response.get_text()?;
+            return Err(ProtocolError::Failure { code, message });
+        }
+        Ok(response)
+    }
+
+    // Send a request and read back a response of the expected type
+    async fn call(&mut self, request: &Message, expected: u8) -> ProtocolResult<Message> {
+        let span = self.request_span(request.mtype);
+        let start = Instant::now();
+        let response = async {
+            let seq = self.send(request).await?;
+            let response = self.recv().await;
+            self.settle_result(seq, response)
+        }
+        .instrument(span)
+        .await;
+        self.record_latency(request.mtype, start);
+        let response = response?;
+        if response.mtype != expected {
+            return Err(ProtocolError::Protocol(format!(
+                "Unexpected response type {} (expected {})",
+                response.mtype, expected
+            )));
+        }
+        Ok(response)
+    }
+
+    // Write `request` without reading its response. Responses to requests
+    // sent this way must be read, oldest first, with recv_pipelined.
+    pub(crate) async fn send_pipelined(&mut self, request: &Message) -> ProtocolResult<u64> {
+        self.send(request).await
+    }
+
+    // Wait until the server has started answering, without consuming any of
+    // the answer, so this can be dropped half way without desyncing the stream
+    pub(crate) async fn readable(&mut self) -> ProtocolResult<()> {
+        self.check_net()?;
+        let result = match self.conn.fill_buf().await {
+            Ok([]) => Err(ProtocolError::Io(io::ErrorKind::UnexpectedEof.into())),
+            Ok(_) => Ok(()),
+            Err(e) => Err(ProtocolError::Io(e)),
+        };
+        self.note_net_err(result)
+    }
+
+    // Read the response to `seq`, which must be the oldest request in flight
+    pub(crate) async fn recv_pipelined(&mut self, seq: u64, expected: u8) -> ProtocolResult<Message> {
+        let response = self.recv().await;
+        let response = self.settle_result(seq, response)?;
+        if response.mtype != expected {
+            return Err(ProtocolError::Protocol(format!(
+                "Unexpected response type {} (expected {})",
+                response.mtype, expected
+            )));
+        }
+        Ok(response)
+    }
+
+    // Like recv_pipelined, for a query: every batch up to the last one
+    pub(crate) async fn recv_rows_pipelined(&mut self, seq: u64) -> ProtocolResult<Rows> {
+        let rows = self.recv_rows().await;
+        self.settle_result(seq, rows)
+    }
+
+    // Register the client and return the heartbeat timeout announced by the server
+    pub async fn register_client(&mut self, client_id: u64) -> ProtocolResult<u64> {
+        let mut request = Message::new(REQUEST_CLIENT);
+        request.put_u64(client_id);
+
+        let mut response = self.call(&request, RESPONSE_WELCOME).await?;
+        let timeout = response.get_u64()?;
+        self.heartbeat_timeout = Some(Duration::from_millis(timeout));
+        Ok(timeout)
+    }
+
+    // Send a heartbeat and record the server list the server replies with
+    pub async fn heartbeat(&mut self) -> ProtocolResult<Vec<NodeInfo>> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut request = Message::new(REQUEST_HEARTBEAT);
+        request.put_u64(timestamp);
+
+        let mut response = self.call(&request, RESPONSE_NODES).await?;
+        let count = response.get_u64()?;
+        let mut servers = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let id = response.get_u64()?;
+            let addr = response.get_text()?;
+            // Heartbeat replies use the legacy format without roles
+            servers.push(NodeInfo {
+                id,
+                addr,
+                role: NodeRole::VOTER,
+                failure_domain: None,
+            });
+        }
+
+        self.servers = servers.clone();
+        Ok(servers)
+    }
+
+    // Current cluster membership, including each node's role
+    pub async fn cluster(&mut self) -> ProtocolResult<Vec<NodeInfo>> {
+        let mut request = Message::new(REQUEST_CLUSTER);
+        // Format 1 adds the role to each entry
+        request.put_u64(1);
+
+        let mut response = self.call(&request, RESPONSE_NODES).await?;
+        let count = response.get_u64()?;
+        let mut nodes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let id = response.get_u64()?;
+            let addr = response.get_text()?;
+            let role = NodeRole::new(response.get_u64()? as u8).map_err(ProtocolError::Protocol)?;
+            nodes.push(NodeInfo { id, addr, role, failure_domain: None });
+        }
+        Ok(nodes)
+    }
+
+    // Change the role of node `id`. Must be sent to the leader.
+    pub async fn assign(&mut self, id: u64, role: NodeRole) -> ProtocolResult<()> {
+        let mut request = Message::new(REQUEST_ASSIGN);
+        request.put_u64(id);
+        request.put_u64(role.value() as u64);
+
+        self.call(&request, RESPONSE_EMPTY).await?;
+        Ok(())
+    }
+
+    // Set the weight of node `id`, used by the leader when it picks nodes to
+    // promote. Only available with protocol version one.
+    pub async fn set_weight(&mut self, id: u64, weight: u64) -> ProtocolResult<()> {
+        if self.version != VERSION_ONE {
+            return Err(ProtocolError::Unsupported(format!(
+                "REQUEST_WEIGHT needs protocol version {}, negotiated {:#x}",
+                VERSION_ONE, self.version
+            )));
+        }
+
+        let mut request = Message::new(REQUEST_WEIGHT);
+        request.put_u64(id);
+        request.put_u64(weight);
+
+        self.call(&request, RESPONSE_EMPTY).await?;
+        Ok(())
+    }
+
+    // Failure domain and weight of the node serving this connection. That is
+    // all REQUEST_DESCRIBE reports: raft progress (last index and term) isn't
+    // exposed over the wire, only locally through Node::describe_last_entry
+    // on a stopped node. Follower lag can't be measured from the client
+    // either: followers refuse every query, so a counter table can only ever
+    // be read on the leader. Only available with protocol version one.
+    pub async fn describe(&mut self) -> ProtocolResult<NodeMetadata> {
+        if self.version != VERSION_ONE {
+            return Err(ProtocolError::Unsupported(format!(
+                "REQUEST_DESCRIBE needs protocol version {}, negotiated {:#x}",
+                VERSION_ONE, self.version
+            )));
+        }
+
+        let mut request = Message::new(REQUEST_DESCRIBE);
+        // Format version
+        request.put_u64(0);
+
+        let mut response = self.call(&request, RESPONSE_METADATA).await?;
+        let failure_domain = response.get_u64()?;
+        let weight = response.get_u64()?;
+        Ok(NodeMetadata { failure_domain, weight })
+    }
+
+    // Ask the server who the current leader is. An id of 0 means no leader is known.
+    pub async fn leader(&mut self) -> ProtocolResult<(u64, String)> {
+        let mut request = Message::new(REQUEST_LEADER);
+        request.put_u64(0);
+
+        let mut response = self.call(&request, RESPONSE_NODE).await?;
+        // Legacy servers reply with the address only; the id is unknown
+        if self.version == VERSION_LEGACY {
+            return Ok((0, response.get_text()?));
+        }
+        let id = response.get_u64()?;
+        let addr = response.get_text()?;
+        Ok((id, addr))
+    }
+
+    // Open a database, passing the SQLite open flags along to the server.
+    // OpenFlags::default() is the usual read-write, create-if-missing open.
+    // The server allows one open database per connection (a second open
+    // fails with SQLITE_BUSY) and has no request to close it: it stays open
+    // until the connection does, see close_database.
+    pub async fn open(&mut self, name: &str, flags: impl Into<OpenFlags>) -> ProtocolResult<u32> {
+        let request = open_request(name, flags.into())?;
+        let mut response = self.call(&request, RESPONSE_DB).await?;
+        let reply = DbReply::decode(&mut response)?;
+        self.databases.retain(|db| db.id != reply.id);
+        self.databases.push(OpenDatabase { id: reply.id, name: name.to_string(), last_used: Instant::now() });
+        Ok(reply.id)
+    }
+
+    // Names of the databases opened on this connection and not closed since
+    pub fn open_databases(&self) -> Vec<&str> {
+        self.databases.iter().map(|db| db.name.as_str()).collect()
+    }
+
+    /// Close `db_id`: it is removed from the open databases and, once none
+    /// is left, the connection is shut down. dqlite has no request to close
+    /// a database, so that is how the server is made to release it; the
+    /// protocol fails every later request.
+    pub async fn close_database(&mut self, db_id: u32) -> ProtocolResult<()> {
+        let before = self.databases.len();
+        self.databases.retain(|db| db.id != db_id);
+        self.release_databases(before).await
+    }
+
+    /// Close every database no request has used for `idle`, as
+    /// [`Protocol::close_database`] does, and return how many were closed.
+    pub async fn close_idle_databases(&mut self, idle: Duration) -> ProtocolResult<usize> {
+        let before = self.databases.len();
+        self.databases.retain(|db| db.last_used.elapsed() < idle);
+        self.release_databases(before).await?;
+        Ok(before - self.databases.len())
+    }
+
+    // Shut the connection down if closing databases left none of the
+    // `before` open
+    async fn release_databases(&mut self, before: usize) -> ProtocolResult<()> {
+        if before == 0 || !self.databases.is_empty() || self.net_err.is_some() {
+            return Ok(());
+        }
+        self.net_err = Some("connection shut down to close its databases".to_string());
+        let deadline = self.deadline;
+        deadline
+            .run(async { self.conn.shutdown().await.map_err(ProtocolError::Io) })
+            .await
+    }
+
+    fn touch(&mut self, db_id: u32) {
+        if let Some(db) = self.databases.iter_mut().find(|db| db.id == db_id) {
+            db.last_used = Instant::now();
+        }
+    }
+
+    pub async fn prepare(&mut self, db_id: u32, sql: &str) -> ProtocolResult<StmtInfo> {
+        self.touch(db_id);
+        let mut request = Message::new(REQUEST_PREPARE);
+        request.put_u64(db_id as u64);
+        check_text("SQL", sql, MAX_SQL_LENGTH)?;
+        request.put_text(sql);
+
+        let mut response = self.call(&request, RESPONSE_STMT).await?;
+        Ok(StmtInfo {
+            db_id: response.get_u32()?,
+            stmt_id: response.get_u32()?,
+            params: response.get_u64()?,
+        })
+    }
+
+    pub async fn exec(&mut self, db_id: u32, stmt_id: u32, params: &[Value]) -> ProtocolResult<ExecResult> {
+        self.touch(db_id);
+        let mut request = Message::new(REQUEST_EXEC);
+        request.put_u32(db_id);
+        request.put_u32(stmt_id);
+        put_params(&mut request, params)?;
+
+        let mut response = self.call(&request, RESPONSE_RESULT).await?;
+        Ok(response.get_result()?)
+    }
+
+    pub async fn query(&mut self, db_id: u32, stmt_id: u32, params: &[Value]) -> ProtocolResult<Rows> {
+        self.touch(db_id);
+        let mut request = Message::new(REQUEST_QUERY);
+        request.put_u32(db_id);
+        request.put_u32(stmt_id);
+        put_params(&mut request, params)?;
+
+        let span = self.request_span(request.mtype);
+        let start = Instant::now();
+        let rows = async {
+            let seq = self.send(&request).await?;
+            let rows = self.recv_rows().await;
+            self.settle_result(seq, rows)
+        }
+        .instrument(span)
+        .await;
+        self.record_latency(request.mtype, start);
+        rows
+    }
+
+    /// Execute a prepared statement once per parameter tuple in `rows`.
+    ///
+    /// dqlite has no multi-tuple EXEC, so on protocol version one all requests
+    /// are encoded back to back into a single buffer, written in one go, and
+    /// the results are read back in order. Every response is consumed even if
+    /// one fails, keeping the stream in sync; the first failure is returned.
+    /// Older protocol versions fall back to one round trip per tuple.
+    pub async fn exec_many(&mut self, db_id: u32, stmt_id: u32, rows: &[Vec<Value>]) -> ProtocolResult<Vec<ExecResult>> {
+        self.touch(db_id);
+        if self.version != VERSION_ONE {
+            let mut results = Vec::with_capacity(rows.len());
+            for params in rows {
+                results.push(self.exec(db_id, stmt_id, params).await?);
+            }
+            return Ok(results);
+        }
+
+        let mut requests = Vec::with_capacity(rows.len());
+        for params in rows {
+            let mut request = Message::new(REQUEST_EXEC);
+            request.put_u32(db_id);
+            request.put_u32(stmt_id);
+            put_params(&mut request, params)?;
+            check_frame(&request)?;
+            requests.push(request);
+        }
+        self.pipeline(&requests).await
+    }
+
+    /// Execute several SQL statements, pipelined the same way as exec_many:
+    /// one write for all of them, then every result read back in order. The
+    /// first failure is returned once all responses have been consumed.
+    pub async fn exec_sql_many(&mut self, db_id: u32, statements: &[(String, Vec<Value>)]) -> ProtocolResult<Vec<ExecResult>> {
+        self.touch(db_id);
+        if self.version != VERSION_ONE {
+            let mut results = Vec::with_capacity(statements.len());
+            for (sql, params) in statements {
+                results.push(self.exec_sql(db_id, sql, params).await?);
+            }
+            return Ok(results);
+        }
+
+        let mut requests = Vec::with_capacity(statements.len());
+        for (sql, params) in statements {
+            let request = sql_request(REQUEST_EXEC_SQL, db_id, sql, params)?;
+            check_frame(&request)?;
+            requests.push(request);
+        }
+        self.pipeline(&requests).await
+    }
+
+    // Write exec requests back to back and read their results in order
+    async fn pipeline(&mut self, requests: &[Message]) -> ProtocolResult<Vec<ExecResult>> {
+        let mut batch = Vec::new();
+        let mut seqs = Vec::with_capacity(requests.len());
+        for request in requests {
+            request.encode_into(&mut batch);
+            seqs.push(self.issue(request.mtype));
+        }
+        self.write_bytes(&batch).await?;
+
+        let mut results = Vec::with_capacity(requests.len());
+        let mut first_err = None;
+        for seq in seqs {
+            let response = self.recv().await;
+            match self.settle_result(seq, response) {
+                Ok(mut response) if response.mtype == RESPONSE_RESULT => {
+                    results.push(response.get_result()?);
+                }
+                Ok(response) => {
+                    first_err.get_or_insert(ProtocolError::Protocol(format!(
+                        "Unexpected response type {} (expected {})",
+                        response.mtype, RESPONSE_RESULT
+                    )));
+                }
+                // The connection is unusable, there is nothing left to drain
+                Err(err @ (ProtocolError::Io(_) | ProtocolError::Protocol(_))) => return Err(err),
+                Err(err) => {
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(results),
+        }
+    }
+
+    pub async fn finalize(&mut self, db_id: u32, stmt_id: u32) -> ProtocolResult<()> {
+        self.touch(db_id);
+        let mut request = Message::new(REQUEST_FINALIZE);
+        request.put_u32(db_id);
+        request.put_u32(stmt_id);
+
+        self.call(&request, RESPONSE_EMPTY).await?;
+        Ok(())
+    }
+
+    /// Execute a statement that does not produce rows and return its result.
+    ///
+    /// Whether a statement is treated as an exec or a query is decided solely
+    /// by the method the caller picks; the SQL text is never inspected. Use
+    /// [`Protocol::query_sql`] for statements that yield rows, including
+    /// `INSERT ... RETURNING`.
+    pub async fn exec_sql(&mut self, db_id: u32, sql: &str, params: &[Value]) -> ProtocolResult<ExecResult> {
+        self.touch(db_id);
+        let request = sql_request(REQUEST_EXEC_SQL, db_id, sql, params)?;
+        let mut response = self.call(&request, RESPONSE_RESULT).await?;
+        Ok(response.get_result()?)
+    }
+
+    /// Run a statement that produces rows and collect every batch the server sends.
+    ///
+    /// This is also the path for `INSERT/UPDATE/DELETE ... RETURNING`, for which
+    /// dqlite replies with RESPONSE_ROWS rather than RESPONSE_RESULT.
+    pub async fn query_sql(&mut self, db_id: u32, sql: &str, params: &[Value]) -> ProtocolResult<Rows> {
+        self.touch(db_id);
+        let request = sql_request(REQUEST_QUERY_SQL, db_id, sql, params)?;
+        let span = self.request_span(request.mtype);
+        let start = Instant::now();
+        let rows = async {
+            let seq = self.send(&request).await?;
+            let rows = self.recv_rows().await;
+            self.settle_result(seq, rows)
+        }
+        .instrument(span)
+        .await;
+        self.record_latency(request.mtype, start);
+        rows
+    }
+
+    // Ask the server to stop streaming rows for `db_id`. Row batches already
+    // in flight are read and discarded up to the server's RESPONSE_EMPTY, after
+    // which the connection is back in sync and can be reused.
+    pub async fn interrupt(&mut self, db_id: u32) -> ProtocolResult<()> {
+        let mut request = Message::new(REQUEST_INTERRUPT);
+        request.put_u64(db_id as u64);
+        let seq = self.send(&request).await?;
+
+        loop {
+            let response = self.recv().await?;
+            match response.mtype {
+                RESPONSE_ROWS => continue,
+                RESPONSE_EMPTY => {
+                    // The EMPTY also ends whatever query was being interrupted
+                    while self.in_flight.front().is_some_and(|&pending| pending < seq) {
+                        self.in_flight.pop_front();
+                    }
+                    return self.settle(seq);
+                }
+                other => {
+                    return Err(ProtocolError::Protocol(format!(
+                        "Unexpected response type {} to interrupt",
+                        other
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Like query_sql, but rows are read from the socket one batch at a time
+    /// as the returned stream is consumed. Only the first batch is read here.
+    pub async fn query_sql_stream(&mut self, db_id: u32, sql: &str, params: &[Value]) -> ProtocolResult<RowStream<'_>> {
+        self.touch(db_id);
+        let request = sql_request(REQUEST_QUERY_SQL, db_id, sql, params)?;
+        let seq = self.send(&request).await?;
+        let mut rows = Vec::new();
+        // The request stays pending while the server has more batches to send
+        let (columns, more) = match self.recv_batch(&mut rows).await {
+            Ok((columns, true)) => (columns, true),
+            result => self.settle_result(seq, result)?,
+        };
+        Ok(RowStream {
+            proto: self,
+            seq,
+            db_id,
+            columns,
+            batch: rows.into_iter(),
+            more,
+            runtime: tokio::runtime::Handle::try_current().ok(),
+        })
+    }
+
+    // Read RESPONSE_ROWS messages until the server marks the result set as done
+    async fn recv_rows(&mut self) -> ProtocolResult<Rows> {
+        let mut rows = Vec::new();
+        loop {
+            let (columns, more) = self.recv_batch(&mut rows).await?;
+            if !more {
+                return Ok(Rows::new(columns, rows));
+            }
+        }
+    }
+
+    // Read one RESPONSE_ROWS message into `rows`. Returns the column names and
+    // whether the server will send another batch.
+    async fn recv_batch(&mut self, rows: &mut Vec<Row>) -> ProtocolResult<(Vec<String>, bool)> {
+        let mut response = self.recv().await?;
+        if response.mtype != RESPONSE_ROWS {
+            return Err(ProtocolError::Protocol(format!(
+                "Unexpected response type {} (expected {})",
+                response.mtype, RESPONSE_ROWS
+            )));
+        }
+
+        let columns = response.get_columns()?;
+        let more = response.get_rows(columns.len(), rows)?;
+        Ok((columns, more))
+    }
+}
+
+/// Rows of a query, read lazily from the connection: the next batch is only
+/// read once every row of the current one has been taken. A slow consumer
+/// therefore leaves unread batches in the socket, where TCP flow control
+/// pushes back on the server, instead of buffering them here.
+///
+/// The stream borrows the protocol. Call `close` to stop a query before its
+/// last row. Dropping the stream instead interrupts the query on a best-effort
+/// basis, blocking on the runtime the stream was created in; when that isn't
+/// possible (a current_thread runtime) or the server doesn't acknowledge the
+/// interrupt, the connection is marked broken and must be discarded.
+pub struct RowStream<'a> {
+    proto: &'a mut Protocol,
+    seq: u64,
+    db_id: u32,
+    columns: Vec<String>,
+    batch: std::vec::IntoIter<Row>,
+    more: bool,
+    runtime: Option<tokio::runtime::Handle>,
+}
+
+impl<'a> RowStream<'a> {
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    pub async fn next(&mut self) -> Option<ProtocolResult<Row>> {
+        loop {
+            if let Some(row) = self.batch.next() {
+                return Some(Ok(row));
+            }
+            if !self.more {
+                return None;
+            }
+
+            let mut rows = Vec::new();
+            match self.proto.recv_batch(&mut rows).await {
+                Ok((_, true)) => self.batch = rows.into_iter(),
+                result => {
+                    self.more = false;
+                    match self.proto.settle_result(self.seq, result) {
+                        Ok(_) => self.batch = rows.into_iter(),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+            }
+        }
+    }
+
+    // Stop the query if the server still has rows to send
+    pub async fn close(mut self) -> ProtocolResult<()> {
+        if self.more {
+            self.more = false;
+            self.proto.interrupt(self.db_id).await?;
+        }
+        Ok(())
+    }
+
+    // The rows as a futures Stream, still pulled one batch at a time
+    pub fn into_stream(self) -> impl futures::Stream<Item = ProtocolResult<Row>> + 'a {
+        futures::stream::unfold(self, |mut rows| async move {
+            let item = rows.next().await?;
+            Some((item, rows))
+        })
+    }
+
+    // Interrupt the query from a synchronous context. Returns whether the
+    // server acknowledged it, which is what puts the connection back in sync.
+    fn interrupt_blocking(&mut self) -> bool {
+        use tokio::runtime::{Handle, RuntimeFlavor};
+
+        let Some(runtime) = self.runtime.clone() else {
+            return false;
+        };
+        let in_runtime = match Handle::try_current() {
+            // Only a multi-thread runtime can give up a worker to block on
+            Ok(current) if current.runtime_flavor() == RuntimeFlavor::MultiThread => true,
+            Ok(_) => return false,
+            Err(_) => false,
+        };
+
+        let previous = self.proto.deadline;
+        self.proto.set_deadline(previous.cap(DROP_INTERRUPT_TIMEOUT));
+        let interrupt = self.proto.interrupt(self.db_id);
+        let result = if in_runtime {
+            tokio::task::block_in_place(|| runtime.block_on(interrupt))
+        } else {
+            runtime.block_on(interrupt)
+        };
+        self.proto.set_deadline(previous);
+        result.is_ok()
+    }
+}
+
+// How long dropping an unfinished RowStream waits for the interrupt's ack
+const DROP_INTERRUPT_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl Drop for RowStream<'_> {
+    fn drop(&mut self) {
+        if !self.more || self.proto.is_broken() {
+            return;
+        }
+        self.more = false;
+        if !self.interrupt_blocking() {
+            tracing::warn!(addr = %self.proto.addr, "row stream dropped before its last batch and not interrupted");
+            self.proto.net_err = Some("row stream dropped before its last batch".to_string());
+        }
+    }
+}
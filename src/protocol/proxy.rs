@@ -0,0 +1,146 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::protocol::connector::{Conn, DialFunc};
+
+// Longest HTTP CONNECT response head we are willing to read
+const MAX_HTTP_RESPONSE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    // SOCKS5 without authentication (RFC 1928)
+    Socks5,
+    // HTTP/1.1 CONNECT tunnel
+    HttpConnect,
+}
+
+/// DialFunc reaching every node through the proxy at `proxy_addr`.
+///
+/// The node address is handed to the proxy as given, so host names are
+/// resolved on the proxy side. Unix socket addresses cannot be tunneled.
+pub fn proxy_dial(proxy_addr: &str, kind: ProxyKind) -> DialFunc {
+    let proxy_addr = proxy_addr.to_string();
+    Arc::new(move |addr: &str| {
+        let proxy_addr = proxy_addr.clone();
+        let addr = addr.to_string();
+        Box::pin(async move {
+            if addr.starts_with("unix:") {
+                return Err(format!("Cannot dial {} through a proxy", addr));
+            }
+            tunnel(&proxy_addr, &addr, kind)
+                .await
+                .map_err(|e| format!("Proxy {} to {}: {}", proxy_addr, addr, e))
+        })
+    })
+}
+
+async fn tunnel(proxy_addr: &str, target: &str, kind: ProxyKind) -> io::Result<Conn> {
+    let mut stream = TcpStream::connect(proxy_addr).await?;
+    match kind {
+        ProxyKind::Socks5 => socks5_connect(&mut stream, target).await?,
+        ProxyKind::HttpConnect => http_connect(&mut stream, target).await?,
+    }
+    Ok(Conn::from_tcp(stream))
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+// Split "host:port", accepting bracketed IPv6 hosts
+fn split_host_port(target: &str) -> io::Result<(String, u16)> {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        return Ok((addr.ip().to_string(), addr.port()));
+    }
+    let (host, port) = target
+        .rsplit_once(':')
+        .ok_or_else(|| invalid(format!("Address {} has no port", target)))?;
+    let port = port
+        .parse()
+        .map_err(|_| invalid(format!("Address {} has an invalid port", target)))?;
+    Ok((host.trim_matches(|c| c == '[' || c == ']').to_string(), port))
+}
+
+async fn socks5_connect(stream: &mut TcpStream, target: &str) -> io::Result<()> {
+    // Greeting: version 5, one method, "no authentication"
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [5, 0] {
+        return Err(invalid("SOCKS5 proxy refused unauthenticated access"));
+    }
+
+    let (host, port) = split_host_port(target)?;
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len())
+                .map_err(|_| invalid(format!("Host name {} is too long for SOCKS5", host)))?;
+            request.push(3);
+            request.push(len);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    // Reply: version, status, reserved, bound address type
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 {
+        return Err(invalid(format!("Unexpected SOCKS version {}", reply[0])));
+    }
+    if reply[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("SOCKS5 connect failed with status {}", reply[1]),
+        ));
+    }
+
+    // The bound address is of no use to us, but must be consumed
+    let bound_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        other => return Err(invalid(format!("Unknown SOCKS5 address type {}", other))),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+async fn http_connect(stream: &mut TcpStream, target: &str) -> io::Result<()> {
+    let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target);
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte so nothing past the response head is consumed: what
+    // follows already belongs to the dqlite stream.
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HTTP_RESPONSE {
+            return Err(invalid("HTTP CONNECT response head too large"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let status_line = head.lines().next().unwrap_or_default();
+    let status = status_line.split_whitespace().nth(1);
+    if !status.is_some_and(|code| code.starts_with('2')) {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("HTTP CONNECT failed: {}", status_line),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use std::time::Duration;
+use crate::protocol::config::Config;
+use crate::protocol::protocol::{ProtocolError, SqliteCode};
+
+/// Decides whether a failed operation is tried again, and after how long.
+///
+/// Set one with [`Config::with_retry_policy`] to replace the connector's
+/// built-in schedule. It is consulted by [`Connector::connect`] and by
+/// [`ReconnectingDatabase`]; the latter still never retries a write that may
+/// have been applied, whatever the policy says.
+///
+/// [`Connector::connect`]: crate::protocol::connector::Connector::connect
+/// [`ReconnectingDatabase`]: crate::protocol::database::ReconnectingDatabase
+pub trait RetryPolicy: Send + Sync {
+    /// `attempt` counts the failures so far, starting at 1. Return the delay
+    /// before the next try, or None to give up with `err`.
+    fn should_retry(&self, err: &ProtocolError, attempt: u32) -> Option<Duration>;
+}
+
+/// Retries I/O failures, requests that reached a node that isn't the leader,
+/// busy or locked databases, and statements sent with a database id the
+/// server doesn't know (they never ran), with capped exponential backoff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DefaultRetry {
+    pub backoff_factor: Duration,
+    pub backoff_cap: Duration,
+    pub retry_limit: u32,
+}
+
+impl DefaultRetry {
+    // Same schedule as a connector built from `config`
+    pub fn from_config(config: &Config) -> Self {
+        let defaults = Self::default();
+        Self {
+            backoff_factor: if config.backoff_factor.is_zero() { defaults.backoff_factor } else { config.backoff_factor },
+            backoff_cap: if config.backoff_cap.is_zero() { defaults.backoff_cap } else { config.backoff_cap },
+            retry_limit: config.retry_limit.unwrap_or(defaults.retry_limit),
+        }
+    }
+
+    // factor * 2^(attempt - 1), capped
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(16);
+        self.backoff_factor.saturating_mul(1 << exp).min(self.backoff_cap)
+    }
+}
+
+impl Default for DefaultRetry {
+    // The values Config::with_defaults fills in
+    fn default() -> Self {
+        Self {
+            backoff_factor: Duration::from_millis(100),
+            backoff_cap: Duration::from_secs(1),
+            retry_limit: 10,
+        }
+    }
+}
+
+impl RetryPolicy for DefaultRetry {
+    fn should_retry(&self, err: &ProtocolError, attempt: u32) -> Option<Duration> {
+        if attempt > self.retry_limit {
+            return None;
+        }
+        let retryable = err.is_transient()
+            || is_unknown_database(err)
+            || err.sqlite_code().is_some_and(|code| code.is_not_leader() || code.is_busy());
+        retryable.then(|| self.backoff(attempt))
+    }
+}
+
+/// Never retries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NoRetry;
+
+impl RetryPolicy for NoRetry {
+    fn should_retry(&self, _err: &ProtocolError, _attempt: u32) -> Option<Duration> {
+        None
+    }
+}
+
+// The server doesn't know our database id ("no database opened"), e.g. the
+// id belongs to an earlier connection. The statement never ran, so it is safe
+// to reopen by name and retry.
+pub(crate) fn is_unknown_database(err: &ProtocolError) -> bool {
+    err.sqlite_code().is_some_and(|code| code.primary() == SqliteCode::NOTFOUND)
+}
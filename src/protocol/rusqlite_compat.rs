@@ -0,0 +1,64 @@
+// Conversions between our Value and rusqlite's, so code already built on
+// rusqlite can hand its values straight to the protocol layer, and rows read
+// from a local SQLite database (e.g. by DatabaseNodeStore) can feed remote
+// statements. Compiled with the `rusqlite-compat` feature.
+
+use crate::protocol::message::Value;
+use rusqlite::types::{Value as SqliteValue, ValueRef as SqliteValueRef};
+
+impl From<SqliteValue> for Value {
+    fn from(v: SqliteValue) -> Self {
+        match v {
+            SqliteValue::Null => Value::Null,
+            SqliteValue::Integer(i) => Value::Integer(i),
+            SqliteValue::Real(f) => Value::Float(f),
+            SqliteValue::Text(s) => Value::Text(s),
+            SqliteValue::Blob(b) => Value::Blob(b),
+        }
+    }
+}
+
+// Text that isn't valid UTF-8 is converted lossily, since Value::Text holds a String
+impl From<SqliteValueRef<'_>> for Value {
+    fn from(v: SqliteValueRef<'_>) -> Self {
+        match v {
+            SqliteValueRef::Null => Value::Null,
+            SqliteValueRef::Integer(i) => Value::Integer(i),
+            SqliteValueRef::Real(f) => Value::Float(f),
+            SqliteValueRef::Text(t) => Value::Text(String::from_utf8_lossy(t).into_owned()),
+            SqliteValueRef::Blob(b) => Value::Blob(b.to_vec()),
+        }
+    }
+}
+
+// SQLite has no boolean or timestamp storage class, so Boolean becomes 0/1 like
+// it does on the wire, and timestamps their integer or text form
+impl From<Value> for SqliteValue {
+    fn from(v: Value) -> Self {
+        match v {
+            Value::Null => SqliteValue::Null,
+            Value::Integer(i) => SqliteValue::Integer(i),
+            Value::Float(f) => SqliteValue::Real(f),
+            Value::Text(s) => SqliteValue::Text(s),
+            Value::Blob(b) => SqliteValue::Blob(b),
+            Value::Boolean(b) => SqliteValue::Integer(b as i64),
+            Value::Unixtime(t) => SqliteValue::Integer(t),
+            Value::Iso8601(s) => SqliteValue::Text(s),
+        }
+    }
+}
+
+impl<'a> From<&'a Value> for SqliteValueRef<'a> {
+    fn from(v: &'a Value) -> Self {
+        match v {
+            Value::Null => SqliteValueRef::Null,
+            Value::Integer(i) => SqliteValueRef::Integer(*i),
+            Value::Float(f) => SqliteValueRef::Real(*f),
+            Value::Text(s) => SqliteValueRef::Text(s.as_bytes()),
+            Value::Blob(b) => SqliteValueRef::Blob(b),
+            Value::Boolean(b) => SqliteValueRef::Integer(*b as i64),
+            Value::Unixtime(t) => SqliteValueRef::Integer(*t),
+            Value::Iso8601(s) => SqliteValueRef::Text(s.as_bytes()),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use crate::protocol::connector::Connector;
+use crate::protocol::store::NodeStore;
+
+/// Routes keys (e.g. tenant ids) to one of several clusters using rendezvous
+/// (highest random weight) hashing: every shard scores the key and the
+/// highest score wins. Adding a shard only takes over the keys it now wins;
+/// removing one only moves the keys it owned.
+///
+/// Shards are identified by name, so scores and therefore placement only
+/// depend on the names, not on insertion order.
+pub struct ShardRouter<S: NodeStore + Send + Sync> {
+    shards: Vec<(String, Connector<S>)>,
+}
+
+impl<S: NodeStore + Send + Sync> ShardRouter<S> {
+    pub fn new() -> Self {
+        Self { shards: Vec::new() }
+    }
+
+    // Add a shard, replacing any existing shard with the same name
+    pub fn add(&mut self, name: impl Into<String>, connector: Connector<S>) {
+        let name = name.into();
+        self.shards.retain(|(n, _)| *n != name);
+        self.shards.push((name, connector));
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Connector<S>> {
+        let index = self.shards.iter().position(|(n, _)| n == name)?;
+        Some(self.shards.remove(index).1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.is_empty()
+    }
+
+    // Name of the shard owning `key`, None if there are no shards
+    pub fn shard_name(&self, key: &[u8]) -> Option<&str> {
+        self.owner(key).map(|(name, _)| name.as_str())
+    }
+
+    /// The connector of the shard owning `key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the router has no shards.
+    pub fn for_key(&self, key: &[u8]) -> &Connector<S> {
+        &self.owner(key).expect("ShardRouter has no shards").1
+    }
+
+    fn owner(&self, key: &[u8]) -> Option<&(String, Connector<S>)> {
+        // Ties are practically impossible; break them by name to stay deterministic
+        self.shards
+            .iter()
+            .max_by(|(a, _), (b, _)| score(a, key).cmp(&score(b, key)).then_with(|| b.cmp(a)))
+    }
+}
+
+impl<S: NodeStore + Send + Sync> Default for ShardRouter<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// FNV-1a over shard name and key, finished with the splitmix64 mixer so that
+// similar names still score independently. Stable across builds and
+// platforms, unlike std's DefaultHasher.
+fn score(shard: &str, key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in shard.as_bytes().iter().chain([0xffu8].iter()).chain(key) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
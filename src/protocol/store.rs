@@ -6,7 +6,9 @@ use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
 use std::path::PathBuf;
 use rusqlite::{Connection as SqliteConnection, params, Result as SqliteResult};
-use tokio::sync::{Mutex, broadcast};
+use tokio::sync::{Mutex, broadcast, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 use std::path::Path;
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
@@ -14,7 +16,7 @@ use async_trait::async_trait;
 use thiserror::Error;
 
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct NodeRole(u8);
 
 type NodeAddress = String;
@@ -28,7 +30,7 @@ impl NodeRole {
 
     pub fn new(value: u8) -> Result<Self, String> {
         match value {
-            0 | 1 | 2 => Ok(NodeRole(value)),
+            0..=2 => Ok(NodeRole(value)),
             _ => Err(format!("Invalid NodeRole value: {}", value)),
         }
     }
@@ -49,6 +51,20 @@ impl std::fmt::Display for NodeRole {
     }
 }
 
+// Inverse of Display, ignoring case; "standby" is accepted as well
+impl std::str::FromStr for NodeRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "voter" => Ok(NodeRole::VOTER),
+            "stand-by" | "standby" => Ok(NodeRole::STAND_BY),
+            "spare" => Ok(NodeRole::SPARE),
+            _ => Err(format!("Invalid NodeRole value: {}", s)),
+        }
+    }
+}
+
 impl Serialize for NodeRole {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -77,11 +93,41 @@ pub struct NodeInfo {
     #[serde(rename = "Address")]
     pub addr: String,
 
-    #[serde(rename = "Role")]
+    // Files written before roles existed have no Role; those nodes were voters
+    #[serde(rename = "Role", default = "default_role")]
     pub role: NodeRole,
+
+    // Client side placement hint, see ConnectorBuilder::failure_domain. The
+    // server doesn't report it, so it only comes from the store's own data;
+    // DatabaseNodeStore doesn't persist it.
+    #[serde(rename = "FailureDomain", default, skip_serializing_if = "Option::is_none")]
+    pub failure_domain: Option<u64>,
+}
+
+fn default_role() -> NodeRole {
+    NodeRole::VOTER
 }
 
 impl NodeInfo {
+    // Whether both entries describe the same node (id and address), ignoring role
+    pub fn same_identity(&self, other: &NodeInfo) -> bool {
+        self.id == other.id && self.addr == other.addr
+    }
+
+    // Whether both entries are the same cluster member, i.e. have the same
+    // id, whatever its address or role
+    pub fn same_node(&self, other: &NodeInfo) -> bool {
+        self.id == other.id
+    }
+
+    // For two entries of the same node: whether its address or role changed,
+    // i.e. whether the cluster sees a difference. The failure domain is a
+    // client side hint and doesn't count here, though diff_nodes still
+    // reports a change of it.
+    pub fn differs_materially(&self, other: &NodeInfo) -> bool {
+        self.addr != other.addr || self.role != other.role
+    }
+
     // Validate if the node info is valid
     pub fn validate(&self) -> Result<(), NodeStoreError> {
         if self.addr.is_empty() {
@@ -107,7 +153,7 @@ impl NodeInfo {
             return Ok(());
         }
 
-        return Err(NodeStoreError::InvalidNode(format!("Invalid address: {}", self.addr)));
+        Err(NodeStoreError::InvalidNode(format!("Invalid address: {}", self.addr)))
     }
 }
 
@@ -130,12 +176,79 @@ fn validate_nodes(nodes: &[NodeInfo]) -> NodeStoreResult<()> {
     Ok(())
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeChange {
+    Added(NodeInfo),
+    Removed(NodeInfo),
+    AddressChanged { id: NodeId, old: NodeAddress, new: NodeAddress },
+    RoleChanged { id: NodeId, old: NodeRole, new: NodeRole },
+    FailureDomainChanged { id: NodeId, old: Option<u64>, new: Option<u64> },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReconcileReport {
+    pub changes: Vec<NodeChange>,
+}
+
+impl ReconcileReport {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+// Classify how `incoming` differs from `current`. Nodes are matched by id, so a
+// node that moved shows up as AddressChanged rather than a remove plus add.
+// Every stored field counts, the failure domain included, so reconcile writes
+// whenever the stored data would change.
+pub fn diff_nodes(current: &[NodeInfo], incoming: &[NodeInfo]) -> ReconcileReport {
+    let current_by_id: HashMap<NodeId, &NodeInfo> = current.iter().map(|n| (n.id, n)).collect();
+    let incoming_ids: HashSet<NodeId> = incoming.iter().map(|n| n.id).collect();
+    let mut changes = Vec::new();
+
+    for node in incoming {
+        match current_by_id.get(&node.id) {
+            None => changes.push(NodeChange::Added(node.clone())),
+            Some(old) => {
+                if !old.same_identity(node) {
+                    changes.push(NodeChange::AddressChanged {
+                        id: node.id,
+                        old: old.addr.clone(),
+                        new: node.addr.clone(),
+                    });
+                }
+                if old.role != node.role {
+                    changes.push(NodeChange::RoleChanged {
+                        id: node.id,
+                        old: old.role,
+                        new: node.role,
+                    });
+                }
+                if old.failure_domain != node.failure_domain {
+                    changes.push(NodeChange::FailureDomainChanged {
+                        id: node.id,
+                        old: old.failure_domain,
+                        new: node.failure_domain,
+                    });
+                }
+            }
+        }
+    }
+
+    for node in current {
+        if !incoming_ids.contains(&node.id) {
+            changes.push(NodeChange::Removed(node.clone()));
+        }
+    }
+
+    ReconcileReport { changes }
+}
+
 #[derive(Error, Debug)]
 pub enum NodeStoreError {
     #[error("Invalid Node info: {0}")]
     InvalidNode(String),
 
-    #[error("Node not found: {0}")]
+    #[error("Node not found: {id}")]
     NotFound { id: u64 },
 
     #[error("Concurrent modification detected")]
@@ -155,7 +268,7 @@ pub type NodeStoreResult<T> = Result<T, NodeStoreError>;
 
 #[async_trait]
 pub trait NodeStore: Send + Sync {
-    /// Get all nodes
+    /// Get all nodes, sorted by id
     async fn get_all(&self) -> NodeStoreResult<Vec<NodeInfo>>;
     
     /// Get a single node by ID
@@ -166,6 +279,11 @@ pub trait NodeStore: Send + Sync {
     
     /// Set all nodes (atomic replace)
     async fn set_all(&self, nodes: Vec<NodeInfo>) -> NodeStoreResult<()>;
+
+    /// Check that `set_all(nodes)` would be accepted, without writing anything
+    async fn validate_set_all(&self, nodes: &[NodeInfo]) -> NodeStoreResult<()> {
+        validate_nodes(nodes)
+    }
     
     /// Add or update a single node
     async fn upsert(&self, node: NodeInfo) -> NodeStoreResult<()>;
@@ -175,17 +293,64 @@ pub trait NodeStore: Send + Sync {
     
     /// Get the store version (for optimistic locking)
     async fn version(&self) -> NodeStoreResult<NodeVersion>;
+
+    /// Number of nodes, without cloning them where the backend allows
+    async fn count(&self) -> NodeStoreResult<usize> {
+        Ok(self.get_all().await?.len())
+    }
     
     /// Set with version check (optimistic locking)
     async fn set_if_version(&self, nodes: Vec<NodeInfo>, version: NodeVersion) -> NodeStoreResult<()>;
+
+    /// Replace the current nodes with `incoming`, reporting what changed.
+    /// The write is guarded by the store version, so a concurrent modification
+    /// fails with VersionConflict instead of being silently overwritten.
+    async fn reconcile(&self, incoming: Vec<NodeInfo>) -> NodeStoreResult<ReconcileReport> {
+        let version = self.version().await?;
+        let current = self.get_all().await?;
+
+        let report = diff_nodes(&current, &incoming);
+        if !report.is_empty() {
+            self.set_if_version(incoming, version).await?;
+        }
+        Ok(report)
+    }
+}
+
+// Point-in-time copy of a NodeStoreBackend, see snapshot/restore
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendSnapshot {
+    nodes: HashMap<u64, NodeInfo>,
+    addresses: HashMap<String, u64>,
+    version: u64,
 }
 
+impl BackendSnapshot {
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    // Sorted by id, like NodeStoreBackend::get_all
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.nodes.values().cloned().collect();
+        nodes.sort_by_key(|node| node.id);
+        nodes
+    }
+}
+
+#[derive(Clone)]
 pub struct NodeStoreBackend {
     nodes: Arc<RwLock<HashMap<u64, NodeInfo>>>,
     addresses: Arc<RwLock<HashMap<String, u64>>>,
     version: Arc<RwLock<u64>>,
 }
 
+impl Default for NodeStoreBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl NodeStoreBackend {
     pub fn new() -> Self {
         Self {
@@ -202,8 +367,8 @@ impl NodeStoreBackend {
         let mut addresses_map = HashMap::new();
         
         for node in nodes {
-            nodes_map.insert(node.id, node);
             addresses_map.insert(node.addr.clone(), node.id);
+            nodes_map.insert(node.id, node);
         }
         
         Ok(Self {
@@ -213,21 +378,28 @@ impl NodeStoreBackend {
         })
     }
     
+    pub fn count(&self) -> usize {
+        self.nodes.read().unwrap().len()
+    }
+
+    // Sorted by id, so listings and the files written from them are stable
     pub fn get_all(&self) -> Vec<NodeInfo> {
         let nodes = self.nodes.read().unwrap();
-        nodes.values().copied().collect()
+        let mut all: Vec<NodeInfo> = nodes.values().cloned().collect();
+        all.sort_by_key(|node| node.id);
+        all
     }
     
     pub fn get_by_id(&self, id: u64) -> Option<NodeInfo> {
         let nodes = self.nodes.read().unwrap();
-        nodes.get(&id).copied()
+        nodes.get(&id).cloned()
     }
     
     pub fn get_by_address(&self, address: &str) -> Option<NodeInfo> {
         let addresses = self.addresses.read().unwrap();
         if let Some(&id) = addresses.get(address) {
             let nodes = self.nodes.read().unwrap();
-            nodes.get(&id).copied()
+            nodes.get(&id).cloned()
         } else {
             None
         }
@@ -244,8 +416,8 @@ impl NodeStoreBackend {
         addrs.clear();
         
         for node in nodes {
-            store.insert(node.id, node);
             addrs.insert(node.addr.clone(), node.id);
+            store.insert(node.id, node);
         }
         
         *version += 1;
@@ -263,8 +435,8 @@ impl NodeStoreBackend {
             addrs.remove(&old_node.addr);
         }
         
-        store.insert(node.id, node);
         addrs.insert(node.addr.clone(), node.id);
+        store.insert(node.id, node);
         *version += 1;
         
         Ok(())
@@ -296,12 +468,43 @@ impl NodeStoreBackend {
         }
         self.set_all(nodes)
     }
+
+    // Capture nodes and version together, consistent with each other
+    pub fn snapshot(&self) -> BackendSnapshot {
+        let store = self.nodes.read().unwrap();
+        let addrs = self.addresses.read().unwrap();
+        let version = self.version.read().unwrap();
+
+        BackendSnapshot {
+            nodes: store.clone(),
+            addresses: addrs.clone(),
+            version: *version,
+        }
+    }
+
+    // Put back a state captured by `snapshot`, version included. Unlike
+    // set_all this does not bump the version.
+    pub fn restore(&self, snapshot: BackendSnapshot) {
+        let mut store = self.nodes.write().unwrap();
+        let mut addrs = self.addresses.write().unwrap();
+        let mut version = self.version.write().unwrap();
+
+        *store = snapshot.nodes;
+        *addrs = snapshot.addresses;
+        *version = snapshot.version;
+    }
 }
 
 pub struct InMemoryNodeStore {
     backend: NodeStoreBackend,
 }
 
+impl Default for InMemoryNodeStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl InMemoryNodeStore {
     pub fn new() -> Self {
         Self {
@@ -339,15 +542,42 @@ impl NodeStore for InMemoryNodeStore {
     async fn version(&self) -> NodeStoreResult<u64> {
         Ok(self.backend.version())
     }
+
+    async fn count(&self) -> NodeStoreResult<usize> {
+        Ok(self.backend.count())
+    }
     
     async fn set_if_version(&self, nodes: Vec<NodeInfo>, expected_version: u64) -> NodeStoreResult<()> {
         self.backend.set_if_version(nodes, expected_version)
     }
 }
 
+/// When YamlNodeStore writes its file after a change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// Write (temp file, fsync, rename) on every change
+    #[default]
+    Always,
+    /// Coalesce changes and write at most once per interval
+    Batched(Duration),
+    /// Write only when the store is dropped
+    OnDrop,
+}
+
+// Pending-write bookkeeping shared with the Batched flush task
+#[derive(Default)]
+struct FlushState {
+    pending: AtomicBool,
+    scheduled: AtomicBool,
+    // Serializes the blocking writers, which share the temp file
+    write_lock: std::sync::Mutex<()>,
+}
+
 pub struct YamlNodeStore {
     backend: NodeStoreBackend,
     path: PathBuf,
+    policy: FlushPolicy,
+    flush: Arc<FlushState>,
 }
 
 impl YamlNodeStore {
@@ -355,23 +585,60 @@ impl YamlNodeStore {
         let path = path.as_ref().to_path_buf();
 
         let backend = if path.exists() {
-            let content = fs::read_to_string(&path).await?;
-            let nodes: Vec<NodeInfo> = serde_yaml::from_str(&content)
-                .map_err(|e| NodeStoreError::Serialization(e.to_string()))?;
+            let file = path.clone();
+            let nodes = tokio::task::spawn_blocking(move || decode_yaml_file(&file))
+                .await
+                .map_err(|e| NodeStoreError::Store(e.to_string()))??;
 
-            NodeStoreBackend::from_nodes(nodes)?;
+            NodeStoreBackend::from_nodes(nodes)?
         } else {
             NodeStoreBackend::new()
         };
 
-        Ok(Self { backend, path })
+        Ok(Self {
+            backend,
+            path,
+            policy: FlushPolicy::Always,
+            flush: Arc::new(FlushState::default()),
+        })
+    }
+
+    pub fn with_flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.policy = policy;
+        self
     }
 
     async fn save(&self) -> NodeStoreResult<()> {
-        let nodes = self.backend.get_all();
+        match self.policy {
+            FlushPolicy::Always => self.write().await,
+            FlushPolicy::OnDrop => {
+                self.flush.pending.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+            FlushPolicy::Batched(interval) => {
+                self.flush.pending.store(true, Ordering::SeqCst);
+                if !self.flush.scheduled.swap(true, Ordering::SeqCst) {
+                    let backend = self.backend.clone();
+                    let path = self.path.clone();
+                    let flush = self.flush.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(interval).await;
+                        flush.scheduled.store(false, Ordering::SeqCst);
+                        let _ = tokio::task::spawn_blocking(move || {
+                            if let Err(e) = flush_pending(&flush, &backend, &path) {
+                                log::warn!("Failed to flush {}: {}", path.display(), e);
+                            }
+                        })
+                        .await;
+                    });
+                }
+                Ok(())
+            }
+        }
+    }
 
-        let yaml = serde_yaml::to_string(&nodes)
-            .map_err(|e| NodeStoreError::Serialization(e.to_string()))?;
+    async fn write(&self) -> NodeStoreResult<()> {
+        let yaml = encode_yaml(&self.backend)?;
 
         let temp_path = self.path.with_extension("tmp");
         let mut file = fs::File::create(&temp_path).await?;
@@ -384,6 +651,57 @@ impl YamlNodeStore {
     }
 }
 
+// Blocking read of a file in YamlNodeStore's format, validated like the store
+// would. For callers outside a runtime, such as Node::recover_from_yaml.
+pub fn read_yaml_nodes(path: &Path) -> NodeStoreResult<Vec<NodeInfo>> {
+    let nodes = decode_yaml_file(path)?;
+    validate_nodes(&nodes)?;
+    Ok(nodes)
+}
+
+// Deserialize straight from the file rather than from a String copy of it.
+// serde_yaml still buffers the raw bytes, since YAML can't be parsed as a
+// stream, but the file is no longer held twice. There is no JSON node store,
+// so there is no serde_json::from_reader counterpart.
+fn decode_yaml_file(path: &Path) -> NodeStoreResult<Vec<NodeInfo>> {
+    let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    serde_yaml::from_reader(reader).map_err(|e| NodeStoreError::Serialization(e.to_string()))
+}
+
+// Nodes are written sorted by id, so saving the same nodes gives the same
+// file. YAML is the only file format: there is no JSON node store.
+fn encode_yaml(backend: &NodeStoreBackend) -> NodeStoreResult<String> {
+    serde_yaml::to_string(&backend.get_all())
+        .map_err(|e| NodeStoreError::Serialization(e.to_string()))
+}
+
+// Blocking counterpart of YamlNodeStore::write, used by the Batched flush task
+// and on drop. Writes only if a change is still pending.
+fn flush_pending(flush: &FlushState, backend: &NodeStoreBackend, path: &Path) -> NodeStoreResult<()> {
+    let _guard = flush.write_lock.lock().unwrap_or_else(|e| e.into_inner());
+    if !flush.pending.swap(false, Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let yaml = encode_yaml(backend)?;
+    let temp_path = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&temp_path)?;
+    std::io::Write::write_all(&mut file, yaml.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+impl Drop for YamlNodeStore {
+    fn drop(&mut self) {
+        if let Err(e) = flush_pending(&self.flush, &self.backend, &self.path) {
+            log::warn!("Failed to flush {} on drop: {}", self.path.display(), e);
+        }
+    }
+}
+
 #[async_trait]
 impl NodeStore for YamlNodeStore {
     async fn get_all(&self) -> NodeStoreResult<Vec<NodeInfo>> {
@@ -419,6 +737,10 @@ impl NodeStore for YamlNodeStore {
     async fn version(&self) -> NodeStoreResult<u64> {
         Ok(self.backend.version())
     }
+
+    async fn count(&self) -> NodeStoreResult<usize> {
+        Ok(self.backend.count())
+    }
     
     async fn set_if_version(&self, nodes: Vec<NodeInfo>, expected_version: u64) -> NodeStoreResult<()> {
         self.backend.set_if_version(nodes, expected_version)?;
@@ -456,12 +778,53 @@ impl DatabaseNodeStore {
     }
 }
 
+// Replace the servers table with `nodes` inside `tx`. Shared by set_all and
+// its dry run, which rolls the transaction back instead of committing.
+fn replace_servers(tx: &rusqlite::Transaction<'_>, nodes: &[NodeInfo]) -> NodeStoreResult<()> {
+    // Get current node IDs to identify which ones to delete
+    let current_ids: Vec<u64> = tx
+        .prepare("SELECT id FROM servers")
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| row.get::<_, u64>(0))
+                .and_then(|rows| rows.collect::<SqliteResult<Vec<_>>>())
+        })
+        .map_err(|e| NodeStoreError::Store(e.to_string()))?;
+
+    let new_ids: HashSet<u64> = nodes.iter().map(|n| n.id).collect();
+
+    // Delete nodes not in the new list
+    for id in current_ids {
+        if !new_ids.contains(&id) {
+            tx.execute("DELETE FROM servers WHERE id = ?", params![id])
+                .map_err(|e| NodeStoreError::Store(e.to_string()))?;
+        }
+    }
+
+    let mut stmt = tx
+        .prepare(
+            "INSERT INTO servers (id, address, role, updated_at)
+            VALUES (?1, ?2, ?3, strftime('%s', 'now'))
+            ON CONFLICT(id) DO UPDATE SET
+                address = excluded.address,
+                role = excluded.role,
+                updated_at = excluded.updated_at
+        ")
+        .map_err(|e| NodeStoreError::Store(e.to_string()))?;
+
+    for node in nodes {
+        stmt.execute(params![node.id, node.addr, node.role.value() as i64])
+            .map_err(|e| NodeStoreError::Store(e.to_string()))?;
+    }
+
+    Ok(())
+}
+
 #[async_trait]
 impl NodeStore for DatabaseNodeStore {
     async fn get_all(&self) -> NodeStoreResult<Vec<NodeInfo>> {
         let db = self.db.lock().await;
         let mut stmt = db
-            .prepare("SELECT id, address, role FROM servers")
+            .prepare("SELECT id, address, role FROM servers ORDER BY id")
             .map_err(|e| NodeStoreError::Store(e.to_string()))?;
 
         let nodes = stmt
@@ -473,10 +836,12 @@ impl NodeStore for DatabaseNodeStore {
                         0 => NodeRole::VOTER,
                         1 => NodeRole::STAND_BY,
                         2 => NodeRole::SPARE,
-                        _ => return Err(rusqlite::Error::InvalidColumnType(2, "role", rusqlite::types::Type::Integer)),
-                    }
+                        _ => return Err(rusqlite::Error::InvalidColumnType(2, "role".into(), rusqlite::types::Type::Integer)),
+                    },
+                    failure_domain: None,
                 })
-            })?
+            })
+            .map_err(|e| NodeStoreError::Store(e.to_string()))?
             .collect::<SqliteResult<Vec<_>>>()
             .map_err(|e| NodeStoreError::Store(e.to_string()))?;
 
@@ -498,11 +863,12 @@ impl NodeStore for DatabaseNodeStore {
                         0 => NodeRole::VOTER,
                         1 => NodeRole::STAND_BY,
                         2 => NodeRole::SPARE,
-                        _ => return Err(rusqlite::Error::InvalidColumnType(2, "role", rusqlite::types::Type::Integer)),
+                        _ => return Err(rusqlite::Error::InvalidColumnType(2, "role".into(), rusqlite::types::Type::Integer)),
                     },
+                    failure_domain: None,
                 })
             })
-            .map_err(|e| NodeStoreError::Store(e.to_string()))?
+            .map_err(|e| NodeStoreError::Store(e.to_string()))?;
 
         if let Some(node) = rows.next() {
             Ok(Some(node.map_err(|e| NodeStoreError::Store(e.to_string()))?))
@@ -525,8 +891,9 @@ impl NodeStore for DatabaseNodeStore {
                     0 => NodeRole::VOTER,
                     1 => NodeRole::STAND_BY,
                     2 => NodeRole::SPARE,
-                    _ => return Err(rusqlite::Error::InvalidColumnType(2, "role", rusqlite::types::Type::Integer)),
+                    _ => return Err(rusqlite::Error::InvalidColumnType(2, "role".into(), rusqlite::types::Type::Integer)),
                 },
+                failure_domain: None,
             })
         })
         .map_err(|e| NodeStoreError::Store(e.to_string()))?;
@@ -541,44 +908,9 @@ impl NodeStore for DatabaseNodeStore {
     async fn set_all(&self, nodes: Vec<NodeInfo>) -> NodeStoreResult<()> {
         validate_nodes(&nodes)?;
 
-        let db = self.db.lock().await;
+        let mut db = self.db.lock().await;
         let tx = db.transaction().map_err(|e| NodeStoreError::Store(e.to_string()))?;
-
-        // Get current node IDs to identify which ones to delete
-        let current_ids: Vec<u64> = tx
-            .prepare("SELECT id FROM servers")
-            .and_then(|mut stmt| {
-                stmt.query_map([], |row| Ok(row.get::<_, u64>(0)?))
-                    .and_then(|rows| rows.collect::<SqliteResult<Vec<_>>>())
-            })
-            .map_err(|e| NodeStoreError::Store(e.to_string()))?;
-
-        let new_ids: HashSet<u64> = nodes.iter().map(|n| n.id).collect();
-
-        // Delete nodes not in the new list
-        for id in current_ids {
-            if !new_ids.contains(&id) {
-                tx.execute("DELETE FROM servers WHERE id = ?", params![id])
-                    .map_err(|e| NodeStoreError::Store(e.to_string()))?;
-            }
-        }
-
-        let mut stmt = tx
-            .prepare(
-                "INSERT INTO servers (id, address, role, updated_at)
-                VALUES (?1, ?2, ?3, strftime('%s', 'now'))
-                ON CONFLICT(id) DO UPDATE SET
-                    address = excluded.address,
-                    role = excluded.role,
-                    updated_at = excluded.updated_at
-            ")
-            .map_err(|e| NodeStoreError::Store(e.to_string()))?;
-
-        for node in nodes {
-            stmt.execute(params![node.id, node.addr, node.role.value() as i64])
-                .map_err(|e| NodeStoreError::Store(e.to_string()))?;
-        }
-
+        replace_servers(&tx, &nodes)?;
         tx.commit().map_err(|e| NodeStoreError::Store(e.to_string()))?;
 
         let mut version = self.version.write().unwrap();
@@ -586,10 +918,22 @@ impl NodeStore for DatabaseNodeStore {
         Ok(())
     }
 
+    // Runs the full replace in a transaction that is rolled back, so the
+    // table's UNIQUE/PRIMARY KEY constraints are checked as well
+    async fn validate_set_all(&self, nodes: &[NodeInfo]) -> NodeStoreResult<()> {
+        validate_nodes(nodes)?;
+
+        let mut db = self.db.lock().await;
+        let tx = db.transaction().map_err(|e| NodeStoreError::Store(e.to_string()))?;
+        let result = replace_servers(&tx, nodes);
+        tx.rollback().map_err(|e| NodeStoreError::Store(e.to_string()))?;
+        result
+    }
+
     async fn upsert(&self, node: NodeInfo) -> NodeStoreResult<()> {
-        validate_nodes(&[node.clone()])?;
+        validate_nodes(std::slice::from_ref(&node))?;
 
-        let db = self.db.lock().await;
+        let mut db = self.db.lock().await;
         let tx = db.transaction().map_err(|e| NodeStoreError::Store(e.to_string()))?;
 
         let mut stmt = tx
@@ -607,6 +951,7 @@ impl NodeStore for DatabaseNodeStore {
         
         stmt.execute(params![node.id, node.addr, node.role.value() as i64])
             .map_err(|e| NodeStoreError::Store(e.to_string()))?;
+        drop(stmt);
 
         tx.commit().map_err(|e| NodeStoreError::Store(e.to_string()))?;
 
@@ -616,7 +961,7 @@ impl NodeStore for DatabaseNodeStore {
     }
 
     async fn remove(&self, id: NodeId) -> NodeStoreResult<bool> {
-        let db = self.db.lock().await;
+        let mut db = self.db.lock().await;
         let tx = db.transaction().map_err(|e| NodeStoreError::Store(e.to_string()))?;
 
         let mut stmt = tx
@@ -625,7 +970,8 @@ impl NodeStore for DatabaseNodeStore {
 
         let result = stmt.execute(params![id])
             .map_err(|e| NodeStoreError::Store(e.to_string()))?;
-        
+        drop(stmt);
+
         tx.commit().map_err(|e| NodeStoreError::Store(e.to_string()))?;
 
         let mut version = self.version.write().unwrap();
@@ -638,6 +984,14 @@ impl NodeStore for DatabaseNodeStore {
         Ok(*version)
     }
 
+    async fn count(&self) -> NodeStoreResult<usize> {
+        let db = self.db.lock().await;
+        let count: i64 = db
+            .query_row("SELECT COUNT(*) FROM servers", [], |row| row.get(0))
+            .map_err(|e| NodeStoreError::Store(e.to_string()))?;
+        Ok(count as usize)
+    }
+
     async fn set_if_version(&self, nodes: Vec<NodeInfo>, expected_version: NodeVersion) -> NodeStoreResult<()> {
         let current_version = {
             let version = self.version.read().unwrap();
@@ -696,6 +1050,10 @@ impl<S: NodeStore + Send + Sync> NodeStore for ObservableNodeStore<S> {
         self.notify(nodes).await;
         Ok(())
     }
+
+    async fn validate_set_all(&self, nodes: &[NodeInfo]) -> NodeStoreResult<()> {
+        self.store.validate_set_all(nodes).await
+    }
     
     async fn upsert(&self, node: NodeInfo) -> NodeStoreResult<()> {
         self.store.upsert(node.clone()).await?;
@@ -716,10 +1074,288 @@ impl<S: NodeStore + Send + Sync> NodeStore for ObservableNodeStore<S> {
     async fn version(&self) -> NodeStoreResult<NodeVersion> {
         self.store.version().await
     }
+
+    async fn count(&self) -> NodeStoreResult<usize> {
+        self.store.count().await
+    }
     
     async fn set_if_version(&self, nodes: Vec<NodeInfo>, version: NodeVersion) -> NodeStoreResult<()> {
         self.store.set_if_version(nodes.clone(), version).await?;
         self.notify(nodes).await;
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditOperation {
+    SetAll,
+    Upsert,
+    Remove,
+    SetIfVersion,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub timestamp: SystemTime,
+    pub operation: AuditOperation,
+    pub node_id: NodeId,
+    pub old_role: Option<NodeRole>,
+    pub new_role: Option<NodeRole>,
+}
+
+/// Destination for audit records, e.g. an append-only file or a channel.
+#[async_trait]
+pub trait AuditSink: Send + Sync {
+    async fn append(&self, record: AuditRecord) -> NodeStoreResult<()>;
+}
+
+#[async_trait]
+impl AuditSink for mpsc::Sender<AuditRecord> {
+    async fn append(&self, record: AuditRecord) -> NodeStoreResult<()> {
+        self.send(record)
+            .await
+            .map_err(|_| NodeStoreError::Store("Audit channel closed".to_string()))
+    }
+}
+
+// Records every membership mutation to a sink before handing it to the inner
+// store. Input the inner store would reject is validated first and leaves no
+// record, but a mutation that fails later, e.g. on I/O, keeps its record.
+pub struct AuditingNodeStore<S: NodeStore + Send + Sync, A: AuditSink> {
+    store: S,
+    sink: A,
+    // When set, a failure to append is returned to the caller and the
+    // mutation isn't applied, so nothing changes without a record
+    strict: bool,
+    // Held from reading the old state until the mutation is recorded, so
+    // the records of concurrent mutations describe what each one changed
+    write_lock: Mutex<()>,
+}
+
+impl<S: NodeStore + Send + Sync, A: AuditSink> AuditingNodeStore<S, A> {
+    pub fn new(store: S, sink: A) -> Self {
+        Self {
+            store,
+            sink,
+            strict: true,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    async fn record(&self, records: Vec<AuditRecord>) -> NodeStoreResult<()> {
+        for record in records {
+            if let Err(err) = self.sink.append(record).await {
+                if self.strict {
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // One record per node affected by replacing the current nodes with
+    // `nodes`. Callers hold write_lock.
+    async fn records_for_replace(&self, operation: AuditOperation, nodes: &[NodeInfo]) -> NodeStoreResult<Vec<AuditRecord>> {
+        let current = self.store.get_all().await?;
+        let timestamp = SystemTime::now();
+
+        let records = diff_nodes(&current, nodes)
+            .changes
+            .into_iter()
+            .map(|change| {
+                let (node_id, old_role, new_role) = match change {
+                    NodeChange::Added(node) => (node.id, None, Some(node.role)),
+                    NodeChange::Removed(node) => (node.id, Some(node.role), None),
+                    NodeChange::RoleChanged { id, old, new } => (id, Some(old), Some(new)),
+                    NodeChange::AddressChanged { id, .. } | NodeChange::FailureDomainChanged { id, .. } => {
+                        let role = nodes.iter().find(|n| n.id == id).map(|n| n.role);
+                        (id, role, role)
+                    }
+                };
+                AuditRecord {
+                    timestamp,
+                    operation,
+                    node_id,
+                    old_role,
+                    new_role,
+                }
+            })
+            .collect();
+        Ok(records)
+    }
+}
+
+#[async_trait]
+impl<S: NodeStore + Send + Sync, A: AuditSink> NodeStore for AuditingNodeStore<S, A> {
+    async fn get_all(&self) -> NodeStoreResult<Vec<NodeInfo>> {
+        self.store.get_all().await
+    }
+
+    async fn get_by_id(&self, id: NodeId) -> NodeStoreResult<Option<NodeInfo>> {
+        self.store.get_by_id(id).await
+    }
+
+    async fn get_by_address(&self, address: &str) -> NodeStoreResult<Option<NodeInfo>> {
+        self.store.get_by_address(address).await
+    }
+
+    async fn set_all(&self, nodes: Vec<NodeInfo>) -> NodeStoreResult<()> {
+        let _guard = self.write_lock.lock().await;
+        self.store.validate_set_all(&nodes).await?;
+        let records = self.records_for_replace(AuditOperation::SetAll, &nodes).await?;
+        self.record(records).await?;
+        self.store.set_all(nodes).await
+    }
+
+    async fn validate_set_all(&self, nodes: &[NodeInfo]) -> NodeStoreResult<()> {
+        self.store.validate_set_all(nodes).await
+    }
+
+    async fn upsert(&self, node: NodeInfo) -> NodeStoreResult<()> {
+        let _guard = self.write_lock.lock().await;
+        // Dry run of the resulting membership
+        let mut nodes = self.store.get_all().await?;
+        let old_role = nodes.iter().find(|n| n.same_node(&node)).map(|n| n.role);
+        nodes.retain(|n| !n.same_node(&node));
+        nodes.push(node.clone());
+        self.store.validate_set_all(&nodes).await?;
+
+        let record = AuditRecord {
+            timestamp: SystemTime::now(),
+            operation: AuditOperation::Upsert,
+            node_id: node.id,
+            old_role,
+            new_role: Some(node.role),
+        };
+        self.record(vec![record]).await?;
+        self.store.upsert(node).await
+    }
+
+    async fn remove(&self, id: NodeId) -> NodeStoreResult<bool> {
+        let _guard = self.write_lock.lock().await;
+        // Nothing to remove, so nothing to record
+        let Some(old) = self.store.get_by_id(id).await? else {
+            return self.store.remove(id).await;
+        };
+        let record = AuditRecord {
+            timestamp: SystemTime::now(),
+            operation: AuditOperation::Remove,
+            node_id: id,
+            old_role: Some(old.role),
+            new_role: None,
+        };
+        self.record(vec![record]).await?;
+        self.store.remove(id).await
+    }
+
+    async fn version(&self) -> NodeStoreResult<NodeVersion> {
+        self.store.version().await
+    }
+
+    async fn count(&self) -> NodeStoreResult<usize> {
+        self.store.count().await
+    }
+
+    async fn set_if_version(&self, nodes: Vec<NodeInfo>, version: NodeVersion) -> NodeStoreResult<()> {
+        let _guard = self.write_lock.lock().await;
+        let records = self.records_for_replace(AuditOperation::SetIfVersion, &nodes).await?;
+        self.store.set_if_version(nodes, version).await?;
+        self.record(records).await
+    }
+}
+
+// Caches reads of a slower store (e.g. DatabaseNodeStore) for `ttl`. Any
+// mutation through the wrapper drops the cache; changes made to the inner
+// store behind its back show up once the TTL runs out.
+pub struct CachedNodeStore<S: NodeStore + Send + Sync> {
+    store: S,
+    ttl: Duration,
+    // The node list and when it was fetched. The lock is held across the
+    // backend call, so concurrent misses wait for one fetch instead of each
+    // making their own.
+    cache: Mutex<Option<(Instant, Vec<NodeInfo>)>>,
+}
+
+impl<S: NodeStore + Send + Sync> CachedNodeStore<S> {
+    pub fn new(store: S, ttl: Duration) -> Self {
+        Self {
+            store,
+            ttl,
+            cache: Mutex::new(None),
+        }
+    }
+
+    async fn cached_nodes(&self) -> NodeStoreResult<Vec<NodeInfo>> {
+        let mut cache = self.cache.lock().await;
+        if let Some((fetched_at, nodes)) = cache.as_ref() {
+            if fetched_at.elapsed() < self.ttl {
+                return Ok(nodes.clone());
+            }
+        }
+
+        let nodes = self.store.get_all().await?;
+        *cache = Some((Instant::now(), nodes.clone()));
+        Ok(nodes)
+    }
+
+    // Called after the inner store was written, so a fetch racing with the
+    // write can't leave its stale result behind
+    async fn invalidate(&self) {
+        *self.cache.lock().await = None;
+    }
+}
+
+#[async_trait]
+impl<S: NodeStore + Send + Sync> NodeStore for CachedNodeStore<S> {
+    async fn get_all(&self) -> NodeStoreResult<Vec<NodeInfo>> {
+        self.cached_nodes().await
+    }
+
+    async fn get_by_id(&self, id: NodeId) -> NodeStoreResult<Option<NodeInfo>> {
+        Ok(self.cached_nodes().await?.into_iter().find(|node| node.id == id))
+    }
+
+    async fn get_by_address(&self, address: &str) -> NodeStoreResult<Option<NodeInfo>> {
+        Ok(self.cached_nodes().await?.into_iter().find(|node| node.addr == address))
+    }
+
+    async fn set_all(&self, nodes: Vec<NodeInfo>) -> NodeStoreResult<()> {
+        let result = self.store.set_all(nodes).await;
+        self.invalidate().await;
+        result
+    }
+
+    async fn validate_set_all(&self, nodes: &[NodeInfo]) -> NodeStoreResult<()> {
+        self.store.validate_set_all(nodes).await
+    }
+
+    async fn upsert(&self, node: NodeInfo) -> NodeStoreResult<()> {
+        let result = self.store.upsert(node).await;
+        self.invalidate().await;
+        result
+    }
+
+    async fn remove(&self, id: NodeId) -> NodeStoreResult<bool> {
+        let result = self.store.remove(id).await;
+        self.invalidate().await;
+        result
+    }
+
+    async fn version(&self) -> NodeStoreResult<NodeVersion> {
+        self.store.version().await
+    }
+
+    async fn count(&self) -> NodeStoreResult<usize> {
+        Ok(self.cached_nodes().await?.len())
+    }
+
+    async fn set_if_version(&self, nodes: Vec<NodeInfo>, version: NodeVersion) -> NodeStoreResult<()> {
+        let result = self.store.set_if_version(nodes, version).await;
+        self.invalidate().await;
+        result
+    }
+}
@@ -0,0 +1,92 @@
+// Little-endian primitives for the dqlite wire format.
+//
+// Every integer dqlite puts on the wire (header words, tuple values, result
+// fields, row headers) is little-endian. All encoders and decoders go through
+// these helpers so byte order is decided in exactly one place.
+//
+// The get_* integer functions read from the start of `b` and expect the
+// caller to have checked its length already.
+
+use std::io;
+
+pub(crate) const WORD_SIZE: usize = 8;
+
+// Bytes needed to bring `len` up to the next word boundary
+pub(crate) fn padding(len: usize) -> usize {
+    (WORD_SIZE - len % WORD_SIZE) % WORD_SIZE
+}
+
+// Zero-fill `buf` up to the next word boundary
+pub(crate) fn put_padding(buf: &mut Vec<u8>) {
+    let n = padding(buf.len());
+    buf.resize(buf.len() + n, 0);
+}
+
+pub(crate) fn put_u16_le(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+pub(crate) fn put_u32_le(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+pub(crate) fn put_u64_le(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+pub(crate) fn put_i64_le(buf: &mut Vec<u8>, v: i64) {
+    put_u64_le(buf, v as u64);
+}
+
+pub(crate) fn put_f64_le(buf: &mut Vec<u8>, v: f64) {
+    put_u64_le(buf, v.to_bits());
+}
+
+pub(crate) fn get_u16_le(b: &[u8]) -> u16 {
+    u16::from_le_bytes([b[0], b[1]])
+}
+
+pub(crate) fn get_u32_le(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+pub(crate) fn get_u64_le(b: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&b[..8]);
+    u64::from_le_bytes(buf)
+}
+
+pub(crate) fn get_i64_le(b: &[u8]) -> i64 {
+    get_u64_le(b) as i64
+}
+
+pub(crate) fn get_f64_le(b: &[u8]) -> f64 {
+    f64::from_bits(get_u64_le(b))
+}
+
+// Text is NUL terminated and padded to a word boundary
+pub(crate) fn put_text(buf: &mut Vec<u8>, v: &str) {
+    buf.extend_from_slice(v.as_bytes());
+    buf.push(0);
+    put_padding(buf);
+}
+
+// Decode a text field starting at a word boundary. Returns the text and the
+// number of bytes it occupied, including terminator and padding.
+pub(crate) fn get_text(b: &[u8]) -> io::Result<(String, usize)> {
+    let len = b
+        .iter()
+        .position(|&c| c == 0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unterminated text field"))?;
+    let text = std::str::from_utf8(&b[..len])
+        .map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Invalid UTF-8 in text field: {}", e))
+        })?
+        .to_string();
+
+    let consumed = len + 1 + padding(len + 1);
+    if consumed > b.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Short padding after text field"));
+    }
+    Ok((text, consumed))
+}
@@ -0,0 +1,127 @@
+//! Throwaway in-process clusters for tests.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! use dqlite_rs::testkit::TestCluster;
+//!
+//! let cluster = TestCluster::single().await?;
+//! let db = cluster.connector().open("test").await?;
+//! db.execute("CREATE TABLE t (n INTEGER)", &[]).await?;
+//! cluster.shutdown()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::bindings::server::{DqliteError, Node};
+use crate::protocol::config::Config;
+use crate::protocol::connector::Connector;
+use crate::protocol::store::{InMemoryNodeStore, NodeInfo, NodeRole, NodeStore, ObservableNodeStore};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// dqlite bootstraps a fresh single-node cluster on start only for this id
+const BOOTSTRAP_ID: u64 = 1;
+
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Distinguishes the data directories of clusters created by one process
+static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+
+pub struct TestCluster {
+    node: Node,
+    connector: Arc<Connector<InMemoryNodeStore>>,
+    address: String,
+    dir: PathBuf,
+}
+
+impl TestCluster {
+    /// Start a bootstrapped one-node cluster on an ephemeral loopback port,
+    /// with its data in a fresh temporary directory, and wait until it
+    /// answers. Needs a tokio runtime.
+    pub async fn single() -> Result<Self, DqliteError> {
+        let dir = std::env::temp_dir().join(format!(
+            "dqlite-testkit-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&dir)?;
+        let dir_str = dir.to_str().ok_or_else(|| {
+            DqliteError::Configuration(format!("Temporary directory is not valid UTF-8: {}", dir.display()))
+        })?;
+
+        // Let the OS pick a free port. Another process could take it before
+        // the node binds it; that is rare enough for tests.
+        let address = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.to_string();
+
+        let node = Node::new(BOOTSTRAP_ID, &address, dir_str)?;
+        node.set_bind_address(&address)?;
+        node.start()?;
+
+        let store = InMemoryNodeStore::new();
+        store
+            .set_all(vec![NodeInfo {
+                id: BOOTSTRAP_ID,
+                addr: address.clone(),
+                role: NodeRole::VOTER,
+                failure_domain: None,
+            }])
+            .await
+            .map_err(|e| DqliteError::Configuration(e.to_string()))?;
+        let connector = Connector::builder()
+            .store(Arc::new(ObservableNodeStore::new(store)))
+            .config(Config::new().with_retry_limit(0))
+            .build()
+            .map_err(|e| DqliteError::Configuration(e.to_string()))?;
+
+        let cluster = Self {
+            node,
+            connector: Arc::new(connector),
+            address,
+            dir,
+        };
+        cluster.wait_ready().await?;
+        Ok(cluster)
+    }
+
+    // Poll until the node accepts a client and reports itself as leader
+    async fn wait_ready(&self) -> Result<(), DqliteError> {
+        let deadline = Instant::now() + READY_TIMEOUT;
+        loop {
+            let err = match self.connector.connect().await {
+                Ok(_) => return Ok(()),
+                Err(e) => e,
+            };
+            if Instant::now() >= deadline {
+                return Err(DqliteError::Timeout(format!(
+                    "node at {} not ready after {:?}: {}",
+                    self.address, READY_TIMEOUT, err
+                )));
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    pub fn connector(&self) -> &Arc<Connector<InMemoryNodeStore>> {
+        &self.connector
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    // Stop the node, unless the test already did, and remove its data directory
+    pub fn shutdown(self) -> Result<(), DqliteError> {
+        if self.node.is_running() {
+            self.node.stop()?;
+        }
+        drop(self.node);
+        std::fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+}
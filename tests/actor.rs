@@ -0,0 +1,95 @@
+mod common;
+
+use common::*;
+use dqlite_rs::protocol::actor::ProtocolActor;
+use dqlite_rs::protocol::connector::Conn;
+use dqlite_rs::protocol::message::*;
+use dqlite_rs::protocol::protocol::Protocol;
+use std::time::Duration;
+
+// A protocol whose server end is handed to the test
+async fn pair() -> (Protocol, Conn) {
+    let (client, mut server) = Conn::from_unix_pair().unwrap();
+    let mut proto = Protocol::new(client, "mock");
+    proto.handshake().await.unwrap();
+    read_version(&mut server).await.unwrap();
+    (proto, server)
+}
+
+// The number a "SELECT <n>" query asks for
+fn selected(request: &mut Message) -> i64 {
+    request.get_u64().unwrap();
+    let sql = request.get_text().unwrap();
+    sql.strip_prefix("SELECT ").unwrap().parse().unwrap()
+}
+
+#[tokio::test]
+async fn fifty_concurrent_queries_are_pipelined_on_one_connection() {
+    let (proto, mut server) = pair().await;
+    // Nothing is answered until all 50 requests are on the wire, which only
+    // happens if the actor keeps writing while earlier ones are unanswered
+    let server = tokio::spawn(async move {
+        let mut answers = Vec::new();
+        for _ in 0..50 {
+            let mut request = read_request(&mut server).await.unwrap().unwrap();
+            assert_eq!(request.mtype, REQUEST_QUERY_SQL);
+            let n = selected(&mut request);
+            // Long results arrive in two batches
+            if n % 2 == 0 {
+                answers.push(rows(&["n"], &[vec![Value::Integer(n)]], true));
+                answers.push(rows(&["n"], &[vec![Value::Integer(-n)]], false));
+            } else {
+                answers.push(rows(&["n"], &[vec![Value::Integer(n)]], false));
+            }
+        }
+        write_messages(&mut server, &answers).await.unwrap();
+        server
+    });
+
+    let handle = ProtocolActor::spawn(proto);
+    let queries: Vec<_> = (0..50)
+        .map(|n| {
+            let handle = handle.clone();
+            tokio::spawn(async move { (n, handle.query_sql(0, &format!("SELECT {}", n), &[]).await) })
+        })
+        .collect();
+
+    let all = async {
+        for query in queries {
+            let (n, rows) = query.await.unwrap();
+            let values: Vec<_> = rows.unwrap().iter().map(|row| row.values().to_vec()).collect();
+            if n % 2 == 0 {
+                assert_eq!(values, [vec![Value::Integer(n)], vec![Value::Integer(-n)]]);
+            } else {
+                assert_eq!(values, [vec![Value::Integer(n)]]);
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), all).await.expect("queries were not pipelined");
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn a_broken_connection_fails_every_pending_request() {
+    let (proto, mut server) = pair().await;
+    let server = tokio::spawn(async move {
+        let mut request = read_request(&mut server).await.unwrap().unwrap();
+        write_messages(&mut server, &[result(selected(&mut request) as u64, 1)]).await.unwrap();
+        for _ in 0..2 {
+            read_request(&mut server).await.unwrap().unwrap();
+        }
+        // Hang up with two requests unanswered
+    });
+
+    let handle = ProtocolActor::spawn(proto);
+    let first = handle.clone();
+    let first = tokio::spawn(async move { first.exec_sql(0, "SELECT 7", &[]).await });
+    assert_eq!(first.await.unwrap().unwrap().last_insert_id, 7);
+
+    let (second, third) = tokio::join!(handle.exec_sql(0, "SELECT 8", &[]), handle.query_sql(0, "SELECT 9", &[]));
+    assert!(second.is_err());
+    assert!(third.is_err());
+    server.await.unwrap();
+    // The connection stays unusable
+    assert!(handle.exec_sql(0, "SELECT 10", &[]).await.is_err());
+}
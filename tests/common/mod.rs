@@ -0,0 +1,389 @@
+// In-process stand-in for dqlite nodes, speaking just enough of the wire
+// protocol to drive the client against canned responses.
+//
+// `serve` answers a single connection; `MockCluster` hands out a DialFunc
+// whose every dial gets a fresh socket pair served as the node at that
+// address, so a Connector can be pointed at it without any networking.
+
+#![allow(dead_code)]
+
+use dqlite_rs::protocol::config::Config;
+use dqlite_rs::protocol::connector::{Conn, Connector, DialFunc};
+use dqlite_rs::protocol::message::*;
+use dqlite_rs::protocol::protocol::Protocol;
+use dqlite_rs::protocol::store::{InMemoryNodeStore, NodeInfo, NodeRole, NodeStore, ObservableNodeStore};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task::JoinHandle;
+
+// Addresses for MockCluster nodes; nothing listens on them
+pub const N1: &str = "10.0.0.1:9001";
+pub const N2: &str = "10.0.0.2:9001";
+pub const N3: &str = "10.0.0.3:9001";
+
+pub const SQLITE_ERROR: u64 = 1;
+pub const SQLITE_IOERR_NOT_LEADER: u64 = 10 | (40 << 8);
+
+// Responses
+
+pub fn node(id: u64, addr: &str) -> Message {
+    let mut m = Message::new(RESPONSE_NODE);
+    m.put_u64(id);
+    m.put_text(addr);
+    m
+}
+
+pub fn legacy_node(addr: &str) -> Message {
+    let mut m = Message::new(RESPONSE_NODE);
+    m.put_text(addr);
+    m
+}
+
+pub fn welcome(heartbeat_ms: u64) -> Message {
+    let mut m = Message::new(RESPONSE_WELCOME);
+    m.put_u64(heartbeat_ms);
+    m
+}
+
+// Server list as sent in reply to a heartbeat: id and address only
+pub fn servers(nodes: &[(u64, &str)]) -> Message {
+    let mut m = Message::new(RESPONSE_NODES);
+    m.put_u64(nodes.len() as u64);
+    for (id, addr) in nodes {
+        m.put_u64(*id);
+        m.put_text(addr);
+    }
+    m
+}
+
+// Server list as sent in reply to REQUEST_CLUSTER format 1, with roles
+pub fn cluster(nodes: &[(u64, &str, u8)]) -> Message {
+    let mut m = Message::new(RESPONSE_NODES);
+    m.put_u64(nodes.len() as u64);
+    for (id, addr, role) in nodes {
+        m.put_u64(*id);
+        m.put_text(addr);
+        m.put_u64(*role as u64);
+    }
+    m
+}
+
+pub fn db(id: u32) -> Message {
+    let mut m = Message::new(RESPONSE_DB);
+    m.put_u32(id);
+    m.put_u32(0);
+    m
+}
+
+pub fn stmt(db_id: u32, stmt_id: u32, params: u64) -> Message {
+    let mut m = Message::new(RESPONSE_STMT);
+    m.put_u32(db_id);
+    m.put_u32(stmt_id);
+    m.put_u64(params);
+    m
+}
+
+pub fn result(last_insert_id: u64, rows_affected: u64) -> Message {
+    let mut m = Message::new(RESPONSE_RESULT);
+    m.put_u64(last_insert_id);
+    m.put_u64(rows_affected);
+    m
+}
+
+pub fn empty() -> Message {
+    let mut m = Message::new(RESPONSE_EMPTY);
+    m.put_u64(0);
+    m
+}
+
+pub fn failure(code: u64, message: &str) -> Message {
+    let mut m = Message::new(RESPONSE_FAILURE);
+    m.put_u64(code);
+    m.put_text(message);
+    m
+}
+
+pub fn metadata(failure_domain: u64, weight: u64) -> Message {
+    let mut m = Message::new(RESPONSE_METADATA);
+    m.put_u64(failure_domain);
+    m.put_u64(weight);
+    m
+}
+
+// One RESPONSE_ROWS batch, ending with the PART marker when `more` is set
+pub fn rows(columns: &[&str], values: &[Vec<Value>], more: bool) -> Message {
+    let mut m = Message::new(RESPONSE_ROWS);
+    m.put_u64(columns.len() as u64);
+    for column in columns {
+        m.put_text(column);
+    }
+    let header_size = (columns.len() * 4).div_ceil(64) * WORD_SIZE;
+    for row in values {
+        let mut header = vec![0u8; header_size];
+        for (i, value) in row.iter().enumerate() {
+            header[i / 2] |= value.type_code() << ((i % 2) * 4);
+        }
+        for byte in header {
+            m.put_u8(byte);
+        }
+        for value in row {
+            m.put_value(value);
+        }
+    }
+    m.put_u64(if more { ROWS_PART } else { ROWS_DONE });
+    m
+}
+
+// Framing
+
+pub async fn read_version(conn: &mut Conn) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    conn.read_exact(&mut buf).await?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+// Next request frame, or None once the client has hung up
+pub async fn read_request(conn: &mut Conn) -> io::Result<Option<Message>> {
+    let mut header = [0u8; HEADER_SIZE];
+    match conn.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut body = vec![0u8; Message::header_words(&header) as usize * WORD_SIZE];
+    conn.read_exact(&mut body).await?;
+    Ok(Some(Message::from_parts(header, body)))
+}
+
+pub async fn write_messages(conn: &mut Conn, messages: &[Message]) -> io::Result<()> {
+    let mut out = Vec::new();
+    for message in messages {
+        message.encode_into(&mut out);
+    }
+    conn.write_all(&out).await?;
+    conn.flush().await
+}
+
+// The next request if one arrives within `wait`. Nothing is lost on a
+// timeout as long as the client hadn't started writing a request.
+pub async fn request_within(conn: &mut Conn, wait: Duration) -> Option<Message> {
+    tokio::time::timeout(wait, read_request(conn)).await.ok()?.ok()?
+}
+
+/// Serve one connection: read the version, then answer each request with
+/// whatever `handler` returns; an empty answer hangs up. Ends when either
+/// side hangs up, returning the version the client sent and the type of
+/// every request seen.
+pub fn serve<F>(mut conn: Conn, mut handler: F) -> JoinHandle<(u64, Vec<u8>)>
+where
+    F: FnMut(&mut Message) -> Vec<Message> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let version = read_version(&mut conn).await.unwrap_or(0);
+        let mut seen = Vec::new();
+        while let Ok(Some(mut request)) = read_request(&mut conn).await {
+            seen.push(request.mtype);
+            let responses = handler(&mut request);
+            if responses.is_empty() || write_messages(&mut conn, &responses).await.is_err() {
+                break;
+            }
+        }
+        (version, seen)
+    })
+}
+
+/// A protocol over a socket pair whose far end is served by `handler`,
+/// already past the handshake
+pub async fn connected<F>(handler: F) -> (Protocol, JoinHandle<(u64, Vec<u8>)>)
+where
+    F: FnMut(&mut Message) -> Vec<Message> + Send + 'static,
+{
+    let (client, server) = Conn::from_unix_pair().unwrap();
+    let task = serve(server, handler);
+    let mut proto = Protocol::new(client, "mock");
+    proto.handshake().await.unwrap();
+    (proto, task)
+}
+
+pub type Handler = Arc<dyn Fn(&str, &mut Message) -> Option<Vec<Message>> + Send + Sync>;
+
+#[derive(Default)]
+struct ClusterState {
+    // id of every node, by address
+    ids: HashMap<String, u64>,
+    leader: Option<String>,
+    down: HashSet<String>,
+    legacy: HashSet<String>,
+    delays: HashMap<String, Duration>,
+    dials: Vec<String>,
+    // (address, request type) of every request served
+    requests: Vec<(String, u8)>,
+}
+
+/// A set of fake nodes reachable through [`MockCluster::dial_func`]. Nodes
+/// answer LEADER with the current leader, CLIENT with a welcome and OPEN
+/// with database 0; anything else goes to the handler set with
+/// [`MockCluster::on_request`], and fails with SQLITE_ERROR if it has no
+/// answer. A handler answering with no messages makes the node hang up.
+#[derive(Clone)]
+pub struct MockCluster {
+    state: Arc<Mutex<ClusterState>>,
+    handler: Arc<Mutex<Option<Handler>>>,
+}
+
+impl MockCluster {
+    pub fn new(nodes: &[(u64, &str)]) -> Self {
+        let mut state = ClusterState::default();
+        for (id, addr) in nodes {
+            state.ids.insert(addr.to_string(), *id);
+        }
+        state.leader = nodes.first().map(|(_, addr)| addr.to_string());
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            handler: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn set_leader(&self, addr: Option<&str>) {
+        self.state.lock().unwrap().leader = addr.map(str::to_string);
+    }
+
+    // Dials of `addr` fail while it is down
+    pub fn set_down(&self, addr: &str, down: bool) {
+        let mut state = self.state.lock().unwrap();
+        if down {
+            state.down.insert(addr.to_string());
+        } else {
+            state.down.remove(addr);
+        }
+    }
+
+    // The node at `addr` only speaks the legacy protocol: it hangs up on a
+    // client that sends VERSION_ONE, and sends legacy leader replies
+    pub fn set_legacy(&self, addr: &str) {
+        self.state.lock().unwrap().legacy.insert(addr.to_string());
+    }
+
+    // Wait `delay` before each response from `addr`
+    pub fn set_delay(&self, addr: &str, delay: Duration) {
+        self.state.lock().unwrap().delays.insert(addr.to_string(), delay);
+    }
+
+    pub fn on_request<F>(&self, handler: F)
+    where
+        F: Fn(&str, &mut Message) -> Option<Vec<Message>> + Send + Sync + 'static,
+    {
+        *self.handler.lock().unwrap() = Some(Arc::new(handler));
+    }
+
+    pub fn dials(&self) -> Vec<String> {
+        self.state.lock().unwrap().dials.clone()
+    }
+
+    // Requests of type `mtype` served by `addr`
+    pub fn count(&self, addr: &str, mtype: u8) -> usize {
+        let state = self.state.lock().unwrap();
+        state.requests.iter().filter(|(a, t)| a == addr && *t == mtype).count()
+    }
+
+    // Every node, as a store would list them
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        let state = self.state.lock().unwrap();
+        let mut nodes: Vec<NodeInfo> = state
+            .ids
+            .iter()
+            .map(|(addr, id)| NodeInfo { id: *id, addr: addr.clone(), role: NodeRole::VOTER, failure_domain: None })
+            .collect();
+        nodes.sort_by_key(|node| node.id);
+        nodes
+    }
+
+    // A store listing every node of the cluster
+    pub async fn store(&self) -> Arc<ObservableNodeStore<InMemoryNodeStore>> {
+        let store = InMemoryNodeStore::new();
+        store.set_all(self.nodes()).await.unwrap();
+        Arc::new(ObservableNodeStore::new(store))
+    }
+
+    // A connector dialing this cluster, with `config` otherwise unchanged
+    pub async fn connector(&self, config: Config) -> Connector<InMemoryNodeStore> {
+        Connector::builder()
+            .store(self.store().await)
+            .config(config.with_dial(self.dial_func()))
+            .build()
+            .unwrap()
+    }
+
+    pub fn dial_func(&self) -> DialFunc {
+        let cluster = self.clone();
+        Arc::new(move |addr: &str| {
+            let cluster = cluster.clone();
+            let addr = addr.to_string();
+            Box::pin(async move { cluster.dial(&addr) })
+        })
+    }
+
+    fn dial(&self, addr: &str) -> Result<Conn, String> {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.dials.push(addr.to_string());
+            if state.down.contains(addr) || !state.ids.contains_key(addr) {
+                return Err(format!("connection refused: {}", addr));
+            }
+        }
+        let (client, server) = Conn::from_unix_pair().map_err(|e| e.to_string())?;
+        tokio::spawn(self.clone().serve_node(addr.to_string(), server));
+        Ok(client)
+    }
+
+    async fn serve_node(self, addr: String, mut conn: Conn) {
+        let Ok(version) = read_version(&mut conn).await else {
+            return;
+        };
+        let legacy = self.state.lock().unwrap().legacy.contains(&addr);
+        if legacy && version != VERSION_LEGACY {
+            return;
+        }
+
+        while let Ok(Some(mut request)) = read_request(&mut conn).await {
+            let delay = {
+                let mut state = self.state.lock().unwrap();
+                state.requests.push((addr.clone(), request.mtype));
+                state.delays.get(&addr).copied()
+            };
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            let responses = self.respond(&addr, legacy, &mut request);
+            if responses.is_empty() || write_messages(&mut conn, &responses).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn respond(&self, addr: &str, legacy: bool, request: &mut Message) -> Vec<Message> {
+        let handler = self.handler.lock().unwrap().clone();
+        if let Some(responses) = handler.and_then(|h| h(addr, request)) {
+            return responses;
+        }
+
+        let state = self.state.lock().unwrap();
+        match request.mtype {
+            REQUEST_LEADER => {
+                let leader = state.leader.clone().unwrap_or_default();
+                let id = state.ids.get(&leader).copied().unwrap_or(0);
+                if legacy {
+                    vec![legacy_node(&leader)]
+                } else {
+                    vec![node(id, &leader)]
+                }
+            }
+            REQUEST_CLIENT => vec![welcome(15_000)],
+            REQUEST_OPEN => vec![db(0)],
+            other => vec![failure(SQLITE_ERROR, &format!("unexpected request {}", other))],
+        }
+    }
+}
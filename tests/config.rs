@@ -0,0 +1,58 @@
+use dqlite_rs::protocol::config::{Config, ConfigError};
+use std::time::Duration;
+
+const VARS: [&str; 6] = [
+    "DQLITE_DIAL_TIMEOUT",
+    "DQLITE_ATTEMPT_TIMEOUT",
+    "DQLITE_BACKOFF_FACTOR_MS",
+    "DQLITE_BACKOFF_CAP_MS",
+    "DQLITE_RETRY_LIMIT",
+    "DQLITE_CONCURRENT_LEADER_CONNS",
+];
+
+fn set_env(vars: &[(&str, &str)]) {
+    for name in VARS {
+        std::env::remove_var(name);
+    }
+    for (name, value) in vars {
+        std::env::set_var(name, value);
+    }
+}
+
+// The environment is process-wide, so every case runs in this one test
+#[test]
+fn from_env_parses_set_vars_and_reports_every_bad_one() {
+    set_env(&[
+        ("DQLITE_DIAL_TIMEOUT", "500ms"),
+        ("DQLITE_ATTEMPT_TIMEOUT", "2m"),
+        ("DQLITE_RETRY_LIMIT", "3"),
+    ]);
+    let config = Config::from_env().unwrap();
+    assert_eq!(config.dial_timeout, Duration::from_millis(500));
+    assert_eq!(config.attempt_timeout, Duration::from_secs(120));
+    assert_eq!(config.retry_limit, Some(3));
+    // Unset vars are left for the connector's defaults
+    assert_eq!(config.backoff_factor, Duration::ZERO);
+    assert_eq!(config.concurrent_leader_conns, 0);
+
+    set_env(&[("DQLITE_BACKOFF_CAP_MS", " 250 "), ("DQLITE_DIAL_TIMEOUT", "5")]);
+    let config = Config::from_env().unwrap();
+    assert_eq!(config.backoff_cap, Duration::from_millis(250));
+    assert_eq!(config.dial_timeout, Duration::from_secs(5));
+
+    set_env(&[
+        ("DQLITE_DIAL_TIMEOUT", "5h"),
+        ("DQLITE_RETRY_LIMIT", "-1"),
+        ("DQLITE_BACKOFF_FACTOR_MS", "10"),
+    ]);
+    let err = Config::from_env().unwrap_err();
+    assert_eq!(
+        err,
+        ConfigError(vec!["DQLITE_DIAL_TIMEOUT=\"5h\"".to_string(), "DQLITE_RETRY_LIMIT=\"-1\"".to_string()])
+    );
+    assert_eq!(
+        err.to_string(),
+        "Invalid dqlite environment configuration: DQLITE_DIAL_TIMEOUT=\"5h\"; DQLITE_RETRY_LIMIT=\"-1\""
+    );
+    set_env(&[]);
+}
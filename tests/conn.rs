@@ -0,0 +1,80 @@
+mod common;
+
+use common::*;
+use dqlite_rs::protocol::connector::Conn;
+use dqlite_rs::protocol::message::HEADER_SIZE;
+use tokio::io::AsyncReadExt;
+
+// Bytes queued in the kernel for `conn`, not yet read from the socket
+fn kernel_pending(conn: &Conn) -> usize {
+    let mut pending: libc::c_int = 0;
+    let rc = unsafe { libc::ioctl(conn.as_raw_fd(), libc::FIONREAD, &mut pending) };
+    assert_eq!(rc, 0);
+    pending as usize
+}
+
+#[tokio::test]
+async fn many_small_frames_are_served_from_one_socket_read() {
+    let (mut client, mut server) = Conn::from_unix_pair().unwrap();
+    let frames: Vec<_> = (0..100).map(|_| empty()).collect();
+    write_messages(&mut server, &frames).await.unwrap();
+    assert_eq!(kernel_pending(&client), 100 * 16);
+
+    // A header-sized read drains the whole socket into the buffer
+    let mut header = [0u8; HEADER_SIZE];
+    client.read_exact(&mut header).await.unwrap();
+    assert_eq!(kernel_pending(&client), 0);
+
+    let mut rest = vec![0u8; 100 * 16 - HEADER_SIZE];
+    client.read_exact(&mut rest).await.unwrap();
+    assert_eq!(kernel_pending(&client), 0);
+    // The rest of the first frame, then the second one's header
+    assert_eq!(rest[8..16], empty().header());
+}
+
+#[tokio::test]
+async fn is_alive_turns_false_once_the_peer_closes() {
+    let (client, server) = Conn::from_unix_pair().unwrap();
+    assert!(client.is_alive());
+
+    drop(server);
+    assert!(!client.is_alive());
+}
+
+#[tokio::test]
+async fn unread_data_keeps_a_closed_conn_alive_until_consumed() {
+    let (mut client, mut server) = Conn::from_unix_pair().unwrap();
+    write_messages(&mut server, &[empty()]).await.unwrap();
+    drop(server);
+    assert!(client.is_alive());
+
+    let mut frame = [0u8; 16];
+    client.read_exact(&mut frame).await.unwrap();
+    assert!(!client.is_alive());
+}
+
+#[tokio::test]
+async fn frames_round_trip_in_both_directions_over_a_pair() {
+    use dqlite_rs::protocol::message::{Message, Value, REQUEST_EXEC_SQL};
+
+    let (mut client, mut server) = Conn::from_unix_pair().unwrap();
+    let mut request = Message::new(REQUEST_EXEC_SQL);
+    request.put_u64(3);
+    request.put_text("INSERT INTO t VALUES (?)");
+    request.put_params(&[Value::Integer(-1), Value::Text("x".into())]).unwrap();
+
+    write_messages(&mut client, &[request.clone()]).await.unwrap();
+    let mut received = read_request(&mut server).await.unwrap().unwrap();
+    assert_eq!(received.header(), request.header());
+    assert_eq!(received.body(), request.body());
+    assert_eq!(received.get_u64().unwrap(), 3);
+    assert_eq!(received.get_text().unwrap(), "INSERT INTO t VALUES (?)");
+
+    write_messages(&mut server, &[result(7, 1), empty()]).await.unwrap();
+    assert_eq!(read_request(&mut client).await.unwrap().unwrap().body(), result(7, 1).body());
+    assert_eq!(read_request(&mut client).await.unwrap().unwrap().header(), empty().header());
+
+    // Closing one end is a clean EOF on the other
+    drop(server);
+    assert!(read_request(&mut client).await.unwrap().is_none());
+}
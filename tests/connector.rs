@@ -0,0 +1,494 @@
+mod common;
+
+use common::*;
+use dqlite_rs::protocol::config::Config;
+use dqlite_rs::protocol::connector::{dial_timeout, ConnState, Connector, DialFunc};
+use dqlite_rs::protocol::deadline::Deadline;
+use dqlite_rs::protocol::message::*;
+use dqlite_rs::protocol::protocol::ProtocolError;
+use dqlite_rs::protocol::store::{InMemoryNodeStore, NodeInfo, NodeRole, NodeStore, NodeStoreResult, ObservableNodeStore};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+
+#[tokio::test]
+async fn deadline_expires_during_the_handshake() {
+    let cluster = MockCluster::new(&[(1, N1)]);
+    // The node takes far longer than the deadline to answer the leader probe
+    // that follows the version
+    cluster.set_delay(N1, Duration::from_secs(5));
+    let connector = cluster.connector(Config::new()).await;
+
+    let started = Instant::now();
+    let result = connector.connect_with_deadline(Deadline::after(Duration::from_millis(100))).await;
+
+    assert!(matches!(result, Err(ProtocolError::DeadlineExceeded)));
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert_eq!(cluster.dials(), [N1]);
+}
+
+#[tokio::test]
+async fn a_single_dial_permit_serializes_dials_across_connectors() {
+    let cluster = MockCluster::new(&[(1, N1)]);
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let most = Arc::new(AtomicUsize::new(0));
+    let inner = cluster.dial_func();
+    let (current, peak) = (in_flight.clone(), most.clone());
+    // Slow dials that record how many of them overlap
+    let dial: DialFunc = Arc::new(move |addr: &str| {
+        let (inner, current, peak, addr) = (inner.clone(), current.clone(), peak.clone(), addr.to_string());
+        Box::pin(async move {
+            peak.fetch_max(current.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            current.fetch_sub(1, Ordering::SeqCst);
+            inner(&addr).await
+        })
+    });
+    let limiter = Arc::new(Semaphore::new(1));
+    let config = Config::new().with_dial(dial).with_dial_limiter(limiter);
+    let first = Connector::builder().store(cluster.store().await).config(config.clone()).build().unwrap();
+    let second = Connector::builder().store(cluster.store().await).config(config).build().unwrap();
+
+    let started = Instant::now();
+    let (a, b) = tokio::join!(first.connect(), second.connect());
+
+    assert!(a.is_ok() && b.is_ok());
+    assert_eq!(most.load(Ordering::SeqCst), 1);
+    assert!(started.elapsed() >= Duration::from_millis(100));
+    assert_eq!(cluster.dials(), [N1, N1]);
+}
+
+#[tokio::test]
+async fn dial_timeout_gives_up_on_an_unroutable_address() {
+    let started = Instant::now();
+    // TEST-NET-1, reserved for documentation and never routed
+    let result = dial_timeout("192.0.2.1:9001", Duration::from_millis(100)).await;
+
+    assert!(result.is_err());
+    assert!(started.elapsed() < Duration::from_millis(500), "took {:?}", started.elapsed());
+}
+
+// Every node answers QUERY_SQL with a single row holding its own address
+fn answer_with_address(cluster: &MockCluster) {
+    cluster.on_request(|addr, request| match request.mtype {
+        REQUEST_QUERY_SQL => Some(vec![rows(&["addr"], &[vec![Value::Text(addr.to_string())]], false)]),
+        _ => None,
+    });
+}
+
+#[tokio::test]
+async fn query_any_returns_the_fast_node_answer() {
+    let cluster = MockCluster::new(&[(1, N1), (2, N2), (3, N3)]);
+    answer_with_address(&cluster);
+    cluster.set_delay(N1, Duration::from_secs(5));
+    cluster.set_down(N2, true);
+    let connector = cluster.connector(Config::new().with_concurrent_leader_conns(3)).await;
+
+    let started = Instant::now();
+    let rows = connector.query_any("app", "SELECT addr", &[]).await.unwrap();
+
+    assert_eq!(rows.iter().next().unwrap().values(), [Value::Text(N3.into())]);
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn query_any_reaches_every_node_past_the_concurrency_limit() {
+    let cluster = MockCluster::new(&[(1, N1), (2, N2), (3, N3)]);
+    answer_with_address(&cluster);
+    cluster.set_down(N1, true);
+    cluster.set_down(N2, true);
+    let connector = cluster.connector(Config::new().with_concurrent_leader_conns(1)).await;
+
+    let rows = connector.query_any("app", "SELECT addr", &[]).await.unwrap();
+
+    assert_eq!(rows.iter().next().unwrap().values(), [Value::Text(N3.into())]);
+    assert_eq!(cluster.dials(), [N1, N2, N3]);
+}
+
+#[tokio::test]
+async fn a_same_domain_follower_is_asked_before_a_cross_domain_one() {
+    let cluster = MockCluster::new(&[(1, N1), (2, N2), (3, N3)]);
+    answer_with_address(&cluster);
+    // N1 leads from domain 1; of the followers, N2 is remote and N3 local
+    let store = InMemoryNodeStore::new();
+    let mut nodes = cluster.nodes();
+    for (node, domain) in nodes.iter_mut().zip([1, 2, 3]) {
+        node.failure_domain = Some(domain);
+    }
+    store.set_all(nodes).await.unwrap();
+    let connector = Connector::builder()
+        .store(Arc::new(ObservableNodeStore::new(store)))
+        .config(Config::new().with_concurrent_leader_conns(1).with_dial(cluster.dial_func()))
+        .failure_domain(3)
+        .build()
+        .unwrap();
+
+    let rows = connector.query_any("app", "SELECT addr", &[]).await.unwrap();
+    assert_eq!(rows.iter().next().unwrap().values(), [Value::Text(N3.into())]);
+    assert_eq!(cluster.dials(), [N3]);
+
+    // Writes still go to the leader, found by asking the local follower
+    connector.connect().await.unwrap();
+    assert_eq!(cluster.dials(), [N3, N3, N1]);
+}
+
+#[tokio::test]
+async fn cached_leader_is_looked_up_again_after_the_ttl() {
+    let cluster = MockCluster::new(&[(1, N1), (2, N2)]);
+    let connector = cluster.connector(Config::new().with_leader_ttl(Duration::from_millis(50))).await;
+    assert_eq!(connector.connect().await.unwrap().addr(), N1);
+
+    // Leadership moves; within the TTL the cached address is still trusted
+    cluster.set_leader(Some(N2));
+    assert_eq!(connector.connect().await.unwrap().addr(), N1);
+
+    // Past it, N1 is asked again and points at the new leader
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(connector.connect().await.unwrap().addr(), N2);
+    assert_eq!(cluster.dials(), [N1, N1, N1, N2]);
+}
+
+#[tokio::test]
+async fn cancelling_during_a_backoff_sleep_returns_promptly() {
+    let cluster = MockCluster::new(&[(1, N1)]);
+    cluster.set_down(N1, true);
+    let config = Config::new()
+        .with_retry_limit(10)
+        .with_backoff_factor(Duration::from_secs(10))
+        .with_backoff_cap(Duration::from_secs(10));
+    let connector = cluster.connector(config).await;
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        cancel.cancel();
+    });
+
+    let started = Instant::now();
+    let result = connector.connect_with_cancel(token).await;
+
+    assert!(matches!(result, Err(ProtocolError::Cancelled)));
+    assert!(started.elapsed() < Duration::from_secs(1));
+    // Cancelled in the sleep after the first failed attempt
+    assert_eq!(cluster.dials(), [N1]);
+}
+
+#[tokio::test]
+async fn a_store_update_cuts_the_backoff_short() {
+    let cluster = MockCluster::new(&[(1, N1), (2, N2)]);
+    cluster.set_leader(Some(N2));
+    cluster.set_down(N1, true);
+    // The store only knows the node that is down
+    let inner = InMemoryNodeStore::new();
+    inner.set_all(vec![cluster.nodes()[0].clone()]).await.unwrap();
+    let store = Arc::new(ObservableNodeStore::new(inner));
+    let config = Config::new()
+        .with_dial(cluster.dial_func())
+        .with_retry_limit(10)
+        .with_backoff_factor(Duration::from_secs(10))
+        .with_backoff_cap(Duration::from_secs(10));
+    let connector = Connector::builder().store(store.clone()).config(config).build().unwrap();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        store.set_all(cluster.nodes()).await.unwrap();
+    });
+
+    let started = Instant::now();
+    let proto = connector.connect().await.unwrap();
+
+    assert_eq!(proto.addr(), N2);
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+// Every node answers EXEC_SQL, logging the SQL, and fails `failing`
+fn log_exec_sql(cluster: &MockCluster, failing: &'static str) -> Arc<std::sync::Mutex<Vec<String>>> {
+    let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = log.clone();
+    cluster.on_request(move |_, request| match request.mtype {
+        REQUEST_OPEN => {
+            seen.lock().unwrap().push("open".to_string());
+            None
+        }
+        REQUEST_EXEC_SQL => {
+            request.get_u64().unwrap();
+            let sql = request.get_text().unwrap();
+            seen.lock().unwrap().push(sql.clone());
+            if sql == failing {
+                Some(vec![failure(SQLITE_ERROR, "no such pragma")])
+            } else {
+                Some(vec![result(0, 0)])
+            }
+        }
+        _ => None,
+    });
+    log
+}
+
+#[tokio::test]
+async fn init_statements_run_in_order_after_open() {
+    let cluster = MockCluster::new(&[(1, N1)]);
+    let log = log_exec_sql(&cluster, "");
+    let statements = vec!["PRAGMA foreign_keys=ON".to_string(), "PRAGMA busy_timeout=500".to_string()];
+    let connector = cluster.connector(Config::new().with_init_statements(statements)).await;
+
+    connector.open("app").await.unwrap();
+
+    assert_eq!(*log.lock().unwrap(), ["open", "PRAGMA foreign_keys=ON", "PRAGMA busy_timeout=500"]);
+}
+
+#[tokio::test]
+async fn a_failing_init_statement_aborts_the_open() {
+    let cluster = MockCluster::new(&[(1, N1)]);
+    let log = log_exec_sql(&cluster, "PRAGMA bogus");
+    let statements = vec!["PRAGMA bogus".to_string(), "PRAGMA foreign_keys=ON".to_string()];
+    let connector = cluster.connector(Config::new().with_init_statements(statements)).await;
+
+    assert!(connector.open("app").await.is_err());
+    assert_eq!(*log.lock().unwrap(), ["open", "PRAGMA bogus"]);
+}
+
+// Five voters, two pairs of which share a failure domain
+#[tokio::test]
+async fn apply_topology_spreads_voters_and_stand_bys_across_failure_domains() {
+    let addrs: Vec<String> = (1..=5).map(|i| format!("10.0.0.{}:9001", i)).collect();
+    let members: Vec<(u64, &str)> = addrs.iter().enumerate().map(|(i, a)| (i as u64 + 1, a.as_str())).collect();
+    let mock = MockCluster::new(&members);
+    let roles = Arc::new(std::sync::Mutex::new(vec![NodeRole::VOTER.value(); 5]));
+    let assigned = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let (state, log, names) = (roles.clone(), assigned.clone(), addrs.clone());
+    mock.on_request(move |_, request| match request.mtype {
+        REQUEST_CLUSTER => {
+            let roles = state.lock().unwrap();
+            let nodes: Vec<_> = (0..5).map(|i| (i as u64 + 1, names[i].as_str(), roles[i])).collect();
+            Some(vec![cluster(&nodes)])
+        }
+        REQUEST_ASSIGN => {
+            let id = request.get_u64().unwrap();
+            let role = request.get_u64().unwrap() as u8;
+            state.lock().unwrap()[id as usize - 1] = role;
+            log.lock().unwrap().push((id, role));
+            Some(vec![empty()])
+        }
+        _ => None,
+    });
+
+    let mut nodes = mock.nodes();
+    for (node, domain) in nodes.iter_mut().zip([1, 1, 2, 2, 3]) {
+        node.failure_domain = Some(domain);
+    }
+    let store = InMemoryNodeStore::new();
+    store.set_all(nodes).await.unwrap();
+    let connector: Connector<InMemoryNodeStore> = Connector::builder()
+        .store(Arc::new(ObservableNodeStore::new(store)))
+        .config(Config::new().with_dial(mock.dial_func()))
+        .build()
+        .unwrap();
+
+    connector.apply_topology(3, 2).await.unwrap();
+
+    // By id alone the voters would be 1, 2 and 3; one per domain keeps 1, 3 and 5
+    let stand_by = NodeRole::STAND_BY.value();
+    let voter = NodeRole::VOTER.value();
+    assert_eq!(*assigned.lock().unwrap(), [(2, stand_by), (4, stand_by)]);
+    assert_eq!(*roles.lock().unwrap(), [voter, stand_by, voter, stand_by, voter]);
+
+    // Already in shape: nothing more is assigned
+    connector.apply_topology(3, 2).await.unwrap();
+    assert_eq!(assigned.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn dropping_the_tracked_database_makes_connect_rediscover_the_leader() {
+    let cluster = MockCluster::new(&[(1, N1), (2, N2)]);
+    let connector = cluster.connector(Config::new().with_leader_ttl(Duration::from_secs(60))).await;
+    let app = connector.open("app").await.unwrap();
+    connector.track_database(&app).await;
+
+    // While it or a clone is alive the cached leader is trusted within the
+    // TTL, even though leadership has moved
+    let clone = app.clone();
+    drop(app);
+    cluster.set_leader(Some(N2));
+    assert_eq!(connector.connect().await.unwrap().addr(), N1);
+
+    // Once the last handle is dropped the cache counts as empty and the
+    // leader is asked for
+    drop(clone);
+    let dials = cluster.dials().len();
+    let proto = connector.connect().await.unwrap();
+    assert_eq!(proto.addr(), N2);
+    assert_eq!(cluster.dials()[dials..], [N1, N2]);
+}
+
+#[tokio::test]
+async fn states_record_failed_and_connected_dials() {
+    let cluster = MockCluster::new(&[(1, N1), (2, N2)]);
+    cluster.set_leader(Some(N2));
+    cluster.set_down(N1, true);
+    let connector = cluster.connector(Config::new()).await;
+
+    let proto = connector.connect().await.unwrap();
+    assert_eq!(proto.addr(), N2);
+
+    let states = connector.states();
+    assert_eq!(states.len(), 2);
+    assert_eq!(states[0].addr, N1);
+    assert_eq!(states[0].state, ConnState::Failed);
+    assert!(states[0].last_error.as_deref().unwrap().contains("connection refused"), "{:?}", states[0]);
+    assert_eq!(states[0].last_rtt, None);
+    assert_eq!(states[1].addr, N2);
+    assert_eq!(states[1].state, ConnState::Connected);
+    assert_eq!(states[1].last_error, None);
+    assert!(states[1].last_rtt.is_some());
+}
+
+#[tokio::test]
+async fn identity_accessors_return_what_the_builder_was_given() {
+    let store = Arc::new(ObservableNodeStore::new(InMemoryNodeStore::new()));
+    let connector = Connector::builder()
+        .store(store.clone())
+        .client_id(7)
+        .node(3, "10.0.0.3:9001")
+        .failure_domain(2)
+        .build()
+        .unwrap();
+
+    assert_eq!(connector.client_id(), 7);
+    assert_eq!(connector.node_id(), 3);
+    assert_eq!(connector.node_addr(), "10.0.0.3:9001");
+    assert_eq!(connector.failure_domain(), Some(2));
+
+    // Without a local node
+    let connector = Connector::builder().store(store).build().unwrap();
+    assert_eq!(connector.node_id(), 0);
+    assert_eq!(connector.node_addr(), "");
+    assert_eq!(connector.failure_domain(), None);
+}
+
+// A store that takes whatever it is given, like one backed by a file edited
+// by hand, so malformed addresses reach the connector
+#[derive(Default)]
+struct UncheckedStore(std::sync::Mutex<Vec<NodeInfo>>);
+
+#[async_trait::async_trait]
+impl NodeStore for UncheckedStore {
+    async fn get_all(&self) -> NodeStoreResult<Vec<NodeInfo>> {
+        Ok(self.0.lock().unwrap().clone())
+    }
+
+    async fn get_by_id(&self, id: u64) -> NodeStoreResult<Option<NodeInfo>> {
+        Ok(self.0.lock().unwrap().iter().find(|node| node.id == id).cloned())
+    }
+
+    async fn get_by_address(&self, address: &str) -> NodeStoreResult<Option<NodeInfo>> {
+        Ok(self.0.lock().unwrap().iter().find(|node| node.addr == address).cloned())
+    }
+
+    async fn set_all(&self, nodes: Vec<NodeInfo>) -> NodeStoreResult<()> {
+        *self.0.lock().unwrap() = nodes;
+        Ok(())
+    }
+
+    async fn upsert(&self, node: NodeInfo) -> NodeStoreResult<()> {
+        let mut nodes = self.0.lock().unwrap();
+        nodes.retain(|n| n.id != node.id);
+        nodes.push(node);
+        Ok(())
+    }
+
+    async fn remove(&self, id: u64) -> NodeStoreResult<bool> {
+        let mut nodes = self.0.lock().unwrap();
+        let before = nodes.len();
+        nodes.retain(|n| n.id != id);
+        Ok(nodes.len() != before)
+    }
+
+    async fn version(&self) -> NodeStoreResult<u64> {
+        Ok(0)
+    }
+
+    async fn set_if_version(&self, nodes: Vec<NodeInfo>, _version: u64) -> NodeStoreResult<()> {
+        self.set_all(nodes).await
+    }
+}
+
+#[tokio::test]
+async fn a_malformed_store_address_is_skipped_for_the_next_candidate() {
+    let mock = MockCluster::new(&[(2, N1)]);
+    let node = |id, addr: &str| NodeInfo { id, addr: addr.to_string(), role: NodeRole::VOTER, failure_domain: None };
+    let connector = |nodes| {
+        let store = UncheckedStore(std::sync::Mutex::new(nodes));
+        Connector::builder()
+            .store(Arc::new(ObservableNodeStore::new(store)))
+            .config(Config::new().with_retry_limit(0).with_dial(mock.dial_func()))
+            .build()
+            .unwrap()
+    };
+
+    // The bogus entry comes first
+    let proto = connector(vec![node(1, "not an address"), node(2, N1)]).connect().await.unwrap();
+    assert_eq!(proto.addr(), N1);
+    assert_eq!(mock.dials(), [N1]);
+
+    // With no good address left, every failure is reported
+    let err = connector(vec![node(1, "not an address"), node(2, "host:port")]).connect().await.err().unwrap();
+    let err = err.to_string();
+    assert!(err.contains("not an address: not a valid dial address"), "{}", err);
+    assert!(err.contains("host:port: not a valid dial address"), "{}", err);
+}
+
+#[tokio::test]
+async fn connect_to_reaches_the_follower_it_is_asked_for() {
+    let cluster = MockCluster::new(&[(1, N1), (2, N2), (3, N3)]);
+    let connector = cluster.connector(Config::new()).await;
+
+    let mut proto = connector.connect_to(2).await.unwrap();
+
+    // No redirection to the leader, N1
+    assert_eq!(cluster.dials(), [N2]);
+    assert_eq!(proto.leader().await.unwrap(), (1, N1.to_string()));
+    assert_eq!(cluster.count(N1, REQUEST_LEADER), 0);
+
+    let err = connector.connect_to(9).await.err().unwrap();
+    assert!(matches!(&err, ProtocolError::Io(e) if e.kind() == std::io::ErrorKind::NotFound), "{}", err);
+    assert_eq!(cluster.dials(), [N2]);
+}
+
+#[tokio::test]
+async fn verify_peer_address_rejects_a_dialer_reaching_the_wrong_node() {
+    use tokio::io::AsyncReadExt;
+
+    let requested = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let wrong = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let wrong_addr = wrong.local_addr().unwrap().to_string();
+    // A misconfigured dialer: whatever it is asked for, it reaches `wrong`
+    let dial: DialFunc = Arc::new(move |_: &str| {
+        let wrong_addr = wrong_addr.clone();
+        Box::pin(async move { dqlite_rs::protocol::connector::dial(&wrong_addr).await })
+    });
+    let store = InMemoryNodeStore::new();
+    store.set_all(vec![NodeInfo {
+        id: 1,
+        addr: requested.local_addr().unwrap().to_string(),
+        role: NodeRole::VOTER,
+        failure_domain: None,
+    }])
+    .await
+    .unwrap();
+    let connector = Connector::builder()
+        .store(Arc::new(ObservableNodeStore::new(store)))
+        .config(Config::new().with_dial(dial).with_verify_peer_address(true))
+        .build()
+        .unwrap();
+
+    let err = connector.connect_to(1).await.err().unwrap();
+
+    assert!(matches!(&err, ProtocolError::Protocol(message) if message.contains("peer")), "{}", err);
+    // The wrong node never saw the handshake
+    let (mut stray, _) = wrong.accept().await.unwrap();
+    let mut received = Vec::new();
+    stray.read_to_end(&mut received).await.unwrap();
+    assert!(received.is_empty());
+}
@@ -0,0 +1,453 @@
+mod common;
+
+use common::*;
+use dqlite_rs::protocol::config::Config;
+use dqlite_rs::protocol::connector::Conn;
+use dqlite_rs::protocol::database::{Database, IdempotentExec, Queued, ReconnectingDatabase, WritePolicy};
+use dqlite_rs::protocol::message::*;
+use dqlite_rs::protocol::protocol::{Protocol, ProtocolError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+#[tokio::test]
+async fn exec_idempotent_reconnects_and_skips_an_applied_write() {
+    let cluster = MockCluster::new(&[(1, N1)]);
+    let applied = Arc::new(AtomicUsize::new(0));
+    let execs = applied.clone();
+    cluster.on_request(move |_, request| match request.mtype {
+        REQUEST_PREPARE => Some(vec![stmt(0, 1, 1)]),
+        // The write lands, but the connection drops before the reply
+        REQUEST_EXEC => {
+            execs.fetch_add(1, Ordering::SeqCst);
+            Some(vec![])
+        }
+        REQUEST_QUERY_SQL => {
+            let found = if execs.load(Ordering::SeqCst) > 0 { vec![vec![Value::Integer(1)]] } else { vec![] };
+            Some(vec![rows(&["1"], &found, false)])
+        }
+        _ => None,
+    });
+    let connector = cluster.connector(Config::new().with_retry_limit(0)).await;
+
+    let db = connector.open("app").await.unwrap();
+    let mut insert = db.prepare("INSERT INTO t (key) VALUES (?)").await.unwrap();
+    let check = Database::dedup_table_check("dedup");
+    let outcome = insert
+        .exec_idempotent(&connector, &[Value::Text("k1".into())], "k1", &check)
+        .await
+        .unwrap();
+
+    assert_eq!(outcome, IdempotentExec::AlreadyApplied);
+    assert_eq!(applied.load(Ordering::SeqCst), 1);
+    // The retry went through a second connection
+    assert_eq!(cluster.dials(), [N1, N1]);
+}
+
+#[tokio::test]
+async fn exec_idempotent_prepares_again_on_the_new_connection() {
+    let cluster = MockCluster::new(&[(1, N1)]);
+    let execs = Arc::new(AtomicUsize::new(0));
+    let counter = execs.clone();
+    cluster.on_request(move |_, request| match request.mtype {
+        REQUEST_PREPARE => Some(vec![stmt(0, 1, 1)]),
+        REQUEST_EXEC if counter.fetch_add(1, Ordering::SeqCst) == 0 => {
+            Some(vec![failure(SQLITE_IOERR_NOT_LEADER, "not leader")])
+        }
+        REQUEST_EXEC => Some(vec![result(5, 1)]),
+        REQUEST_QUERY_SQL => Some(vec![rows(&["1"], &[], false)]),
+        _ => None,
+    });
+    let connector = cluster.connector(Config::new().with_retry_limit(0)).await;
+
+    let db = connector.open("app").await.unwrap();
+    let mut insert = db.prepare("INSERT INTO t (key) VALUES (?)").await.unwrap();
+    let check = Database::dedup_table_check("dedup");
+    let outcome = insert
+        .exec_idempotent(&connector, &[Value::Text("k1".into())], "k1", &check)
+        .await
+        .unwrap();
+
+    assert_eq!(outcome, IdempotentExec::Executed(ExecResult { last_insert_id: 5, rows_affected: 1 }));
+    assert_eq!(execs.load(Ordering::SeqCst), 2);
+    assert_eq!(cluster.count(N1, REQUEST_PREPARE), 2);
+}
+
+#[tokio::test]
+async fn read_only_opens_with_the_read_only_flag() {
+    let (proto, server) = connected(|request| {
+        assert_eq!(request.mtype, REQUEST_OPEN);
+        assert_eq!(request.get_text().unwrap(), "reports");
+        let flags = OpenFlags(request.get_u64().unwrap());
+        assert!(flags.contains(OpenFlags::READONLY));
+        assert!(!flags.contains(OpenFlags::READWRITE));
+        assert!(!flags.contains(OpenFlags::CREATE));
+        vec![db(3)]
+    })
+    .await;
+
+    let db = Database::read_only(Arc::new(Mutex::new(proto)), "reports").await.unwrap();
+    assert_eq!(db.id(), 3);
+    drop(db);
+    assert_eq!(server.await.unwrap().1, [REQUEST_OPEN]);
+}
+
+#[tokio::test]
+async fn rows_affected_of_a_multi_row_update_matches_changes() {
+    let (proto, _server) = connected(|request| match request.mtype {
+        REQUEST_OPEN => vec![db(0)],
+        REQUEST_EXEC_SQL => vec![result(0, 3)],
+        REQUEST_QUERY_SQL => {
+            request.get_u64().unwrap();
+            let count = match request.get_text().unwrap().as_str() {
+                "SELECT changes()" => 3,
+                "SELECT total_changes()" => 7,
+                other => panic!("unexpected query {}", other),
+            };
+            vec![rows(&["n"], &[vec![Value::Integer(count)]], false)]
+        }
+        other => panic!("unexpected request {}", other),
+    })
+    .await;
+    let db = Database::open(Arc::new(Mutex::new(proto)), "app").await.unwrap();
+
+    let result = db.execute("UPDATE t SET v = v + 1", &[]).await.unwrap();
+
+    assert_eq!(result.rows_affected, 3);
+    assert_eq!(db.changes().await.unwrap(), 3);
+    assert_eq!(db.total_changes().await.unwrap(), 7);
+}
+
+#[tokio::test]
+async fn observer_sees_every_statement_with_its_duration() {
+    let (proto, _server) = connected(|request| {
+        if request.mtype != REQUEST_OPEN {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        match request.mtype {
+            REQUEST_OPEN => vec![db(0)],
+            REQUEST_EXEC_SQL => vec![result(1, 1)],
+            REQUEST_QUERY_SQL => vec![rows(&["v"], &[vec![Value::Integer(1)]], false)],
+            other => panic!("unexpected request {}", other),
+        }
+    })
+    .await;
+    let mut db = Database::open(Arc::new(Mutex::new(proto)), "app").await.unwrap();
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let log = seen.clone();
+    db.set_observer(Box::new(move |sql, elapsed, result| {
+        log.lock().unwrap().push((sql.to_string(), elapsed, result.cloned()));
+    }));
+
+    db.execute("INSERT INTO t (v) VALUES (1)", &[]).await.unwrap();
+    db.query("SELECT v FROM t", &[]).await.unwrap();
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 2);
+    assert_eq!(seen[0].0, "INSERT INTO t (v) VALUES (1)");
+    assert_eq!(seen[0].2, Some(ExecResult { last_insert_id: 1, rows_affected: 1 }));
+    assert_eq!(seen[1].0, "SELECT v FROM t");
+    assert_eq!(seen[1].2, None);
+    for (_, elapsed, _) in seen.iter() {
+        assert!(*elapsed >= Duration::from_millis(10) && *elapsed < Duration::from_secs(5), "{:?}", elapsed);
+    }
+}
+
+// N1 loses leadership to N2 between two queries
+#[tokio::test]
+async fn reconnecting_database_follows_a_leader_change() {
+    let cluster = MockCluster::new(&[(1, N1), (2, N2)]);
+    let leader = Arc::new(std::sync::Mutex::new(N1));
+    let current = leader.clone();
+    cluster.on_request(move |addr, request| {
+        let answer = match request.mtype {
+            REQUEST_QUERY_SQL => rows(&["node"], &[vec![Value::Text(addr.to_string())]], false),
+            REQUEST_EXEC_SQL => result(1, 1),
+            _ => return None,
+        };
+        if addr != *current.lock().unwrap() {
+            return Some(vec![failure(SQLITE_IOERR_NOT_LEADER, "not leader")]);
+        }
+        Some(vec![answer])
+    });
+    // The cached leader would be trusted for a minute, unless a not-leader
+    // reply drops it
+    let config = Config::new()
+        .with_backoff_factor(Duration::from_millis(1))
+        .with_leader_ttl(Duration::from_secs(60));
+    let connector = Arc::new(cluster.connector(config).await);
+    let db = ReconnectingDatabase::new(connector, "app");
+
+    let served_by = |rows: Rows| rows.iter().next().unwrap().values()[0].clone();
+    assert_eq!(served_by(db.query("SELECT 1", &[]).await.unwrap()), Value::Text(N1.into()));
+
+    cluster.set_leader(Some(N2));
+    *leader.lock().unwrap() = N2;
+    assert_eq!(served_by(db.query("SELECT 1", &[]).await.unwrap()), Value::Text(N2.into()));
+    // The write is rejected by N2, so retrying it on N1 is safe
+    cluster.set_leader(Some(N1));
+    *leader.lock().unwrap() = N1;
+    assert_eq!(db.execute("INSERT INTO t VALUES (1)", &[]).await.unwrap().rows_affected, 1);
+    assert_eq!(cluster.count(N1, REQUEST_EXEC_SQL), 1);
+    assert_eq!(cluster.count(N2, REQUEST_EXEC_SQL), 1);
+}
+
+#[tokio::test]
+async fn reconnecting_database_reopens_after_the_connection_drops() {
+    let cluster = MockCluster::new(&[(1, N1)]);
+    let queries = Arc::new(AtomicUsize::new(0));
+    let counter = queries.clone();
+    cluster.on_request(move |_, request| match request.mtype {
+        // The second query kills its connection
+        REQUEST_QUERY_SQL if counter.fetch_add(1, Ordering::SeqCst) == 1 => Some(vec![]),
+        REQUEST_QUERY_SQL => Some(vec![rows(&["v"], &[vec![Value::Integer(1)]], false)]),
+        _ => None,
+    });
+    let connector = Arc::new(cluster.connector(Config::new().with_backoff_factor(Duration::from_millis(1))).await);
+    let db = ReconnectingDatabase::new(connector, "app");
+
+    db.query("SELECT v FROM t", &[]).await.unwrap();
+    assert_eq!(db.query("SELECT v FROM t", &[]).await.unwrap().len(), 1);
+
+    assert_eq!(queries.load(Ordering::SeqCst), 3);
+    assert_eq!(cluster.count(N1, REQUEST_OPEN), 2);
+}
+
+#[tokio::test]
+async fn a_write_failing_on_io_leaves_the_next_write_a_new_connection() {
+    let cluster = MockCluster::new(&[(1, N1)]);
+    let execs = Arc::new(AtomicUsize::new(0));
+    let counter = execs.clone();
+    cluster.on_request(move |_, request| match request.mtype {
+        // The first write kills its connection; it may have been applied,
+        // so it isn't retried
+        REQUEST_EXEC_SQL if counter.fetch_add(1, Ordering::SeqCst) == 0 => Some(vec![]),
+        REQUEST_EXEC_SQL => Some(vec![result(2, 1)]),
+        _ => None,
+    });
+    let connector = Arc::new(cluster.connector(Config::new().with_backoff_factor(Duration::from_millis(1))).await);
+    let db = ReconnectingDatabase::new(connector, "app");
+
+    let err = db.execute("INSERT INTO t VALUES (1)", &[]).await.err().unwrap();
+    assert!(matches!(err, ProtocolError::Io(_)), "{}", err);
+    assert_eq!(execs.load(Ordering::SeqCst), 1);
+
+    assert_eq!(db.execute("INSERT INTO t VALUES (2)", &[]).await.unwrap().last_insert_id, 2);
+    assert_eq!(cluster.count(N1, REQUEST_OPEN), 2);
+}
+
+#[tokio::test]
+async fn reconnecting_database_reopens_when_the_server_forgets_the_id() {
+    const SQLITE_NOTFOUND: u64 = 12;
+    let cluster = MockCluster::new(&[(1, N1)]);
+    let execs = Arc::new(AtomicUsize::new(0));
+    let counter = execs.clone();
+    cluster.on_request(move |_, request| match request.mtype {
+        REQUEST_EXEC_SQL if counter.fetch_add(1, Ordering::SeqCst) == 0 => {
+            Some(vec![failure(SQLITE_NOTFOUND, "no database opened")])
+        }
+        REQUEST_EXEC_SQL => Some(vec![result(4, 1)]),
+        _ => None,
+    });
+    let connector = Arc::new(cluster.connector(Config::new().with_backoff_factor(Duration::from_millis(1))).await);
+    let db = ReconnectingDatabase::new(connector, "app");
+
+    let result = db.execute("INSERT INTO t VALUES (1)", &[]).await.unwrap();
+
+    assert_eq!(result.last_insert_id, 4);
+    assert_eq!(cluster.count(N1, REQUEST_OPEN), 2);
+}
+
+// A database on a connection whose server end is handed to the test
+async fn batched_db(n: usize) -> (Database, Conn) {
+    let (client, mut server) = Conn::from_unix_pair().unwrap();
+    let mut proto = Protocol::new(client, "mock");
+    proto.handshake().await.unwrap();
+    read_version(&mut server).await.unwrap();
+    let open = tokio::spawn(async move {
+        read_request(&mut server).await.unwrap().unwrap();
+        write_messages(&mut server, &[db(0)]).await.unwrap();
+        server
+    });
+    let db = Database::open(Arc::new(Mutex::new(proto)), "app").await.unwrap();
+    (db.with_write_policy(WritePolicy::Batched { n }), open.await.unwrap())
+}
+
+#[tokio::test]
+async fn ten_queued_execs_go_out_in_a_single_write() {
+    let (db, mut server) = batched_db(10).await;
+
+    for i in 0..9 {
+        assert_eq!(db.queue("INSERT INTO t VALUES (?)", &[Value::Integer(i)]).await.unwrap(), Queued::Pending);
+    }
+    assert!(request_within(&mut server, Duration::from_millis(50)).await.is_none());
+
+    // The server reads all ten before answering any, which only works if the
+    // batch was written in one go rather than one request per round trip
+    let server = tokio::spawn(async move {
+        for _ in 0..10 {
+            let request = read_request(&mut server).await.unwrap().unwrap();
+            assert_eq!(request.mtype, REQUEST_EXEC_SQL);
+        }
+        let results: Vec<_> = (1..=10).map(|id| result(id, 1)).collect();
+        write_messages(&mut server, &results).await.unwrap();
+        server
+    });
+    let sent = tokio::time::timeout(Duration::from_secs(5), db.queue("INSERT INTO t VALUES (9)", &[]))
+        .await
+        .expect("the batch was not pipelined")
+        .unwrap();
+
+    let Queued::Sent(results) = sent else { panic!("the tenth exec did not send the batch") };
+    assert_eq!(results.iter().map(|r| r.last_insert_id).collect::<Vec<_>>(), (1..=10).collect::<Vec<_>>());
+    let mut server = server.await.unwrap();
+    assert!(request_within(&mut server, Duration::from_millis(50)).await.is_none());
+}
+
+#[tokio::test]
+async fn execute_sends_queued_statements_first() {
+    let (db, mut server) = batched_db(10).await;
+    let server = tokio::spawn(async move {
+        let mut sql = Vec::new();
+        for id in 1..=3 {
+            let mut request = read_request(&mut server).await.unwrap().unwrap();
+            request.get_u64().unwrap();
+            sql.push(request.get_text().unwrap());
+            write_messages(&mut server, &[result(id, 1)]).await.unwrap();
+        }
+        sql
+    });
+
+    db.queue("INSERT INTO t VALUES (1)", &[]).await.unwrap();
+    db.queue("INSERT INTO t VALUES (2)", &[]).await.unwrap();
+    // A real result, not a placeholder for a buffered statement
+    assert_eq!(db.execute("INSERT INTO t VALUES (3)", &[]).await.unwrap().last_insert_id, 3);
+
+    assert_eq!(
+        server.await.unwrap(),
+        ["INSERT INTO t VALUES (1)", "INSERT INTO t VALUES (2)", "INSERT INTO t VALUES (3)"]
+    );
+}
+
+#[tokio::test]
+async fn dropping_the_last_handle_sends_nothing() {
+    let (db, mut server) = batched_db(10).await;
+    let server = tokio::spawn(async move {
+        let mut seen = 0;
+        while let Ok(Some(_)) = read_request(&mut server).await {
+            seen += 1;
+            write_messages(&mut server, &[result(seen, 1)]).await.unwrap();
+        }
+        seen
+    });
+
+    let clone = db.clone();
+    for i in 0..3 {
+        db.queue("INSERT INTO t VALUES (?)", &[Value::Integer(i)]).await.unwrap();
+    }
+    drop(db);
+    clone.queue("INSERT INTO t VALUES (3)", &[]).await.unwrap();
+    // The buffered statements are discarded with a warning, and the
+    // connection goes with the last handle
+    drop(clone);
+
+    assert_eq!(server.await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn a_forced_reconnect_reports_disconnected_then_connected() {
+    use dqlite_rs::protocol::connector::ConnEvent;
+
+    let cluster = MockCluster::new(&[(1, N1)]);
+    let queries = Arc::new(AtomicUsize::new(0));
+    let counter = queries.clone();
+    cluster.on_request(move |_, request| match request.mtype {
+        // The second query kills its connection
+        REQUEST_QUERY_SQL if counter.fetch_add(1, Ordering::SeqCst) == 1 => Some(vec![]),
+        REQUEST_QUERY_SQL => Some(vec![rows(&["v"], &[vec![Value::Integer(1)]], false)]),
+        _ => None,
+    });
+    let connector = Arc::new(cluster.connector(Config::new().with_backoff_factor(Duration::from_millis(1))).await);
+    let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let log = events.clone();
+    connector.set_event_listener(Box::new(move |event| {
+        let name = match event {
+            ConnEvent::Connected(_) => "connected".to_string(),
+            ConnEvent::Disconnected(_, err) => format!("disconnected: {}", err.is_transient()),
+            ConnEvent::LeaderChanged { from, to } => format!("leader {:?} -> {}", from, to),
+        };
+        log.lock().unwrap().push(name);
+    }));
+    let db = ReconnectingDatabase::new(connector, "app");
+
+    db.query("SELECT v FROM t", &[]).await.unwrap();
+    db.query("SELECT v FROM t", &[]).await.unwrap();
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        [format!("leader None -> {}", N1), "connected".into(), "disconnected: true".into(), "connected".into()]
+    );
+}
+
+// A protocol over a socket pair with `name` open, and the server end
+async fn opened(name: &str) -> (Arc<Mutex<Protocol>>, u32, Conn) {
+    let (client, mut server) = Conn::from_unix_pair().unwrap();
+    let mut proto = Protocol::new(client, "mock");
+    proto.handshake().await.unwrap();
+    read_version(&mut server).await.unwrap();
+    let open = tokio::spawn(async move {
+        read_request(&mut server).await.unwrap().unwrap();
+        write_messages(&mut server, &[db(3)]).await.unwrap();
+        server
+    });
+    let id = proto.open(name, OpenFlags::default()).await.unwrap();
+    (Arc::new(Mutex::new(proto)), id, open.await.unwrap())
+}
+
+#[tokio::test]
+async fn closing_a_database_removes_it_from_the_open_databases() {
+    let (proto, _, mut server) = opened("app").await;
+    // The server answers the second open too; the database is listed once
+    let answer = tokio::spawn(async move {
+        read_request(&mut server).await.unwrap().unwrap();
+        write_messages(&mut server, &[db(3)]).await.unwrap();
+        server
+    });
+    let app = Database::open(proto.clone(), "app").await.unwrap();
+    let mut server = answer.await.unwrap();
+    assert_eq!(proto.lock().await.open_databases(), ["app"]);
+
+    app.close().await.unwrap();
+
+    assert!(proto.lock().await.open_databases().is_empty());
+    assert!(proto.lock().await.is_broken());
+    // The server sees the connection end, releasing the database
+    assert!(read_request(&mut server).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn only_databases_idle_for_long_enough_are_closed() {
+    let (proto, id, mut server) = opened("app").await;
+    let mut proto = Arc::try_unwrap(proto).ok().unwrap().into_inner();
+
+    assert_eq!(proto.close_idle_databases(Duration::from_millis(100)).await.unwrap(), 0);
+    assert_eq!(proto.open_databases(), ["app"]);
+
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    // A request resets the idle time
+    let answer = tokio::spawn(async move {
+        read_request(&mut server).await.unwrap().unwrap();
+        write_messages(&mut server, &[result(0, 0)]).await.unwrap();
+        server
+    });
+    proto.exec_sql(id, "DELETE FROM t", &[]).await.unwrap();
+    let mut server = answer.await.unwrap();
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert_eq!(proto.close_idle_databases(Duration::from_millis(100)).await.unwrap(), 0);
+
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert_eq!(proto.close_idle_databases(Duration::from_millis(100)).await.unwrap(), 1);
+    assert!(proto.open_databases().is_empty());
+    assert!(read_request(&mut server).await.unwrap().is_none());
+}
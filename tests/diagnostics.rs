@@ -0,0 +1,35 @@
+use dqlite_rs::bindings::diagnostics::{ClusterDiagnostics, DivergenceWarning};
+use dqlite_rs::bindings::server::RaftEntry;
+
+fn entry(index: u64, term: u64) -> RaftEntry {
+    RaftEntry { index, term }
+}
+
+#[test]
+fn a_healthy_cluster_has_no_divergence() {
+    // Followers may lag behind, but agree on the term wherever they overlap
+    let entries = [(1, entry(10, 3)), (2, entry(10, 3)), (3, entry(8, 2)), (4, entry(12, 4))];
+
+    assert!(ClusterDiagnostics::check_divergence(&entries).is_empty());
+    assert!(ClusterDiagnostics::check_divergence(&[]).is_empty());
+}
+
+#[test]
+fn the_same_index_with_different_terms_is_flagged() {
+    let entries = [
+        (3, entry(10, 4)),
+        (1, entry(10, 3)),
+        (2, entry(10, 3)),
+        (4, entry(7, 2)),
+        (6, entry(5, 1)),
+        (5, entry(5, 2)),
+    ];
+
+    assert_eq!(
+        ClusterDiagnostics::check_divergence(&entries),
+        [
+            DivergenceWarning { index: 5, terms: vec![(5, 2), (6, 1)] },
+            DivergenceWarning { index: 10, terms: vec![(1, 3), (2, 3), (3, 4)] },
+        ]
+    );
+}
@@ -0,0 +1,45 @@
+#![cfg(feature = "metrics-hist")]
+
+mod common;
+
+use common::*;
+use dqlite_rs::protocol::config::Config;
+use dqlite_rs::protocol::message::*;
+use std::time::Duration;
+
+// Time is paused, so the mock node's delays are the exact request latencies
+#[tokio::test(start_paused = true)]
+async fn client_latency_percentiles_follow_a_fake_clock() {
+    let cluster = MockCluster::new(&[(1, N1)]);
+    cluster.on_request(|_, request| match request.mtype {
+        REQUEST_QUERY_SQL => Some(vec![rows(&["v"], &[vec![Value::Integer(1)]], false)]),
+        REQUEST_EXEC_SQL => Some(vec![result(0, 1)]),
+        _ => None,
+    });
+    let client = cluster.connector(Config::new()).await;
+    let db = client.open("app").await.unwrap();
+
+    // 90 fast queries and 10 slow ones
+    cluster.set_delay(N1, Duration::from_millis(2));
+    for _ in 0..90 {
+        db.query("SELECT v FROM t", &[]).await.unwrap();
+    }
+    cluster.set_delay(N1, Duration::from_millis(50));
+    for _ in 0..10 {
+        db.query("SELECT v FROM t", &[]).await.unwrap();
+    }
+    db.execute("INSERT INTO t (v) VALUES (1)", &[]).await.unwrap();
+
+    let stats = client.latency_stats();
+    let queries = stats.get(REQUEST_QUERY_SQL).unwrap();
+    assert_eq!(queries.count, 100);
+    // Percentiles are bucket upper bounds, at most twice the true value
+    assert!(queries.p50 >= Duration::from_millis(2) && queries.p50 < Duration::from_millis(4), "{:?}", queries.p50);
+    assert!(queries.p99 >= Duration::from_millis(50) && queries.p99 < Duration::from_millis(100), "{:?}", queries.p99);
+
+    let execs = stats.get(REQUEST_EXEC_SQL).unwrap();
+    assert_eq!(execs.count, 1);
+    assert!(execs.p50 >= Duration::from_millis(50) && execs.p99 < Duration::from_millis(100), "{:?}", execs);
+    // Requests of the connection setup are recorded too
+    assert!(stats.get(REQUEST_OPEN).is_some());
+}
@@ -0,0 +1,432 @@
+// Tests against real in-process dqlite nodes; they need libdqlite at runtime.
+#![cfg(feature = "testkit")]
+
+use dqlite_rs::bindings::optional::Feature;
+use dqlite_rs::bindings::server::{DqliteError, Node, SnapshotPreset};
+use dqlite_rs::protocol::config::Config;
+use dqlite_rs::protocol::connector::Conn;
+use dqlite_rs::protocol::message::Value;
+use dqlite_rs::protocol::protocol::Protocol;
+use dqlite_rs::testkit::TestCluster;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+
+// An empty data directory of its own for every node a test creates
+fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "dqlite-node-test-{}-{}",
+        std::process::id(),
+        NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// A node that hasn't been started, with its data directory
+fn fresh_node(id: u64) -> (Node, PathBuf) {
+    let dir = temp_dir();
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let node = Node::new(id, &address, dir.to_str().unwrap()).unwrap();
+    (node, dir)
+}
+
+#[test]
+fn every_snapshot_preset_applies_to_a_fresh_node() {
+    for preset in [SnapshotPreset::LowMemory, SnapshotPreset::Balanced, SnapshotPreset::HighThroughput] {
+        let (node, dir) = fresh_node(1);
+        node.apply_snapshot_preset(preset).unwrap();
+        drop(node);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[test]
+fn linked_library_version_is_not_zero() {
+    let (major, minor, release) = dqlite_rs::version();
+    assert_ne!((major, minor, release), (0, 0, 0));
+    assert_eq!(dqlite_rs::version_string(), format!("{}.{}.{}", major, minor, release));
+}
+
+#[test]
+fn start_without_a_bind_address_fails_clearly() {
+    let (node, dir) = fresh_node(1);
+    match node.start() {
+        Err(DqliteError::Start(message)) => assert_eq!(message, "no bind address configured"),
+        other => panic!("unexpected start result: {:?}", other),
+    }
+    drop(node);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rows_affected_counts_every_updated_row() {
+    let cluster = TestCluster::single().await.unwrap();
+    let db = cluster.connector().open("test").await.unwrap();
+    db.execute("CREATE TABLE t (v INTEGER)", &[]).await.unwrap();
+    for v in 0..3 {
+        db.execute("INSERT INTO t (v) VALUES (?)", &[Value::Integer(v)]).await.unwrap();
+    }
+
+    let result = db.execute("UPDATE t SET v = v + 1", &[]).await.unwrap();
+
+    assert_eq!(result.rows_affected, 3);
+    assert_eq!(db.changes().await.unwrap(), 3);
+    assert_eq!(db.total_changes().await.unwrap(), 6);
+    drop(db);
+    cluster.shutdown().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ddl_bumps_the_schema_version() {
+    let cluster = TestCluster::single().await.unwrap();
+    let db = cluster.connector().open("schema").await.unwrap();
+    let before = db.schema_version().await.unwrap();
+
+    db.execute("CREATE TABLE t (v INTEGER)", &[]).await.unwrap();
+    let created = db.schema_version().await.unwrap();
+    assert!(created > before, "{} after {}", created, before);
+
+    // Writing rows leaves the schema alone
+    db.execute("INSERT INTO t (v) VALUES (1)", &[]).await.unwrap();
+    assert_eq!(db.schema_version().await.unwrap(), created);
+
+    db.execute("ALTER TABLE t ADD COLUMN w TEXT", &[]).await.unwrap();
+    assert!(db.schema_version().await.unwrap() > created);
+    drop(db);
+    cluster.shutdown().unwrap();
+}
+
+#[test]
+fn library_version_parses_as_a_version() {
+    let version = dqlite_rs::library_version();
+    assert!(!version.is_empty());
+    let parts: Vec<u32> = version.split('.').map(|part| part.parse().unwrap()).collect();
+    assert_eq!(parts.len(), 3);
+    assert_eq!((parts[0], parts[1], parts[2]), dqlite_rs::version());
+}
+
+// Each optional setter works when the linked libdqlite has its symbol, and
+// fails with a clear error otherwise
+#[test]
+fn optional_setters_follow_supports() {
+    let (node, dir) = fresh_node(1);
+    let results = [
+        (Feature::AutoRecovery, node.set_auto_recovery(true)),
+        (Feature::TargetVoters, node.set_target_voters(3)),
+        (Feature::SnapshotCompression, node.set_snapshot_compression(true)),
+    ];
+    for (feature, result) in results {
+        if node.supports(feature) {
+            assert!(result.is_ok(), "{:?}: {:?}", feature, result);
+        } else {
+            match result {
+                Err(DqliteError::Configuration(message)) => assert!(message.contains("unsupported"), "{}", message),
+                other => panic!("{:?}: unexpected result {:?}", feature, other),
+            }
+        }
+    }
+    drop(node);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn describe_last_entry_is_refused_while_the_node_runs() {
+    let (node, dir) = fresh_node(1);
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    node.describe_last_entry().unwrap();
+
+    node.set_bind_address(&address).unwrap();
+    node.start().unwrap();
+    assert!(matches!(node.describe_last_entry(), Err(DqliteError::Configuration(_))));
+
+    node.stop().unwrap();
+    drop(node);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+// Nodes created and dropped from many threads at once, each with a dial func
+// installed (twice, so replacing one is covered too). Every func holds a clone
+// of `funcs`; once all nodes are gone the registry must have let go of them.
+#[test]
+fn dropping_nodes_releases_their_dial_funcs_under_concurrency() {
+    let funcs = Arc::new(());
+    std::thread::scope(|s| {
+        for thread in 0..8u64 {
+            let funcs = funcs.clone();
+            s.spawn(move || {
+                for i in 0..25 {
+                    let (node, dir) = fresh_node(thread * 100 + i + 1);
+                    for _ in 0..2 {
+                        let held = funcs.clone();
+                        node.set_dial_func(move |_addr: &str| {
+                            let _ = &held;
+                            async move { Err::<Conn, String>("unreachable".to_string()) }
+                        })
+                        .unwrap();
+                    }
+                    drop(node);
+                    std::fs::remove_dir_all(dir).unwrap();
+                }
+            });
+        }
+    });
+    assert_eq!(Arc::strong_count(&funcs), 1);
+}
+
+#[test]
+fn unix_bind_socket_gets_the_requested_mode() {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let (node, dir) = fresh_node(1);
+    let socket = dir.join("node.sock");
+    node.set_bind_unix(socket.to_str().unwrap(), 0o600).unwrap();
+    node.start().unwrap();
+
+    let metadata = std::fs::metadata(&socket).unwrap();
+    assert!(metadata.file_type().is_socket());
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+    node.stop().unwrap();
+    drop(node);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn abstract_unix_bind_ignores_the_mode() {
+    let (node, dir) = fresh_node(1);
+    let name = format!("@dqlite-node-test-{}", std::process::id());
+    node.set_bind_unix(&name, 0o600).unwrap();
+    node.start().unwrap();
+    assert_eq!(node.get_bind_address().unwrap(), name);
+
+    node.stop().unwrap();
+    drop(node);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn new_with_layout_creates_a_private_directory_per_node() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let base = temp_dir().join("nodes");
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let node = Node::new_with_layout(7, &address, base.to_str().unwrap()).unwrap();
+
+    let dir = base.join("7");
+    assert!(dir.is_dir());
+    assert_eq!(std::fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+    // The node initializes in it
+    node.set_bind_address(&address).unwrap();
+    node.start().unwrap();
+    node.stop().unwrap();
+    drop(node);
+    assert!(std::fs::read_dir(&dir).unwrap().next().is_some());
+
+    // An existing directory is reused as is
+    drop(Node::new_with_layout(7, &address, base.to_str().unwrap()).unwrap());
+    std::fs::remove_dir_all(base.parent().unwrap()).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bind_address_is_stable_and_owned_while_the_node_runs() {
+    let cluster = TestCluster::single().await.unwrap();
+    let first = cluster.node().get_bind_address().unwrap();
+    assert_eq!(first, cluster.address());
+
+    for _ in 0..100 {
+        let again = cluster.node().get_bind_address().unwrap();
+        assert_eq!(again, first);
+    }
+    // A copy outlives the node it came from
+    cluster.shutdown().unwrap();
+    assert!(first.starts_with("127.0.0.1:"));
+}
+
+// libdqlite seeds generate_id with the clock, so two calls for one address
+// don't agree; the id only stays the same across restarts when the caller
+// persists it and hands it back to `new`
+#[test]
+fn from_address_id_is_reproducible_through_new() {
+    let dir = temp_dir();
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+
+    let first = Node::from_address(&address, dir.to_str().unwrap()).unwrap();
+    let id = first.id();
+    assert_ne!(id, 0);
+    drop(first);
+
+    let second = Node::new(id, &address, dir.to_str().unwrap()).unwrap();
+    assert_eq!(second.id(), id);
+    let other_dir = temp_dir();
+    let other = Node::from_address(&address, other_dir.to_str().unwrap()).unwrap();
+    assert_ne!(other.id(), 0);
+
+    drop((second, other));
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::remove_dir_all(other_dir).unwrap();
+}
+
+#[test]
+fn a_second_node_binds_the_address_right_after_stop_and_join() {
+    let (node, dir) = fresh_node(1);
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    node.set_bind_address(&address).unwrap();
+    node.start().unwrap();
+
+    node.stop_and_join(Duration::from_secs(5)).unwrap();
+
+    let next_dir = temp_dir();
+    let next = Node::new(2, &address, next_dir.to_str().unwrap()).unwrap();
+    next.set_bind_address(&address).unwrap();
+    next.start().unwrap();
+    assert_eq!(next.get_bind_address().unwrap(), address);
+
+    next.stop().unwrap();
+    drop((node, next));
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::remove_dir_all(next_dir).unwrap();
+}
+
+#[test]
+fn recover_from_yaml_needs_exactly_one_voter() {
+    let (node, dir) = fresh_node(1);
+    let valid = dir.join("valid.yaml");
+    let survivor = "- ID: 1\n  Address: 127.0.0.1:9001\n  Role: 0\n";
+    std::fs::write(&valid, format!("{}- ID: 2\n  Address: 127.0.0.1:9002\n  Role: 2\n", survivor)).unwrap();
+    let invalid = dir.join("no-voter.yaml");
+    std::fs::write(&invalid, "- ID: 1\n  Address: 127.0.0.1:9001\n  Role: 2\n").unwrap();
+
+    match node.recover_from_yaml(&invalid) {
+        Err(DqliteError::Configuration(message)) => assert!(message.contains("0 voters"), "{}", message),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    node.recover_from_yaml(&valid).unwrap();
+
+    drop(node);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_cluster_runs_create_insert_and_select() {
+    let cluster = TestCluster::single().await.unwrap();
+    let db = cluster.connector().open("smoke").await.unwrap();
+
+    db.execute("CREATE TABLE t (n INTEGER, s TEXT)", &[]).await.unwrap();
+    db.execute("INSERT INTO t (n, s) VALUES (?, ?)", &[Value::Integer(1), Value::Text("one".into())])
+        .await
+        .unwrap();
+    let rows = db.query("SELECT n, s FROM t", &[]).await.unwrap();
+
+    assert_eq!(rows.columns(), ["n", "s"]);
+    let values: Vec<_> = rows.iter().map(|row| row.values().to_vec()).collect();
+    assert_eq!(values, [vec![Value::Integer(1), Value::Text("one".into())]]);
+    drop(db);
+    cluster.shutdown().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn checkpoint_succeeds_after_some_writes() {
+    let cluster = TestCluster::single().await.unwrap();
+    let db = cluster.connector().open("wal").await.unwrap();
+    db.execute("CREATE TABLE t (v INTEGER)", &[]).await.unwrap();
+    for v in 0..20 {
+        db.execute("INSERT INTO t (v) VALUES (?)", &[Value::Integer(v)]).await.unwrap();
+    }
+
+    let checkpoint = cluster.node().checkpoint("wal").await.unwrap();
+
+    assert!(!checkpoint.busy);
+    assert!(checkpoint.checkpointed_frames <= checkpoint.log_frames);
+    // The rows are all still there
+    assert_eq!(db.query("SELECT v FROM t", &[]).await.unwrap().len(), 20);
+    drop(db);
+    cluster.shutdown().unwrap();
+}
+
+// A client connection closed by the node side leaves TIME_WAIT entries on the
+// listening port; libuv's SO_REUSEADDR lets the restarted node bind it anyway
+#[tokio::test(flavor = "multi_thread")]
+async fn a_restarted_node_rebinds_its_address_despite_time_wait() {
+    let dir = temp_dir();
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let start = || {
+        let node = Node::new(1, &address, dir.to_str().unwrap()).unwrap();
+        node.set_bind_address(&address).unwrap();
+        node.start().unwrap();
+        node
+    };
+
+    let node = start();
+    let conn = tokio::net::TcpStream::connect(&address).await.unwrap();
+    node.stop().unwrap();
+    drop((node, conn));
+
+    let node = start();
+    assert_eq!(node.get_bind_address().unwrap(), address);
+    tokio::net::TcpStream::connect(&address).await.unwrap();
+    node.stop().unwrap();
+    drop(node);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_connection_handed_to_handle_conn_runs_a_query() {
+    let cluster = TestCluster::single().await.unwrap();
+    // As if accepted by the application's own listener
+    let (client, accepted) = Conn::from_unix_pair().unwrap();
+    let relay = tokio::spawn(cluster.node().handle_conn(accepted).unwrap());
+
+    let (mut proto, db_id) = Protocol::establish(client, 1, "handed", &Config::new()).await.unwrap();
+    proto.exec_sql(db_id, "CREATE TABLE t (v INTEGER)", &[]).await.unwrap();
+    proto.exec_sql(db_id, "INSERT INTO t (v) VALUES (7)", &[]).await.unwrap();
+    let rows = proto.query_sql(db_id, "SELECT v FROM t", &[]).await.unwrap();
+
+    assert_eq!(rows.iter().next().unwrap().values(), [Value::Integer(7)]);
+    // Closing the client ends the relay
+    proto.close().await.unwrap();
+    relay.await.unwrap().unwrap();
+    cluster.shutdown().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn raft_stats_report_the_last_entry_of_a_stopped_node() {
+    let cluster = TestCluster::single().await.unwrap();
+    let db = cluster.connector().open("stats").await.unwrap();
+    db.execute("CREATE TABLE t (v INTEGER)", &[]).await.unwrap();
+    db.execute("INSERT INTO t (v) VALUES (1)", &[]).await.unwrap();
+    drop(db);
+    assert!(matches!(cluster.node().raft_stats(), Err(DqliteError::Configuration(_))));
+
+    cluster.node().stop().unwrap();
+    let stats = cluster.node().raft_stats().unwrap();
+
+    // Bootstrap, barrier and the two writes at least
+    assert!(stats.last_log.index >= 3, "{:?}", stats);
+    assert!(stats.last_log.term >= 1);
+    // libdqlite doesn't report these
+    assert_eq!((stats.term, stats.commit_index, stats.last_applied), (None, None, None));
+    cluster.shutdown().unwrap();
+}
+
+#[test]
+fn a_second_node_on_a_taken_bind_address_gets_address_in_use() {
+    let (first, first_dir) = fresh_node(1);
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    first.set_bind_address(&address).unwrap();
+    first.start().unwrap();
+
+    let (second, second_dir) = fresh_node(2);
+    // libdqlite binds in set_bind_address and listens in start; either may
+    // be the call that notices
+    let err = second.set_bind_address(&address).and_then(|_| second.start()).unwrap_err();
+
+    assert!(matches!(err, DqliteError::AddressInUse(_)), "{}", err);
+    first.stop().unwrap();
+    drop((first, second));
+    std::fs::remove_dir_all(first_dir).unwrap();
+    std::fs::remove_dir_all(second_dir).unwrap();
+}
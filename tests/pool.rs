@@ -0,0 +1,96 @@
+mod common;
+
+use common::*;
+use dqlite_rs::protocol::config::Config;
+use dqlite_rs::protocol::connector::{Conn, Connector, DialFunc};
+use dqlite_rs::protocol::pool::Pool;
+use dqlite_rs::protocol::protocol::ProtocolError;
+use dqlite_rs::protocol::store::InMemoryNodeStore;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Dials `mock` through a relay that counts the connections whose client end
+// went away: the relay only finishes once the client side reads as closed
+fn counting_dial(mock: &MockCluster, closed: Arc<AtomicUsize>) -> DialFunc {
+    let inner = mock.dial_func();
+    Arc::new(move |addr: &str| {
+        let (inner, closed, addr) = (inner.clone(), closed.clone(), addr.to_string());
+        Box::pin(async move {
+            let mut upstream = inner(&addr).await?;
+            let (client, mut relay) = Conn::from_unix_pair().map_err(|e| e.to_string())?;
+            tokio::spawn(async move {
+                let _ = tokio::io::copy_bidirectional(&mut relay, &mut upstream).await;
+                closed.fetch_add(1, Ordering::SeqCst);
+            });
+            Ok(client)
+        })
+    })
+}
+
+async fn pool(mock: &MockCluster, closed: &Arc<AtomicUsize>) -> Pool<InMemoryNodeStore> {
+    let connector = Connector::builder()
+        .store(mock.store().await)
+        .config(Config::new().with_dial(counting_dial(mock, closed.clone())))
+        .build()
+        .unwrap();
+    Pool::new(connector, 4)
+}
+
+#[tokio::test]
+async fn idle_connections_are_reused() {
+    let mock = MockCluster::new(&[(1, N1)]);
+    let pool = pool(&mock, &Arc::new(AtomicUsize::new(0))).await;
+
+    drop(pool.acquire().await.unwrap());
+    let dials = mock.dials().len();
+    assert_eq!(pool.idle(), 1);
+    let proto = pool.acquire().await.unwrap();
+
+    assert_eq!(pool.idle(), 0);
+    assert_eq!(mock.dials().len(), dials);
+    drop(proto);
+}
+
+#[tokio::test]
+async fn after_close_acquire_fails_and_every_connection_is_closed() {
+    let mock = MockCluster::new(&[(1, N1)]);
+    let closed = Arc::new(AtomicUsize::new(0));
+    let pool = pool(&mock, &closed).await;
+    let idle = pool.acquire().await.unwrap();
+    let busy = pool.acquire().await.unwrap();
+    drop(idle);
+
+    // close waits for the busy connection to come back
+    let returning = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(busy);
+    });
+    pool.clone().close().await.unwrap();
+    returning.await.unwrap();
+
+    assert!(matches!(pool.acquire().await, Err(ProtocolError::PoolClosed)));
+    assert_eq!(pool.idle(), 0);
+    tokio::time::timeout(Duration::from_secs(1), async {
+        while closed.load(Ordering::SeqCst) < mock.dials().len() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .expect("every dialed connection closes");
+}
+
+#[tokio::test]
+async fn close_gives_up_on_a_connection_kept_past_the_timeout() {
+    let mock = MockCluster::new(&[(1, N1)]);
+    let pool = pool(&mock, &Arc::new(AtomicUsize::new(0))).await.with_close_timeout(Duration::from_millis(50));
+    let kept = pool.acquire().await.unwrap();
+
+    let result = pool.clone().close().await;
+
+    assert!(matches!(result, Err(ProtocolError::DeadlineExceeded)));
+    assert!(matches!(pool.acquire().await, Err(ProtocolError::PoolClosed)));
+    // Coming back late, it is dropped rather than pooled
+    drop(kept);
+    assert_eq!(pool.idle(), 0);
+}
@@ -0,0 +1,721 @@
+mod common;
+
+use common::*;
+use dqlite_rs::protocol::config::Config;
+use dqlite_rs::protocol::connector::{Conn, Connector};
+use dqlite_rs::protocol::message::*;
+use dqlite_rs::protocol::protocol::{Protocol, ProtocolError, SqliteCode};
+use dqlite_rs::protocol::store::{NodeInfo, NodeRole, NodeStore};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinHandle;
+
+#[tokio::test]
+async fn query_sql_reads_rows_of_a_returning_statement() {
+    let (mut proto, server) = connected(|request| {
+        assert_eq!(request.mtype, REQUEST_QUERY_SQL);
+        assert_eq!(request.get_u64().unwrap(), 7);
+        assert_eq!(request.get_text().unwrap(), "INSERT INTO t (v) VALUES (?) RETURNING id, v");
+        vec![rows(&["id", "v"], &[vec![Value::Integer(1), Value::Text("a".into())]], false)]
+    })
+    .await;
+
+    let rows = proto
+        .query_sql(7, "INSERT INTO t (v) VALUES (?) RETURNING id, v", &[Value::Text("a".into())])
+        .await
+        .unwrap();
+    assert_eq!(rows.columns(), ["id", "v"]);
+    assert_eq!(rows.iter().next().unwrap().values(), [Value::Integer(1), Value::Text("a".into())]);
+
+    drop(proto);
+    assert_eq!(server.await.unwrap().1, [REQUEST_QUERY_SQL]);
+}
+
+// Answers `n` exec requests, checking that none arrives before the previous
+// one was answered. Returns the version the client sent.
+fn serve_one_at_a_time(mut conn: Conn, n: usize, mtype: u8) -> JoinHandle<u64> {
+    tokio::spawn(async move {
+        let version = read_version(&mut conn).await.unwrap();
+        for i in 0..n {
+            let request = read_request(&mut conn).await.unwrap().unwrap();
+            assert_eq!(request.mtype, mtype);
+            assert!(request_within(&mut conn, Duration::from_millis(50)).await.is_none());
+            write_messages(&mut conn, &[result(i as u64, 1)]).await.unwrap();
+        }
+        version
+    })
+}
+
+#[tokio::test]
+async fn connector_falls_back_to_the_legacy_version() {
+    let cluster = MockCluster::new(&[(1, N1)]);
+    cluster.set_legacy(N1);
+    let connector = cluster.connector(Config::new()).await;
+
+    let proto = connector.connect().await.unwrap();
+    assert_eq!(proto.version(), VERSION_LEGACY);
+    // One dial hung up on VERSION_ONE, the next one offered VERSION_LEGACY
+    assert_eq!(cluster.dials(), [N1, N1]);
+}
+
+#[tokio::test]
+async fn exec_many_sends_one_request_at_a_time_on_legacy_servers() {
+    let (client, server) = Conn::from_unix_pair().unwrap();
+    let server = serve_one_at_a_time(server, 3, REQUEST_EXEC);
+    let mut proto = Protocol::with_version(client, "mock", VERSION_LEGACY);
+    proto.handshake().await.unwrap();
+
+    let rows: Vec<_> = (0..3).map(|i| vec![Value::Integer(i)]).collect();
+    let results = proto.exec_many(0, 1, &rows).await.unwrap();
+
+    assert_eq!(results.iter().map(|r| r.last_insert_id).collect::<Vec<_>>(), [0, 1, 2]);
+    assert_eq!(server.await.unwrap(), VERSION_LEGACY);
+}
+
+#[tokio::test]
+async fn exec_sql_many_sends_one_request_at_a_time_on_legacy_servers() {
+    let (client, server) = Conn::from_unix_pair().unwrap();
+    let server = serve_one_at_a_time(server, 3, REQUEST_EXEC_SQL);
+    let mut proto = Protocol::with_version(client, "mock", VERSION_LEGACY);
+    proto.handshake().await.unwrap();
+
+    let statements: Vec<_> = (0..3).map(|i| ("INSERT INTO t VALUES (?)".to_string(), vec![Value::Integer(i)])).collect();
+    let results = proto.exec_sql_many(0, &statements).await.unwrap();
+
+    assert_eq!(results.iter().map(|r| r.last_insert_id).collect::<Vec<_>>(), [0, 1, 2]);
+    assert_eq!(server.await.unwrap(), VERSION_LEGACY);
+}
+
+#[tokio::test]
+async fn exec_many_sends_1000_rows_before_reading_a_reply() {
+    let (client, mut server) = Conn::from_unix_pair().unwrap();
+    let server = tokio::spawn(async move {
+        assert_eq!(read_version(&mut server).await.unwrap(), VERSION_ONE);
+        // Nothing is answered until every request is in
+        for _ in 0..1000 {
+            let request = request_within(&mut server, Duration::from_secs(1)).await.unwrap();
+            assert_eq!(request.mtype, REQUEST_EXEC);
+        }
+        let replies: Vec<_> = (0..1000).map(|i| result(i, 1)).collect();
+        write_messages(&mut server, &replies).await.unwrap();
+    });
+    let mut proto = Protocol::new(client, "mock");
+    proto.handshake().await.unwrap();
+
+    let rows: Vec<_> = (0..1000).map(|i| vec![Value::Integer(i), Value::Text(format!("row {}", i))]).collect();
+    let results = proto.exec_many(0, 1, &rows).await.unwrap();
+
+    assert_eq!(results.len(), 1000);
+    assert_eq!(results[999].last_insert_id, 999);
+    server.await.unwrap();
+}
+
+#[test]
+fn raw_codes_map_to_named_sqlite_codes() {
+    let unique = SqliteCode::from_raw(2067);
+    assert_eq!(unique, SqliteCode::CONSTRAINT_UNIQUE);
+    assert_eq!(unique.primary(), SqliteCode::CONSTRAINT);
+    assert_eq!(unique.name(), Some("SQLITE_CONSTRAINT_UNIQUE"));
+    assert!(unique.is_constraint() && !unique.is_busy());
+
+    let snapshot = SqliteCode::from_raw(517);
+    assert_eq!(snapshot, SqliteCode::BUSY_SNAPSHOT);
+    assert!(snapshot.is_busy());
+
+    assert!(SqliteCode::from_raw(6).is_busy());
+    assert!(SqliteCode::from_raw(1032).is_readonly());
+    assert_eq!(SqliteCode::from_raw(1032).name(), Some("SQLITE_READONLY_DBMOVED"));
+    assert!(SqliteCode::from_raw(SQLITE_IOERR_NOT_LEADER).is_not_leader());
+    // An extended code without a name of its own falls back to its primary one
+    assert_eq!(SqliteCode::from_raw(19 | (99 << 8)).name(), Some("SQLITE_CONSTRAINT"));
+}
+
+#[tokio::test]
+async fn failure_responses_carry_the_extended_code() {
+    let (mut proto, _server) = connected(|_| vec![failure(2067, "UNIQUE constraint failed: t.k")]).await;
+
+    match proto.exec_sql(0, "INSERT INTO t (k) VALUES (1)", &[]).await {
+        Err(ProtocolError::Failure { code, message }) => {
+            assert_eq!(code, SqliteCode::CONSTRAINT_UNIQUE);
+            assert_eq!(message, "UNIQUE constraint failed: t.k");
+        }
+        _ => panic!("expected a failure"),
+    }
+}
+
+#[tokio::test]
+async fn welcome_and_heartbeat_replies_are_retained() {
+    let (mut proto, _server) = connected(|request| match request.mtype {
+        REQUEST_CLIENT => vec![welcome(15_000)],
+        REQUEST_HEARTBEAT => vec![servers(&[(1, N1), (2, N2)])],
+        other => panic!("unexpected request {}", other),
+    })
+    .await;
+    assert_eq!(proto.heartbeat_timeout(), None);
+    assert!(proto.servers().is_empty());
+
+    proto.register_client(42).await.unwrap();
+    proto.heartbeat().await.unwrap();
+
+    assert_eq!(proto.heartbeat_timeout(), Some(Duration::from_secs(15)));
+    let servers: Vec<_> = proto.servers().iter().map(|n| (n.id, n.addr.as_str())).collect();
+    assert_eq!(servers, [(1, N1), (2, N2)]);
+}
+
+#[tokio::test]
+async fn connector_feeds_heartbeat_servers_into_the_store() {
+    let cluster = MockCluster::new(&[(1, N1)]);
+    cluster.on_request(|_, request| match request.mtype {
+        REQUEST_HEARTBEAT => Some(vec![servers(&[(1, N1), (2, N2)])]),
+        _ => None,
+    });
+    let store = cluster.store().await;
+    let connector = Connector::builder()
+        .store(store.clone())
+        .config(Config::new().with_dial(cluster.dial_func()))
+        .build()
+        .unwrap();
+    let mut proto = connector.connect().await.unwrap();
+
+    proto.heartbeat().await.unwrap();
+    connector.sync_servers(&proto).await.unwrap();
+
+    let stored: Vec<_> = store.get_all().await.unwrap().into_iter().map(|n| n.id).collect();
+    assert_eq!(stored, [1, 2]);
+}
+
+#[tokio::test]
+async fn syncing_heartbeat_servers_keeps_stored_roles_and_domains() {
+    let cluster = MockCluster::new(&[(1, N1), (2, N2)]);
+    cluster.on_request(|_, request| match request.mtype {
+        REQUEST_HEARTBEAT => Some(vec![servers(&[(1, N1), (2, "10.0.0.9:9001"), (3, N3)])]),
+        _ => None,
+    });
+    let store = cluster.store().await;
+    let mut nodes = store.get_all().await.unwrap();
+    nodes[1].role = NodeRole::STAND_BY;
+    nodes[1].failure_domain = Some(3);
+    store.set_all(nodes).await.unwrap();
+    let connector = Connector::builder()
+        .store(store.clone())
+        .config(Config::new().with_dial(cluster.dial_func()))
+        .build()
+        .unwrap();
+    let mut proto = connector.connect().await.unwrap();
+
+    proto.heartbeat().await.unwrap();
+    connector.sync_servers(&proto).await.unwrap();
+
+    let stored = store.get_all().await.unwrap();
+    let moved = NodeInfo {
+        id: 2,
+        addr: "10.0.0.9:9001".to_string(),
+        role: NodeRole::STAND_BY,
+        failure_domain: Some(3),
+    };
+    assert_eq!(stored[1], moved);
+    assert_eq!((stored[2].id, stored[2].role), (3, NodeRole::VOTER));
+}
+
+#[tokio::test]
+async fn db_reply_is_decoded_and_its_reserved_word_checked() {
+    let (mut proto, _server) = connected(|_| vec![db(7)]).await;
+    assert_eq!(proto.open("app", OpenFlags::default()).await.unwrap(), 7);
+
+    let (mut proto, _server) = connected(|_| {
+        let mut reply = Message::new(RESPONSE_DB);
+        reply.put_u32(7);
+        reply.put_u32(0xdead);
+        vec![reply]
+    })
+    .await;
+    match proto.open("app", OpenFlags::default()).await {
+        Err(ProtocolError::Protocol(message)) => assert!(message.contains("0xdead"), "{}", message),
+        _ => panic!("expected a protocol error"),
+    }
+}
+
+#[tokio::test]
+async fn oversized_sql_is_rejected_before_anything_is_sent() {
+    let (mut proto, server) = connected(|request| panic!("request {} reached the server", request.mtype)).await;
+    // One byte over SQLite's default SQLITE_MAX_SQL_LENGTH. Zeroed memory is
+    // cheap to allocate; the length is checked before the content.
+    let sql = String::from_utf8(vec![0u8; 1_000_000_001]).unwrap();
+
+    match proto.exec_sql(0, &sql, &[]).await {
+        Err(ProtocolError::RequestTooLarge(message)) => assert!(message.starts_with("SQL is"), "{}", message),
+        Err(other) => panic!("unexpected error {:?}", other),
+        Ok(_) => panic!("oversized SQL was accepted"),
+    }
+    match proto.open(&"d".repeat(512), OpenFlags::default()).await {
+        Err(ProtocolError::RequestTooLarge(_)) => {}
+        other => panic!("unexpected open result {:?}", other.map(|_| ())),
+    }
+    assert!(!proto.is_broken());
+
+    drop(proto);
+    assert!(server.await.unwrap().1.is_empty());
+}
+
+#[tokio::test]
+async fn a_blob_over_the_inline_limit_is_rejected_before_encoding() {
+    let (mut proto, server) = connected(|request| match request.mtype {
+        REQUEST_EXEC_SQL => vec![result(1, 1)],
+        other => panic!("unexpected request {}", other),
+    })
+    .await;
+    // One byte over SQLite's default SQLITE_MAX_LENGTH, as zeroed memory
+    // that is never touched
+    let blob = Value::Blob(vec![0u8; 1_000_000_001]);
+
+    match proto.exec_sql(0, "INSERT INTO t VALUES (?, ?)", &[Value::Integer(1), blob]).await {
+        Err(ProtocolError::RequestTooLarge(message)) => assert!(message.starts_with("parameter 2 is"), "{}", message),
+        Err(other) => panic!("unexpected error {:?}", other),
+        Ok(_) => panic!("oversized blob was accepted"),
+    }
+
+    // Nothing was sent, so the connection is still in sync
+    let small = Value::Blob(vec![0u8; 1024]);
+    assert_eq!(proto.exec_sql(0, "INSERT INTO t VALUES (?)", &[small]).await.unwrap().rows_affected, 1);
+    drop(proto);
+    assert_eq!(server.await.unwrap().1, [REQUEST_EXEC_SQL]);
+}
+
+// Request types seen by `recording_namer`; SpanNamer is a plain fn, so it
+// can only report through a static
+static NAMED: std::sync::Mutex<Vec<RequestType>> = std::sync::Mutex::new(Vec::new());
+
+fn recording_namer(request_type: &RequestType) -> String {
+    NAMED.lock().unwrap().push(*request_type);
+    format!("test.{}", request_type)
+}
+
+#[tokio::test]
+async fn a_custom_span_namer_is_invoked_for_each_exec() {
+    let cluster = MockCluster::new(&[(1, N1)]);
+    cluster.on_request(|_, request| match request.mtype {
+        REQUEST_PREPARE => Some(vec![stmt(0, 1, 1)]),
+        REQUEST_EXEC | REQUEST_EXEC_SQL => Some(vec![result(1, 1)]),
+        _ => None,
+    });
+    let connector = cluster.connector(Config::new().with_span_namer(recording_namer)).await;
+    let db = connector.open("app").await.unwrap();
+
+    NAMED.lock().unwrap().clear();
+    db.execute("INSERT INTO t (v) VALUES (1)", &[]).await.unwrap();
+    assert_eq!(*NAMED.lock().unwrap(), [RequestType(REQUEST_EXEC_SQL)]);
+
+    let insert = db.prepare("INSERT INTO t (v) VALUES (?)").await.unwrap();
+    NAMED.lock().unwrap().clear();
+    insert.exec(&[Value::Integer(2)]).await.unwrap();
+    assert_eq!(*NAMED.lock().unwrap(), [RequestType(REQUEST_EXEC)]);
+    assert_eq!(recording_namer(&RequestType(REQUEST_EXEC)), "test.exec");
+}
+
+// Like `connected`, for a protocol that negotiated VERSION_LEGACY
+async fn connected_legacy<F>(handler: F) -> (Protocol, JoinHandle<(u64, Vec<u8>)>)
+where
+    F: FnMut(&mut Message) -> Vec<Message> + Send + 'static,
+{
+    let (client, server) = Conn::from_unix_pair().unwrap();
+    let task = serve(server, handler);
+    let mut proto = Protocol::with_version(client, "mock", VERSION_LEGACY);
+    proto.handshake().await.unwrap();
+    (proto, task)
+}
+
+#[tokio::test]
+async fn set_weight_sends_the_id_and_weight_on_version_one() {
+    let (mut proto, server) = connected(|request| {
+        assert_eq!(request.mtype, REQUEST_WEIGHT);
+        assert_eq!(request.get_u64().unwrap(), 2);
+        assert_eq!(request.get_u64().unwrap(), 10);
+        vec![empty()]
+    })
+    .await;
+
+    proto.set_weight(2, 10).await.unwrap();
+
+    drop(proto);
+    assert_eq!(server.await.unwrap(), (VERSION_ONE, vec![REQUEST_WEIGHT]));
+}
+
+#[tokio::test]
+async fn set_weight_is_unsupported_on_legacy_servers() {
+    let (mut proto, server) = connected_legacy(|request| panic!("unexpected request {}", request.mtype)).await;
+
+    assert!(matches!(proto.set_weight(2, 10).await, Err(ProtocolError::Unsupported(_))));
+
+    // Nothing went on the wire
+    drop(proto);
+    assert_eq!(server.await.unwrap(), (VERSION_LEGACY, vec![]));
+}
+
+#[tokio::test]
+async fn describe_decodes_the_node_metadata_on_version_one() {
+    let (mut proto, server) = connected(|request| {
+        assert_eq!(request.mtype, REQUEST_DESCRIBE);
+        assert_eq!(request.get_u64().unwrap(), 0);
+        vec![metadata(3, 7)]
+    })
+    .await;
+
+    let described = proto.describe().await.unwrap();
+
+    assert_eq!((described.failure_domain, described.weight), (3, 7));
+    drop(proto);
+    assert_eq!(server.await.unwrap(), (VERSION_ONE, vec![REQUEST_DESCRIBE]));
+}
+
+#[tokio::test]
+async fn describe_is_unsupported_on_legacy_servers() {
+    let (mut proto, server) = connected_legacy(|request| panic!("unexpected request {}", request.mtype)).await;
+
+    assert!(matches!(proto.describe().await, Err(ProtocolError::Unsupported(_))));
+
+    // Nothing went on the wire, and the connection is still usable
+    assert!(!proto.is_broken());
+    drop(proto);
+    assert_eq!(server.await.unwrap(), (VERSION_LEGACY, vec![]));
+}
+
+#[tokio::test]
+async fn establish_registers_the_client_and_opens_the_database() {
+    let (client, server) = Conn::from_unix_pair().unwrap();
+    let server = serve(server, |request| match request.mtype {
+        REQUEST_CLIENT => {
+            assert_eq!(request.get_u64().unwrap(), 42);
+            vec![welcome(15_000)]
+        }
+        REQUEST_OPEN => {
+            assert_eq!(request.get_text().unwrap(), "app");
+            vec![db(5)]
+        }
+        other => panic!("unexpected request {}", other),
+    });
+
+    let (proto, db_id) = Protocol::establish(client, 42, "app", &Config::new()).await.unwrap();
+
+    assert_eq!(db_id, 5);
+    assert_eq!(proto.heartbeat_timeout(), Some(Duration::from_millis(15_000)));
+    drop(proto);
+    assert_eq!(server.await.unwrap(), (VERSION_ONE, vec![REQUEST_CLIENT, REQUEST_OPEN]));
+}
+
+#[tokio::test]
+async fn establish_gives_up_after_the_attempt_timeout() {
+    let (client, mut server) = Conn::from_unix_pair().unwrap();
+    // Registers the client, then never answers the open
+    let server = tokio::spawn(async move {
+        read_version(&mut server).await.unwrap();
+        read_request(&mut server).await.unwrap().unwrap();
+        write_messages(&mut server, &[welcome(15_000)]).await.unwrap();
+        read_request(&mut server).await.unwrap().unwrap();
+        server
+    });
+
+    let config = Config::new().with_attempt_timeout(Duration::from_millis(100));
+    let result = Protocol::establish(client, 42, "app", &config).await;
+
+    assert!(matches!(result, Err(ProtocolError::DeadlineExceeded)));
+    server.await.unwrap();
+}
+
+// Answers a first query with a batch that has more to come and a second one
+// with a single batch; an interrupt flushes one in-flight batch, then is acked
+fn streaming_server(request: &mut Message, queries: &mut usize) -> Vec<Message> {
+    let batch = |n: i64, more| rows(&["n"], &[vec![Value::Integer(n)], vec![Value::Integer(n + 1)]], more);
+    match request.mtype {
+        REQUEST_QUERY_SQL => {
+            *queries += 1;
+            vec![batch(0, *queries == 1)]
+        }
+        REQUEST_INTERRUPT => {
+            assert_eq!(request.get_u64().unwrap(), 3);
+            vec![batch(2, true), empty()]
+        }
+        other => panic!("unexpected request {}", other),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dropping_a_partly_read_stream_interrupts_the_query() {
+    let mut queries = 0;
+    let (mut proto, server) = connected(move |request| streaming_server(request, &mut queries)).await;
+
+    let mut stream = proto.query_sql_stream(3, "SELECT n FROM t", &[]).await.unwrap();
+    assert_eq!(stream.next().await.unwrap().unwrap().values(), [Value::Integer(0)]);
+    drop(stream);
+
+    // The interrupt was acknowledged, so the connection is still in sync
+    assert!(!proto.is_broken());
+    assert_eq!(proto.query_sql(3, "SELECT n FROM t", &[]).await.unwrap().len(), 2);
+    drop(proto);
+    assert_eq!(server.await.unwrap().1, [REQUEST_QUERY_SQL, REQUEST_INTERRUPT, REQUEST_QUERY_SQL]);
+}
+
+#[tokio::test]
+async fn dropping_a_partly_read_stream_on_a_current_thread_runtime_breaks_the_connection() {
+    let mut queries = 0;
+    let (mut proto, server) = connected(move |request| streaming_server(request, &mut queries)).await;
+
+    let mut stream = proto.query_sql_stream(3, "SELECT n FROM t", &[]).await.unwrap();
+    stream.next().await.unwrap().unwrap();
+    drop(stream);
+
+    // Blocking on the interrupt would stall the only worker
+    assert!(proto.is_broken());
+    assert!(proto.query_sql(3, "SELECT n FROM t", &[]).await.is_err());
+    drop(proto);
+    assert_eq!(server.await.unwrap().1, [REQUEST_QUERY_SQL]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_stream_read_to_the_end_sends_no_interrupt() {
+    let mut queries = 1;
+    let (mut proto, server) = connected(move |request| streaming_server(request, &mut queries)).await;
+
+    let mut stream = proto.query_sql_stream(3, "SELECT n FROM t", &[]).await.unwrap();
+    while let Some(row) = stream.next().await {
+        row.unwrap();
+    }
+    drop(stream);
+
+    assert!(!proto.is_broken());
+    drop(proto);
+    assert_eq!(server.await.unwrap().1, [REQUEST_QUERY_SQL]);
+}
+
+#[tokio::test]
+async fn default_open_flags_encode_as_main_db_create_readwrite() {
+    // SQLITE_OPEN_MAIN_DB | SQLITE_OPEN_CREATE | SQLITE_OPEN_READWRITE
+    assert_eq!(OpenFlags::default().bits(), 0x100 | 0x4 | 0x2);
+    assert!(OpenFlags::default().validate().is_ok());
+
+    let (mut proto, server) = connected(|request| {
+        assert_eq!(request.get_text().unwrap(), "app");
+        assert_eq!(request.get_u64().unwrap(), 0x106);
+        vec![db(1)]
+    })
+    .await;
+
+    proto.open("app", OpenFlags::default()).await.unwrap();
+
+    drop(proto);
+    assert_eq!(server.await.unwrap().1, [REQUEST_OPEN]);
+}
+
+#[tokio::test]
+async fn a_paused_stream_leaves_the_next_batch_on_the_wire() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let (client, mut server) = Conn::from_unix_pair().unwrap();
+    let mut frames = Vec::new();
+    rows(&["v"], &[vec![Value::Integer(1)]], true).encode_into(&mut frames);
+    rows(&["v"], &[vec![Value::Blob(vec![7; 4 << 20])]], false).encode_into(&mut frames);
+    let total = frames.len();
+    // Written in small chunks, so the count follows how much the client has
+    // taken off the socket, give or take the socket and read buffers
+    let written = Arc::new(AtomicUsize::new(0));
+    let counter = written.clone();
+    let server = tokio::spawn(async move {
+        read_version(&mut server).await.unwrap();
+        read_request(&mut server).await.unwrap().unwrap();
+        for chunk in frames.chunks(4096) {
+            server.write_all(chunk).await.unwrap();
+            counter.fetch_add(chunk.len(), Ordering::SeqCst);
+        }
+        server
+    });
+    let mut proto = Protocol::new(client, "mock");
+    proto.handshake().await.unwrap();
+
+    let mut stream = proto.query_sql_stream(0, "SELECT v FROM t", &[]).await.unwrap();
+    assert_eq!(stream.next().await.unwrap().unwrap().values(), [Value::Integer(1)]);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Most of the 4 MiB second batch is still waiting for the consumer
+    assert!(written.load(Ordering::SeqCst) < (1 << 20), "{} bytes read ahead", written.load(Ordering::SeqCst));
+    assert!(matches!(stream.next().await.unwrap().unwrap().values(), [Value::Blob(blob)] if blob.len() == 4 << 20));
+    assert!(stream.next().await.is_none());
+    drop(stream);
+    server.await.unwrap();
+    assert_eq!(written.load(Ordering::SeqCst), total);
+}
+
+// On a current_thread runtime, so a task blocking its worker would stall the
+// whole test rather than just slow it down
+#[tokio::test]
+async fn tasks_waiting_on_a_shared_protocol_leave_the_worker_free() {
+    use std::sync::Arc;
+    use tokio::sync::{oneshot, Mutex};
+
+    let (client, mut server) = Conn::from_unix_pair().unwrap();
+    let (release, released) = oneshot::channel();
+    let server = tokio::spawn(async move {
+        read_version(&mut server).await.unwrap();
+        read_request(&mut server).await.unwrap().unwrap();
+        released.await.unwrap();
+        write_messages(&mut server, &[result(1, 1)]).await.unwrap();
+        read_request(&mut server).await.unwrap().unwrap();
+        write_messages(&mut server, &[result(2, 1)]).await.unwrap();
+    });
+    let mut proto = Protocol::new(client, "mock");
+    proto.handshake().await.unwrap();
+    let proto = Arc::new(Mutex::new(proto));
+
+    let tasks: Vec<_> = (0..2)
+        .map(|_| {
+            let proto = proto.clone();
+            tokio::spawn(async move { proto.lock().await.exec_sql(0, "INSERT INTO t VALUES (1)", &[]).await })
+        })
+        .collect();
+    // One task waits on the socket, the other on the lock; neither holds the
+    // worker, so this task keeps running
+    for _ in 0..100 {
+        tokio::task::yield_now().await;
+    }
+    assert!(tasks.iter().all(|task| !task.is_finished()));
+
+    release.send(()).unwrap();
+    let mut ids = Vec::new();
+    for task in tasks {
+        let result = tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+        ids.push(result.unwrap().last_insert_id);
+    }
+    ids.sort();
+    assert_eq!(ids, [1, 2]);
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn a_protocol_over_a_socket_pair_handshakes_and_answers() {
+    let (client, server) = Conn::from_unix_pair().unwrap();
+    let mut proto = Protocol::new(client, "mock");
+    assert_eq!(proto.addr(), "mock");
+    assert_eq!(proto.version(), VERSION_ONE);
+    assert!(!proto.is_broken());
+
+    let server = serve(server, |_| vec![result(3, 1)]);
+    proto.handshake().await.unwrap();
+    assert_eq!(proto.exec_sql(0, "INSERT INTO t VALUES (1)", &[]).await.unwrap().last_insert_id, 3);
+
+    drop(proto);
+    assert_eq!(server.await.unwrap(), (VERSION_ONE, vec![REQUEST_EXEC_SQL]));
+}
+
+#[tokio::test]
+async fn an_in_order_pipeline_settles_every_request_and_keeps_the_connection() {
+    let mut next = 0;
+    let (mut proto, server) = connected(move |_| {
+        next += 1;
+        vec![result(next, 1)]
+    })
+    .await;
+
+    let statements: Vec<_> = (0..20).map(|i| ("INSERT INTO t VALUES (?)".to_string(), vec![Value::Integer(i)])).collect();
+    let results = proto.exec_sql_many(0, &statements).await.unwrap();
+
+    // Each response went to the request it answered
+    assert_eq!(results.iter().map(|r| r.last_insert_id).collect::<Vec<_>>(), (1..=20).collect::<Vec<_>>());
+    assert!(!proto.is_broken());
+    assert_eq!(proto.exec_sql(0, "INSERT INTO t VALUES (20)", &[]).await.unwrap().last_insert_id, 21);
+    drop(proto);
+    assert_eq!(server.await.unwrap().1.len(), 21);
+}
+
+// Two RESPONSE_ROWS batches of one INTEGER column "n", as a server sends them:
+// the first ends with the PART trailer, the second with DONE. The batch
+// layout is the same under both protocol versions.
+#[rustfmt::skip]
+const TWO_ROW_BATCHES: [u8; 96] = [
+    // header: 5 words, RESPONSE_ROWS, schema 0
+    0x05, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 1 column
+    b'n', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // "n"
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // row header: INTEGER
+    0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 5
+    0xee, 0xee, 0xee, 0xee, 0xee, 0xee, 0xee, 0xee, // PART
+    0x05, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    b'n', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 6
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // DONE
+];
+
+async fn query_captured_batches(version: u64) -> Rows {
+    let (client, mut server) = Conn::from_unix_pair().unwrap();
+    let server = tokio::spawn(async move {
+        assert_eq!(read_version(&mut server).await.unwrap(), version);
+        read_request(&mut server).await.unwrap().unwrap();
+        server.write_all(&TWO_ROW_BATCHES).await.unwrap();
+        server
+    });
+    let mut proto = Protocol::with_version(client, "mock", version);
+    proto.handshake().await.unwrap();
+
+    let rows = proto.query_sql(0, "SELECT n FROM t", &[]).await.unwrap();
+    assert!(!proto.is_broken());
+    server.await.unwrap();
+    rows
+}
+
+#[tokio::test]
+async fn row_batch_trailers_decode_under_both_versions() {
+    for version in [VERSION_ONE, VERSION_LEGACY] {
+        let rows = query_captured_batches(version).await;
+        assert_eq!(rows.columns(), ["n"]);
+        let values: Vec<_> = rows.iter().map(|row| row.values().to_vec()).collect();
+        assert_eq!(values, [vec![Value::Integer(5)], vec![Value::Integer(6)]], "version {:#x}", version);
+    }
+}
+
+#[tokio::test]
+async fn leader_decodes_the_node_reply_of_each_version() {
+    let (mut proto, _server) = connected(|_| vec![node(2, "10.0.0.2:9001")]).await;
+    assert_eq!(proto.leader().await.unwrap(), (2, "10.0.0.2:9001".to_string()));
+
+    // Legacy servers send the address alone
+    let (mut proto, _server) = connected_legacy(|_| vec![legacy_node("10.0.0.2:9001")]).await;
+    assert_eq!(proto.leader().await.unwrap(), (0, "10.0.0.2:9001".to_string()));
+}
+
+#[test]
+fn open_flags_accept_only_what_dqlite_supports() {
+    let accepted = [
+        OpenFlags::default(),
+        OpenFlags::READWRITE,
+        OpenFlags::READONLY,
+        OpenFlags::READONLY | OpenFlags::MAIN_DB,
+        OpenFlags::READWRITE | OpenFlags::CREATE | OpenFlags::NOFOLLOW,
+    ];
+    for flags in accepted {
+        assert!(flags.validate().is_ok(), "{:?}", flags);
+    }
+
+    let rejected = [
+        (OpenFlags::default() | OpenFlags::MEMORY, "MEMORY"),
+        (OpenFlags::READONLY | OpenFlags::READWRITE, "mutually exclusive"),
+        (OpenFlags::MAIN_DB, "One of READONLY or READWRITE"),
+        (OpenFlags::READONLY | OpenFlags::CREATE, "CREATE requires READWRITE"),
+        (OpenFlags::READWRITE | OpenFlags(0x00010000), "0x10000"),
+    ];
+    for (flags, reason) in rejected {
+        let err = flags.validate().unwrap_err();
+        assert!(err.contains(reason), "{:?}: {}", flags, err);
+    }
+}
+
+#[tokio::test]
+async fn open_rejects_unsupported_flags_without_a_round_trip() {
+    let (mut proto, server) = connected(|request| panic!("unexpected request {}", request.mtype)).await;
+
+    let err = proto.open("app", OpenFlags::default() | OpenFlags::MEMORY).await.unwrap_err();
+
+    assert!(err.to_string().contains("MEMORY"), "{}", err);
+    assert!(!proto.is_broken());
+    drop(proto);
+    assert_eq!(server.await.unwrap().1, []);
+}
@@ -0,0 +1,80 @@
+#![cfg(feature = "proxy")]
+
+use dqlite_rs::protocol::proxy::{proxy_dial, ProxyKind};
+use std::net::Ipv4Addr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+// A TCP server echoing back whatever it receives
+async fn echo_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+    addr
+}
+
+// Minimal SOCKS5 responder for one client: no authentication, CONNECT to an
+// IPv4 address only. Replies with `status`; on success it relays to the
+// target. Resolves to the target the client asked for.
+async fn socks5_responder(status: u8) -> (String, JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let responder = tokio::spawn(async move {
+        let (mut client, _) = listener.accept().await.unwrap();
+
+        let mut greeting = [0u8; 3];
+        client.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [5, 1, 0]);
+        client.write_all(&[5, 0]).await.unwrap();
+
+        let mut request = [0u8; 10];
+        client.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[..4], [5, 1, 0, 1]);
+        let ip = Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+        let target = format!("{}:{}", ip, u16::from_be_bytes([request[8], request[9]]));
+
+        client.write_all(&[5, status, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        if status == 0 {
+            let mut upstream = TcpStream::connect(&target).await.unwrap();
+            tokio::spawn(async move {
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+            });
+        }
+        target
+    });
+    (addr, responder)
+}
+
+#[tokio::test]
+async fn socks5_proxy_dial_tunnels_to_the_node() {
+    let node = echo_server().await;
+    let (proxy, responder) = socks5_responder(0).await;
+
+    let dial = proxy_dial(&proxy, ProxyKind::Socks5);
+    let mut conn = dial(&node).await.unwrap();
+
+    assert_eq!(responder.await.unwrap(), node);
+    conn.write_all(b"through the tunnel").await.unwrap();
+    let mut echoed = [0u8; 18];
+    conn.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"through the tunnel");
+}
+
+#[tokio::test]
+async fn socks5_proxy_dial_reports_a_refused_connect() {
+    let (proxy, responder) = socks5_responder(5).await;
+
+    let dial = proxy_dial(&proxy, ProxyKind::Socks5);
+    let err = dial("127.0.0.1:9001").await.err().unwrap();
+
+    assert!(err.contains("status 5"), "{}", err);
+    assert_eq!(responder.await.unwrap(), "127.0.0.1:9001");
+}
@@ -0,0 +1,67 @@
+use dqlite_rs::protocol::protocol::{ProtocolError, SqliteCode};
+use dqlite_rs::protocol::retry::{DefaultRetry, NoRetry, RetryPolicy};
+use std::io;
+use std::time::Duration;
+
+fn failure(code: SqliteCode) -> ProtocolError {
+    ProtocolError::Failure { code, message: "failed".into() }
+}
+
+fn policy() -> DefaultRetry {
+    DefaultRetry {
+        backoff_factor: Duration::from_millis(10),
+        backoff_cap: Duration::from_millis(50),
+        retry_limit: 3,
+    }
+}
+
+#[test]
+fn default_retry_retries_only_what_never_ran_or_may_succeed_later() {
+    let retried = [
+        ProtocolError::Io(io::Error::from(io::ErrorKind::ConnectionReset)),
+        failure(SqliteCode::IOERR_NOT_LEADER),
+        failure(SqliteCode::IOERR_LEADERSHIP_LOST),
+        failure(SqliteCode::BUSY),
+        failure(SqliteCode::BUSY_SNAPSHOT),
+        failure(SqliteCode::LOCKED),
+        failure(SqliteCode::NOTFOUND),
+    ];
+    for err in &retried {
+        assert_eq!(policy().should_retry(err, 1), Some(Duration::from_millis(10)), "{}", err);
+    }
+
+    let given_up = [
+        failure(SqliteCode::ERROR),
+        failure(SqliteCode::CONSTRAINT_UNIQUE),
+        failure(SqliteCode::IOERR),
+        failure(SqliteCode::READONLY),
+        ProtocolError::Protocol("bad reply".into()),
+        ProtocolError::DeadlineExceeded,
+        ProtocolError::Cancelled,
+        ProtocolError::RequestTooLarge("blob".into()),
+        ProtocolError::Unsupported("describe".into()),
+        ProtocolError::PoolClosed,
+    ];
+    for err in &given_up {
+        assert_eq!(policy().should_retry(err, 1), None, "{}", err);
+    }
+}
+
+#[test]
+fn default_retry_backs_off_up_to_the_cap_and_stops_at_the_limit() {
+    let err = failure(SqliteCode::BUSY);
+    let delays: Vec<_> = (1..=4).map(|attempt| policy().should_retry(&err, attempt)).collect();
+
+    assert_eq!(
+        delays,
+        [Some(Duration::from_millis(10)), Some(Duration::from_millis(20)), Some(Duration::from_millis(40)), None]
+    );
+    assert_eq!(DefaultRetry { retry_limit: 10, ..policy() }.should_retry(&err, 5), Some(Duration::from_millis(50)));
+}
+
+#[test]
+fn no_retry_never_retries() {
+    let err = ProtocolError::Io(io::Error::from(io::ErrorKind::ConnectionReset));
+
+    assert_eq!(NoRetry.should_retry(&err, 1), None);
+}
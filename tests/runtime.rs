@@ -0,0 +1,89 @@
+use dqlite_rs::bindings::server::{init_runtime_handle, runtime_handle, with_runtime_handle};
+#[cfg(feature = "testkit")]
+use dqlite_rs::bindings::server::invoke_connect_trampoline;
+#[cfg(feature = "testkit")]
+use dqlite_rs::protocol::connector::default_dial_func;
+use tokio::runtime::{Builder, Runtime};
+
+// raft's RAFT_NOCONNECTION, the trampoline's answer to a failed dial
+#[cfg(feature = "testkit")]
+const RAFT_NOCONNECTION: i32 = 16;
+
+fn runtime(name: &str) -> Runtime {
+    Builder::new_multi_thread().worker_threads(1).thread_name(name).enable_all().build().unwrap()
+}
+
+// Name of a worker thread of the runtime the trampoline would dial on
+fn dialing_runtime() -> String {
+    let handle = runtime_handle().expect("no runtime handle installed");
+    handle
+        .block_on(handle.spawn(async { std::thread::current().name().unwrap().to_string() }))
+        .unwrap()
+}
+
+// Everything touching the global handle lives in this one test, so no other
+// test in the binary can observe it midway
+#[test]
+fn runtime_handles_are_scoped_and_initialized_once() {
+    let (a, b, c) = (runtime("rt-a"), runtime("rt-b"), runtime("rt-c"));
+    assert!(runtime_handle().is_none());
+    #[cfg(feature = "testkit")]
+    trampoline_fails_cleanly_without_a_usable_runtime();
+
+    // Two sequential scopes see only their own handle
+    with_runtime_handle(a.handle().clone(), || assert_eq!(dialing_runtime(), "rt-a"));
+    assert!(runtime_handle().is_none());
+    with_runtime_handle(b.handle().clone(), || assert_eq!(dialing_runtime(), "rt-b"));
+    assert!(runtime_handle().is_none());
+
+    // The first init sticks; a second one is ignored
+    init_runtime_handle(a.handle().clone());
+    init_runtime_handle(b.handle().clone());
+    assert_eq!(dialing_runtime(), "rt-a");
+
+    // A scope overrides it and then puts it back
+    with_runtime_handle(c.handle().clone(), || assert_eq!(dialing_runtime(), "rt-c"));
+    assert_eq!(dialing_runtime(), "rt-a");
+
+    #[cfg(feature = "testkit")]
+    trampoline_dials_from_a_task_on_the_runtime(&a);
+}
+
+// No handle, or a current_thread one the trampoline can't block on: the dial
+// fails instead of panicking
+#[cfg(feature = "testkit")]
+fn trampoline_fails_cleanly_without_a_usable_runtime() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    assert_eq!(invoke_connect_trampoline(default_dial_func(), &addr), Err(RAFT_NOCONNECTION));
+
+    let single = Builder::new_current_thread().enable_all().build().unwrap();
+    with_runtime_handle(single.handle().clone(), || {
+        let result = single.block_on(async { invoke_connect_trampoline(default_dial_func(), &addr) });
+        assert_eq!(result, Err(RAFT_NOCONNECTION));
+    });
+}
+
+// The trampoline running on a worker of the very runtime it dials with, as
+// when a node shares the application's runtime, hands over a working socket
+#[cfg(feature = "testkit")]
+fn trampoline_dials_from_a_task_on_the_runtime(rt: &Runtime) {
+    use std::io::{Read, Write};
+    use std::os::unix::io::FromRawFd;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let fd = rt
+        .block_on(rt.spawn(async move { invoke_connect_trampoline(default_dial_func(), &addr) }))
+        .unwrap()
+        .unwrap();
+
+    // The fd is ours now, still open and connected
+    let mut stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    stream.set_nonblocking(false).unwrap();
+    stream.write_all(b"ping").unwrap();
+    let (mut accepted, _) = listener.accept().unwrap();
+    let mut received = [0u8; 4];
+    accepted.read_exact(&mut received).unwrap();
+    assert_eq!(&received, b"ping");
+}
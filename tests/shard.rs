@@ -0,0 +1,53 @@
+use dqlite_rs::protocol::connector::Connector;
+use dqlite_rs::protocol::shard::ShardRouter;
+use dqlite_rs::protocol::store::{InMemoryNodeStore, ObservableNodeStore};
+use std::sync::Arc;
+
+// Shards are never dialed, so an empty store will do
+fn shard() -> Connector<InMemoryNodeStore> {
+    Connector::builder().store(Arc::new(ObservableNodeStore::new(InMemoryNodeStore::new()))).build().unwrap()
+}
+
+fn router(names: &[&str]) -> ShardRouter<InMemoryNodeStore> {
+    let mut router = ShardRouter::new();
+    for name in names {
+        router.add(*name, shard());
+    }
+    router
+}
+
+fn placement(router: &ShardRouter<InMemoryNodeStore>, keys: &[String]) -> Vec<String> {
+    keys.iter()
+        .map(|key| router.shard_name(key.as_bytes()).unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn adding_a_shard_only_moves_keys_to_the_new_shard() {
+    let keys: Vec<String> = (0..10_000).map(|i| format!("tenant-{}", i)).collect();
+    let mut router = router(&["a", "b", "c", "d"]);
+    let before = placement(&router, &keys);
+    for name in ["a", "b", "c", "d"] {
+        let owned = before.iter().filter(|shard| *shard == name).count();
+        assert!((2_000..3_000).contains(&owned), "{} owns {} keys", name, owned);
+    }
+
+    router.add("e", shard());
+    let after = placement(&router, &keys);
+
+    let moved: Vec<_> = before.iter().zip(&after).filter(|(old, new)| old != new).collect();
+    assert!(moved.iter().all(|(_, new)| *new == "e"));
+    // About a fifth of the keys, as the new shard's fair share
+    assert!((1_600..2_400).contains(&moved.len()), "{} keys moved", moved.len());
+
+    // Removing it again puts every key back where it was
+    router.remove("e").unwrap();
+    assert_eq!(placement(&router, &keys), before);
+}
+
+#[test]
+fn placement_does_not_depend_on_insertion_order() {
+    let keys: Vec<String> = (0..1_000).map(|i| format!("tenant-{}", i)).collect();
+
+    assert_eq!(placement(&router(&["a", "b", "c"]), &keys), placement(&router(&["c", "a", "b"]), &keys));
+}
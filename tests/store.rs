@@ -0,0 +1,440 @@
+use dqlite_rs::protocol::store::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn voter(id: u64, addr: &str) -> NodeInfo {
+    NodeInfo { id, addr: addr.to_string(), role: NodeRole::VOTER, failure_domain: None }
+}
+
+async fn store_with(nodes: Vec<NodeInfo>) -> InMemoryNodeStore {
+    let store = InMemoryNodeStore::new();
+    store.set_all(nodes).await.unwrap();
+    store
+}
+
+#[tokio::test]
+async fn reconcile_reports_an_address_change() {
+    let store = store_with(vec![voter(1, "10.0.0.1:9001"), voter(2, "10.0.0.2:9001")]).await;
+
+    let report = store.reconcile(vec![voter(1, "10.0.0.1:9001"), voter(2, "10.0.0.9:9001")]).await.unwrap();
+
+    assert_eq!(
+        report.changes,
+        [NodeChange::AddressChanged { id: 2, old: "10.0.0.2:9001".into(), new: "10.0.0.9:9001".into() }]
+    );
+    assert_eq!(store.get_by_id(2).await.unwrap().unwrap().addr, "10.0.0.9:9001");
+    assert!(store.get_by_address("10.0.0.2:9001").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn reconcile_reports_a_role_change() {
+    let store = store_with(vec![voter(1, "10.0.0.1:9001"), voter(2, "10.0.0.2:9001")]).await;
+    let mut demoted = voter(2, "10.0.0.2:9001");
+    demoted.role = NodeRole::SPARE;
+
+    let report = store.reconcile(vec![voter(1, "10.0.0.1:9001"), demoted]).await.unwrap();
+
+    assert_eq!(report.changes, [NodeChange::RoleChanged { id: 2, old: NodeRole::VOTER, new: NodeRole::SPARE }]);
+    assert_eq!(store.get_by_id(2).await.unwrap().unwrap().role, NodeRole::SPARE);
+}
+
+#[tokio::test]
+async fn reconcile_stores_a_failure_domain_change() {
+    let store = store_with(vec![voter(1, "10.0.0.1:9001")]).await;
+    let placed = NodeInfo { failure_domain: Some(2), ..voter(1, "10.0.0.1:9001") };
+
+    let report = store.reconcile(vec![placed.clone()]).await.unwrap();
+
+    assert_eq!(report.changes, [NodeChange::FailureDomainChanged { id: 1, old: None, new: Some(2) }]);
+    assert_eq!(store.get_all().await.unwrap(), [placed]);
+}
+
+#[test]
+fn same_identity_ignores_the_role() {
+    let node = voter(1, "10.0.0.1:9001");
+    let mut standby = node.clone();
+    standby.role = NodeRole::STAND_BY;
+
+    assert!(node.same_identity(&standby));
+    assert_ne!(node, standby);
+    assert!(!node.same_identity(&voter(1, "10.0.0.2:9001")));
+    assert!(!node.same_identity(&voter(2, "10.0.0.1:9001")));
+}
+
+#[tokio::test]
+async fn auditing_store_records_an_upsert_then_a_remove() {
+    let (sink, mut records) = tokio::sync::mpsc::channel(16);
+    let store = AuditingNodeStore::new(InMemoryNodeStore::new(), sink);
+
+    store.upsert(voter(1, "10.0.0.1:9001")).await.unwrap();
+    let mut standby = voter(1, "10.0.0.1:9001");
+    standby.role = NodeRole::STAND_BY;
+    store.upsert(standby).await.unwrap();
+    assert!(store.remove(1).await.unwrap());
+    // Nothing was removed, so nothing is recorded
+    assert!(!store.remove(1).await.unwrap());
+    drop(store);
+
+    let mut seen = Vec::new();
+    while let Some(record) = records.recv().await {
+        seen.push((record.operation, record.node_id, record.old_role, record.new_role));
+    }
+    assert_eq!(
+        seen,
+        [
+            (AuditOperation::Upsert, 1, None, Some(NodeRole::VOTER)),
+            (AuditOperation::Upsert, 1, Some(NodeRole::VOTER), Some(NodeRole::STAND_BY)),
+            (AuditOperation::Remove, 1, Some(NodeRole::STAND_BY), None),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn auditing_store_records_nothing_for_a_rejected_mutation() {
+    let (sink, mut records) = tokio::sync::mpsc::channel(16);
+    let store = AuditingNodeStore::new(InMemoryNodeStore::new(), sink);
+
+    assert!(store.upsert(voter(1, "")).await.is_err());
+    assert!(store.set_all(vec![voter(1, "10.0.0.1:9001"), voter(1, "10.0.0.2:9001")]).await.is_err());
+    drop(store);
+
+    assert!(records.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn strict_auditing_store_leaves_the_nodes_alone_when_the_record_fails() {
+    let (sink, records) = tokio::sync::mpsc::channel(16);
+    let inner = InMemoryNodeStore::new();
+    inner.set_all(vec![voter(1, "10.0.0.1:9001")]).await.unwrap();
+    let store = AuditingNodeStore::new(inner, sink);
+    // Closing the channel makes every append fail
+    drop(records);
+
+    assert!(store.set_all(vec![voter(2, "10.0.0.2:9001")]).await.is_err());
+    assert!(store.upsert(voter(3, "10.0.0.3:9001")).await.is_err());
+    assert!(store.remove(1).await.is_err());
+    assert_eq!(store.get_all().await.unwrap(), [voter(1, "10.0.0.1:9001")]);
+
+    // Lenient mode applies them anyway
+    let store = store.with_strict(false);
+    store.upsert(voter(3, "10.0.0.3:9001")).await.unwrap();
+    assert_eq!(store.count().await.unwrap(), 2);
+}
+
+async fn assert_dry_run_rejects_duplicate_addresses<S: NodeStore>(store: &S) {
+    store.set_all(vec![voter(1, "10.0.0.1:9001")]).await.unwrap();
+    let version = store.version().await.unwrap();
+
+    let duplicate = vec![voter(1, "10.0.0.1:9001"), voter(2, "10.0.0.1:9001")];
+    assert!(store.validate_set_all(&duplicate).await.is_err());
+    // A valid set passes the dry run without being written either
+    store.validate_set_all(&[voter(1, "10.0.0.1:9001"), voter(2, "10.0.0.2:9001")]).await.unwrap();
+
+    assert_eq!(store.get_all().await.unwrap(), [voter(1, "10.0.0.1:9001")]);
+    assert_eq!(store.version().await.unwrap(), version);
+}
+
+#[tokio::test]
+async fn in_memory_dry_run_rejects_duplicate_addresses() {
+    assert_dry_run_rejects_duplicate_addresses(&InMemoryNodeStore::new()).await;
+}
+
+#[tokio::test]
+async fn database_dry_run_rejects_duplicate_addresses() {
+    let store = DatabaseNodeStore::new(":memory:").await.unwrap();
+    assert_dry_run_rejects_duplicate_addresses(&store).await;
+}
+
+// A path for a YAML store that nothing else uses
+fn yaml_path(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("dqlite-store-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{}.yaml", name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+async fn assert_counts<S: NodeStore>(store: &S) {
+    assert_eq!(store.count().await.unwrap(), 0);
+    store
+        .set_all(vec![voter(1, "10.0.0.1:9001"), voter(2, "10.0.0.2:9001"), voter(3, "10.0.0.3:9001")])
+        .await
+        .unwrap();
+    assert_eq!(store.count().await.unwrap(), 3);
+}
+
+#[tokio::test]
+async fn count_of_empty_and_three_node_stores() {
+    assert_counts(&InMemoryNodeStore::new()).await;
+    assert_counts(&YamlNodeStore::new(yaml_path("count")).await.unwrap()).await;
+    assert_counts(&DatabaseNodeStore::new(":memory:").await.unwrap()).await;
+}
+
+// Every write replaces the file through a rename, so each one shows up as a
+// new inode
+fn inode(path: &std::path::Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|metadata| metadata.ino())
+}
+
+#[tokio::test]
+async fn batched_yaml_store_coalesces_rapid_upserts_into_one_write() {
+    let path = yaml_path("batched");
+    let store = YamlNodeStore::new(&path).await.unwrap();
+    store.upsert(voter(1, "10.0.0.1:9001")).await.unwrap();
+    let before = inode(&path).unwrap();
+
+    let store = store.with_flush_policy(FlushPolicy::Batched(Duration::from_millis(100)));
+    for id in 2..=11 {
+        store.upsert(voter(id, &format!("10.0.0.{}:9001", id))).await.unwrap();
+    }
+    // Nothing is written until the interval has passed
+    assert_eq!(inode(&path), Some(before));
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let after = inode(&path).unwrap();
+    assert_ne!(after, before);
+    assert_eq!(read_yaml_nodes(&path).unwrap().len(), 11);
+
+    // One write covered all ten upserts
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(inode(&path), Some(after));
+    drop(store);
+    assert_eq!(inode(&path), Some(after));
+}
+
+#[tokio::test]
+async fn batched_yaml_store_flushes_a_pending_write_on_drop() {
+    let path = yaml_path("batched-drop");
+    let store = YamlNodeStore::new(&path)
+        .await
+        .unwrap()
+        .with_flush_policy(FlushPolicy::Batched(Duration::from_secs(3600)));
+    store.upsert(voter(1, "10.0.0.1:9001")).await.unwrap();
+    assert!(!path.exists());
+
+    drop(store);
+
+    assert_eq!(read_yaml_nodes(&path).unwrap(), [voter(1, "10.0.0.1:9001")]);
+}
+
+#[test]
+fn restore_puts_back_the_nodes_and_version_of_a_snapshot() {
+    let backend = NodeStoreBackend::from_nodes(vec![voter(1, "10.0.0.1:9001"), voter(2, "10.0.0.2:9001")]).unwrap();
+    backend.upsert(voter(3, "10.0.0.3:9001")).unwrap();
+    let snapshot = backend.snapshot();
+    let version = backend.version();
+    assert_eq!(snapshot.version(), version);
+
+    backend.remove(1);
+    backend.upsert(voter(2, "10.0.0.9:9001")).unwrap();
+    backend.set_all(vec![voter(4, "10.0.0.4:9001")]).unwrap();
+    assert_ne!(backend.version(), version);
+
+    backend.restore(snapshot.clone());
+
+    assert_eq!(backend.version(), version);
+    assert_eq!(backend.get_all(), snapshot.nodes());
+    assert_eq!(backend.snapshot(), snapshot);
+    // The address index came back too
+    assert_eq!(backend.get_by_address("10.0.0.2:9001").unwrap().id, 2);
+    assert!(backend.get_by_address("10.0.0.4:9001").is_none());
+}
+
+#[tokio::test]
+async fn yaml_without_roles_loads_every_node_as_a_voter() {
+    let path = yaml_path("legacy");
+    std::fs::write(&path, "- ID: 1\n  Address: 10.0.0.1:9001\n- ID: 2\n  Address: 10.0.0.2:9001\n").unwrap();
+
+    let store = YamlNodeStore::new(&path).await.unwrap();
+
+    assert_eq!(store.get_all().await.unwrap(), [voter(1, "10.0.0.1:9001"), voter(2, "10.0.0.2:9001")]);
+}
+
+#[test]
+fn yaml_still_requires_an_id_and_an_address() {
+    for (name, yaml) in [("no-id", "- Address: 10.0.0.1:9001\n"), ("no-address", "- ID: 1\n")] {
+        let path = yaml_path(name);
+        std::fs::write(&path, yaml).unwrap();
+        assert!(matches!(read_yaml_nodes(&path), Err(NodeStoreError::Serialization(_))), "{}", name);
+    }
+}
+
+// Counts get_all calls reaching the wrapped store, each of which takes a
+// little while so concurrent callers overlap
+#[derive(Default)]
+struct CountingStore {
+    inner: InMemoryNodeStore,
+    reads: Arc<AtomicUsize>,
+}
+
+#[async_trait::async_trait]
+impl NodeStore for CountingStore {
+    async fn get_all(&self) -> NodeStoreResult<Vec<NodeInfo>> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        self.inner.get_all().await
+    }
+
+    async fn get_by_id(&self, id: u64) -> NodeStoreResult<Option<NodeInfo>> {
+        self.inner.get_by_id(id).await
+    }
+
+    async fn get_by_address(&self, address: &str) -> NodeStoreResult<Option<NodeInfo>> {
+        self.inner.get_by_address(address).await
+    }
+
+    async fn set_all(&self, nodes: Vec<NodeInfo>) -> NodeStoreResult<()> {
+        self.inner.set_all(nodes).await
+    }
+
+    async fn upsert(&self, node: NodeInfo) -> NodeStoreResult<()> {
+        self.inner.upsert(node).await
+    }
+
+    async fn remove(&self, id: u64) -> NodeStoreResult<bool> {
+        self.inner.remove(id).await
+    }
+
+    async fn version(&self) -> NodeStoreResult<u64> {
+        self.inner.version().await
+    }
+
+    async fn set_if_version(&self, nodes: Vec<NodeInfo>, version: u64) -> NodeStoreResult<()> {
+        self.inner.set_if_version(nodes, version).await
+    }
+}
+
+#[tokio::test]
+async fn cached_store_reads_the_backend_once_per_ttl() {
+    let backend = CountingStore::default();
+    let backend_reads = backend.reads.clone();
+    backend.set_all(vec![voter(1, "10.0.0.1:9001"), voter(2, "10.0.0.2:9001")]).await.unwrap();
+    let store = CachedNodeStore::new(backend, Duration::from_millis(300));
+
+    // Concurrent misses share one fetch
+    let reads = futures::future::join_all((0..10).map(|_| store.get_all())).await;
+    assert!(reads.iter().all(|nodes| nodes.as_ref().unwrap().len() == 2));
+    for _ in 0..10 {
+        assert_eq!(store.get_by_id(2).await.unwrap().unwrap().addr, "10.0.0.2:9001");
+        assert_eq!(store.count().await.unwrap(), 2);
+    }
+    assert_eq!(backend_reads.load(Ordering::SeqCst), 1);
+
+    // A write through the wrapper drops the cache
+    store.upsert(voter(3, "10.0.0.3:9001")).await.unwrap();
+    assert_eq!(store.get_all().await.unwrap().len(), 3);
+    assert_eq!(backend_reads.load(Ordering::SeqCst), 2);
+
+    // So does the TTL running out
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    store.get_all().await.unwrap();
+    assert_eq!(backend_reads.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn node_roles_parse_from_their_names() {
+    for (name, role) in [
+        ("voter", NodeRole::VOTER),
+        ("VOTER", NodeRole::VOTER),
+        ("stand-by", NodeRole::STAND_BY),
+        ("Stand-By", NodeRole::STAND_BY),
+        ("standby", NodeRole::STAND_BY),
+        ("StandBy", NodeRole::STAND_BY),
+        ("spare", NodeRole::SPARE),
+        ("Spare", NodeRole::SPARE),
+    ] {
+        assert_eq!(name.parse::<NodeRole>(), Ok(role), "{}", name);
+    }
+    for role in [NodeRole::VOTER, NodeRole::STAND_BY, NodeRole::SPARE] {
+        assert_eq!(role.to_string().parse::<NodeRole>(), Ok(role));
+    }
+
+    assert!("leader".parse::<NodeRole>().unwrap_err().contains("leader"));
+    assert!("".parse::<NodeRole>().is_err());
+}
+
+#[test]
+fn a_role_change_is_the_same_node_and_a_new_id_is_not() {
+    let node = voter(1, "10.0.0.1:9001");
+    let demoted = NodeInfo { role: NodeRole::STAND_BY, ..node.clone() };
+    let replaced = voter(2, "10.0.0.1:9001");
+
+    assert!(node.same_node(&demoted));
+    assert!(node.differs_materially(&demoted));
+    assert!(!node.same_node(&replaced));
+    // The failure domain is only a hint
+    let placed = NodeInfo { failure_domain: Some(3), ..node.clone() };
+    assert!(node.same_node(&placed) && !node.differs_materially(&placed));
+
+    // ...but a change of it is still reported, so reconcile stores it
+    let report = diff_nodes(std::slice::from_ref(&node), std::slice::from_ref(&placed));
+    assert_eq!(report.changes, [NodeChange::FailureDomainChanged { id: 1, old: None, new: Some(3) }]);
+    let report = diff_nodes(std::slice::from_ref(&node), &[demoted]);
+    assert_eq!(report.changes, [NodeChange::RoleChanged { id: 1, old: NodeRole::VOTER, new: NodeRole::STAND_BY }]);
+    let report = diff_nodes(std::slice::from_ref(&node), std::slice::from_ref(&replaced));
+    assert_eq!(report.changes, [NodeChange::Added(replaced), NodeChange::Removed(node)]);
+}
+
+async fn assert_sorted_by_id<S: NodeStore>(store: &S) {
+    for id in [7, 2, 9, 1, 5, 3, 8] {
+        store.upsert(voter(id, &format!("10.0.0.{}:9001", id))).await.unwrap();
+    }
+
+    let first = store.get_all().await.unwrap();
+    assert_eq!(first.iter().map(|node| node.id).collect::<Vec<_>>(), [1, 2, 3, 5, 7, 8, 9]);
+    assert_eq!(store.get_all().await.unwrap(), first);
+}
+
+#[tokio::test]
+async fn every_store_lists_nodes_by_id() {
+    assert_sorted_by_id(&InMemoryNodeStore::new()).await;
+    assert_sorted_by_id(&YamlNodeStore::new(yaml_path("sorted")).await.unwrap()).await;
+    assert_sorted_by_id(&DatabaseNodeStore::new(":memory:").await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn saving_the_same_nodes_gives_byte_identical_yaml() {
+    let nodes: Vec<_> = (1..=20).map(|id| voter(id, &format!("10.0.0.{}:9001", id))).collect();
+    let path = yaml_path("stable");
+    let store = YamlNodeStore::new(&path).await.unwrap();
+
+    store.set_all(nodes.clone()).await.unwrap();
+    let first = std::fs::read(&path).unwrap();
+    store.set_all(nodes.iter().rev().cloned().collect()).await.unwrap();
+    let second = std::fs::read(&path).unwrap();
+
+    assert_eq!(first, second);
+    let text = String::from_utf8(first).unwrap();
+    assert!(text.find("10.0.0.2:9001").unwrap() < text.find("10.0.0.10:9001").unwrap());
+}
+
+#[tokio::test]
+async fn a_yaml_file_with_thousands_of_nodes_loads_in_full() {
+    use std::fmt::Write;
+
+    let nodes: Vec<NodeInfo> = (1..=5_000u64)
+        .map(|id| NodeInfo {
+            id,
+            addr: format!("10.{}.{}.{}:9001", id / 65_536, id / 256 % 256, id % 256),
+            role: NodeRole::new((id % 3) as u8).unwrap(),
+            failure_domain: (id % 2 == 0).then_some(id % 7),
+        })
+        .collect();
+    // Written by hand, shuffled, rather than by the store under test
+    let mut yaml = String::new();
+    for node in nodes.iter().rev() {
+        write!(yaml, "- ID: {}\n  Address: {}\n  Role: {}\n", node.id, node.addr, node.role.value()).unwrap();
+        if let Some(domain) = node.failure_domain {
+            writeln!(yaml, "  FailureDomain: {}", domain).unwrap();
+        }
+    }
+    let path = yaml_path("thousands");
+    std::fs::write(&path, yaml).unwrap();
+
+    assert_eq!(read_yaml_nodes(&path).unwrap().len(), nodes.len());
+    let store = YamlNodeStore::new(&path).await.unwrap();
+    assert_eq!(store.get_all().await.unwrap(), nodes);
+    assert_eq!(store.get_by_address("10.0.19.136:9001").await.unwrap(), Some(nodes[4_999].clone()));
+}
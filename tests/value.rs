@@ -0,0 +1,106 @@
+use dqlite_rs::protocol::message::*;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// 2023-11-14T22:13:20Z
+const INSTANT_SECS: i64 = 1_700_000_000;
+
+// `value` written into a framed message and decoded back by its type code,
+// as it travels between client and server
+fn over_the_wire(value: &Value) -> Value {
+    let mut message = Message::new(RESPONSE_ROWS);
+    message.put_value(value);
+    let mut bytes = Vec::new();
+    message.encode_into(&mut bytes);
+    let mut header = [0u8; HEADER_SIZE];
+    header.copy_from_slice(&bytes[..HEADER_SIZE]);
+    Message::from_parts(header, bytes[HEADER_SIZE..].to_vec()).get_value(value.type_code()).unwrap()
+}
+
+#[test]
+fn system_time_round_trips_as_unixtime() {
+    let instant = UNIX_EPOCH + Duration::from_secs(INSTANT_SECS as u64);
+
+    let value = Value::from(instant);
+    assert_eq!(value, Value::Unixtime(INSTANT_SECS));
+    assert_eq!(value.type_code(), TYPE_UNIXTIME);
+    let back = over_the_wire(&value);
+    assert_eq!(back, value);
+    assert_eq!(SystemTime::try_from(&back).unwrap(), instant);
+
+    // Before the epoch too
+    let before = UNIX_EPOCH - Duration::from_secs(86_400);
+    assert_eq!(SystemTime::try_from(&over_the_wire(&Value::from(before))).unwrap(), before);
+}
+
+#[cfg(feature = "chrono")]
+#[test]
+fn chrono_instants_round_trip_as_unixtime_and_iso8601() {
+    use chrono::{DateTime, TimeZone, Utc};
+
+    let instant = Utc.with_ymd_and_hms(2023, 11, 14, 22, 13, 20).unwrap();
+
+    let back = over_the_wire(&Value::from(instant));
+    assert_eq!(back, Value::Unixtime(INSTANT_SECS));
+    assert_eq!(DateTime::<Utc>::try_from(&back).unwrap(), instant);
+
+    for text in ["2023-11-14 22:13:20", "2023-11-14T22:13:20Z", "2023-11-14 23:13:20+01:00"] {
+        let back = over_the_wire(&Value::Iso8601(text.into()));
+        assert_eq!(back.type_code(), TYPE_ISO8601);
+        assert_eq!(DateTime::<Utc>::try_from(&back).unwrap(), instant, "{}", text);
+        assert_eq!(SystemTime::try_from(&back).unwrap(), SystemTime::from(instant), "{}", text);
+    }
+}
+
+#[cfg(feature = "rusqlite-compat")]
+mod rusqlite_compat {
+    use dqlite_rs::protocol::message::Value;
+    use rusqlite::types::{Value as SqliteValue, ValueRef as SqliteValueRef};
+
+    fn sqlite_values() -> Vec<SqliteValue> {
+        vec![
+            SqliteValue::Null,
+            SqliteValue::Integer(-42),
+            SqliteValue::Real(2.5),
+            SqliteValue::Text("héllo".into()),
+            SqliteValue::Blob(vec![0, 1, 0xff]),
+        ]
+    }
+
+    #[test]
+    fn owned_values_round_trip_for_every_storage_class() {
+        for original in sqlite_values() {
+            let ours = Value::from(original.clone());
+            assert_eq!(SqliteValue::from(ours), original);
+        }
+    }
+
+    #[test]
+    fn borrowed_values_round_trip_for_every_storage_class() {
+        for original in sqlite_values() {
+            let ours = Value::from(SqliteValueRef::from(&original));
+            assert_eq!(SqliteValueRef::from(&ours), SqliteValueRef::from(&original));
+        }
+    }
+
+    #[test]
+    fn types_sqlite_lacks_map_to_their_storage_form() {
+        assert_eq!(SqliteValue::from(Value::Boolean(true)), SqliteValue::Integer(1));
+        assert_eq!(SqliteValue::from(Value::Unixtime(1_700_000_000)), SqliteValue::Integer(1_700_000_000));
+        let iso = Value::Iso8601("2024-01-02T03:04:05Z".into());
+        assert_eq!(SqliteValueRef::from(&iso), SqliteValueRef::Text(b"2024-01-02T03:04:05Z"));
+    }
+
+    #[test]
+    fn values_read_from_a_local_database_convert() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let row: Vec<Value> = conn
+            .query_row("SELECT 1, 1.5, 'x', x'00ff', NULL", [], |row| {
+                (0..5).map(|i| row.get_ref(i).map(Value::from)).collect()
+            })
+            .unwrap();
+        assert_eq!(
+            row,
+            [Value::Integer(1), Value::Float(1.5), Value::Text("x".into()), Value::Blob(vec![0, 0xff]), Value::Null]
+        );
+    }
+}